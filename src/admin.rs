@@ -0,0 +1,71 @@
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+
+use crate::AppState;
+
+/// Operator endpoints. They only answer requests that carry the configured
+/// admin token as a bearer token, and do not exist when no token is set.
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/api/admin/turn/stats", get(turn_stats))
+        .route("/api/admin/turn/allocations", get(turn_allocations))
+        .route("/api/admin/ws/stats", get(ws_stats))
+}
+
+/// The response to send instead of the requested one, if the request does
+/// not carry the admin token.
+pub fn denial(state: &AppState, headers: &HeaderMap) -> Option<Response> {
+    let Some(expected) = state.config.admin_token.as_deref() else {
+        return Some(StatusCode::NOT_FOUND.into_response());
+    };
+
+    let presented = bearer_token(headers).unwrap_or("");
+
+    if constant_time_eq(presented.as_bytes(), expected.as_bytes()) {
+        None
+    } else {
+        Some((StatusCode::UNAUTHORIZED, [(header::WWW_AUTHENTICATE, "Bearer")]).into_response())
+    }
+}
+
+/// The token of an `Authorization: Bearer` header.
+pub fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+}
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+async fn turn_stats(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if let Some(denied) = denial(&state, &headers) {
+        return denied;
+    }
+    match state.turn.stats().await {
+        Ok(stats) => Json(stats).into_response(),
+        Err(e) => (StatusCode::SERVICE_UNAVAILABLE, e.to_string()).into_response(),
+    }
+}
+
+async fn turn_allocations(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if let Some(denied) = denial(&state, &headers) {
+        return denied;
+    }
+    Json(state.turn.allocations()).into_response()
+}
+
+/// Messages dropped for clients that could not keep up.
+async fn ws_stats(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if let Some(denied) = denial(&state, &headers) {
+        return denied;
+    }
+    Json(state.send_stats.snapshot()).into_response()
+}
@@ -0,0 +1,481 @@
+//! Avatar uploads. Images are checked and stripped of metadata, then stored
+//! under the SHA-256 of what is left, so signaling only carries the short
+//! path they are served from. Resizing happens in the page before upload.
+//! The server never decodes pixels: it reads the size from the image header
+//! and turns away anything larger than the page would have made it.
+//!
+//! Only participants of a room can upload, with their resume token as a
+//! bearer token. Avatars nobody in any room shows anymore are deleted.
+
+use axum::{
+    body::Bytes,
+    extract::{DefaultBodyLimit, Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use ring::digest::{digest, SHA256};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fmt::Write as _;
+use std::time::{Duration, SystemTime};
+
+use crate::admin::bearer_token;
+use crate::room::RoomMap;
+use crate::AppState;
+
+/// Path prefix avatars are served from.
+pub const PATH_PREFIX: &str = "/avatars/";
+
+/// A 256x256 image is well below this in either format.
+const MAX_UPLOAD_LEN: usize = 512 * 1024;
+/// Largest width or height accepted, in pixels. The page scales avatars
+/// down to this size before uploading them.
+const MAX_DIMENSION: u32 = 256;
+/// How often avatars nobody shows are looked for.
+const COLLECT_EVERY: Duration = Duration::from_secs(5 * 60);
+/// Uploads are kept this long before they have to be shown somewhere, as
+/// the page only tells the room about its avatar once the upload is done.
+const UNUSED_GRACE: Duration = Duration::from_secs(10 * 60);
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/api/rooms/:room_id/avatar", post(upload).layer(DefaultBodyLimit::max(MAX_UPLOAD_LEN)))
+        .route("/avatars/:hash", get(serve))
+}
+
+/// Whether `path` names an uploaded avatar.
+pub fn valid_path(path: &str) -> bool {
+    path.strip_prefix(PATH_PREFIX).is_some_and(valid_hash)
+}
+
+fn valid_hash(hash: &str) -> bool {
+    hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+#[derive(Serialize)]
+struct Uploaded {
+    url: String,
+}
+
+async fn upload(State(state): State<AppState>, Path(room_id): Path<String>, headers: HeaderMap, body: Bytes) -> Response {
+    let forbidden = || (StatusCode::FORBIDDEN, "Only participants of a room can upload avatars").into_response();
+    let Some(token) = bearer_token(&headers) else {
+        return forbidden();
+    };
+    let Some(room) = state.rooms.get(&room_id) else {
+        return forbidden();
+    };
+    if room.participant(token).await.is_err() {
+        return forbidden();
+    }
+
+    let image = match Image::parse(&body) {
+        Ok(image) => image,
+        Err(e) => return (e.status(), e.to_string()).into_response(),
+    };
+
+    let hash = digest(&SHA256, &image.bytes).as_ref().iter().fold(String::new(), |mut hex, b| {
+        let _ = write!(hex, "{:02x}", b);
+        hex
+    });
+    let path = state.config.avatar_dir.join(&hash);
+    if tokio::fs::try_exists(&path).await.unwrap_or(false) {
+        // Uploaded again, so in use again: start its grace period over.
+        let touched = path.clone();
+        let _ = tokio::task::spawn_blocking(move || {
+            std::fs::File::options().write(true).open(touched)?.set_modified(SystemTime::now())
+        })
+        .await;
+    } else {
+        // Written under a temporary name first so a half-written file is
+        // never served.
+        let partial = state.config.avatar_dir.join(format!("{}.{}.tmp", hash, uuid::Uuid::new_v4().simple()));
+        let stored = async {
+            tokio::fs::write(&partial, &image.bytes).await?;
+            tokio::fs::rename(&partial, &path).await
+        };
+        if let Err(e) = stored.await {
+            eprintln!("failed to store avatar {}: {}", hash, e);
+            let _ = tokio::fs::remove_file(&partial).await;
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    }
+
+    Json(Uploaded {
+        url: format!("{}{}", PATH_PREFIX, hash),
+    })
+    .into_response()
+}
+
+async fn serve(State(state): State<AppState>, Path(hash): Path<String>) -> Response {
+    if !valid_hash(&hash) {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    let Ok(bytes) = tokio::fs::read(state.config.avatar_dir.join(&hash)).await else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let Some(kind) = Kind::sniff(&bytes) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    (
+        [
+            (header::CONTENT_TYPE, kind.mime()),
+            // The content can never change under the same hash.
+            (header::CACHE_CONTROL, "public, max-age=31536000, immutable"),
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff"),
+        ],
+        bytes,
+    )
+        .into_response()
+}
+
+/// Deletes avatars nobody shows anymore, every few minutes.
+pub async fn collect_garbage(rooms: RoomMap, dir: std::path::PathBuf) {
+    let mut interval = tokio::time::interval(COLLECT_EVERY);
+    loop {
+        interval.tick().await;
+        let shown = rooms.avatars().await;
+        if let Err(e) = sweep(&dir, &shown, SystemTime::now()).await {
+            eprintln!("failed to clean up avatars in {}: {}", dir.display(), e);
+        }
+    }
+}
+
+/// Deletes the avatars in `dir` that are not in `shown` and have been
+/// unused for longer than the grace period, along with leftover partial
+/// writes. Returns how many files were deleted.
+async fn sweep(dir: &std::path::Path, shown: &HashSet<String>, now: SystemTime) -> std::io::Result<usize> {
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    let mut removed = 0;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name();
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+        let hash = name.split('.').next().unwrap_or_default();
+        if !valid_hash(hash) || (name == hash && shown.contains(&format!("{}{}", PATH_PREFIX, hash))) {
+            continue;
+        }
+        let modified = entry.metadata().await?.modified()?;
+        if now.duration_since(modified).unwrap_or_default() < UNUSED_GRACE {
+            continue;
+        }
+        match tokio::fs::remove_file(entry.path()).await {
+            Ok(()) => removed += 1,
+            Err(e) => eprintln!("failed to delete avatar {}: {}", name, e),
+        }
+    }
+    Ok(removed)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Png,
+    Jpeg,
+}
+
+impl Kind {
+    fn sniff(bytes: &[u8]) -> Option<Kind> {
+        if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Some(Kind::Png)
+        } else if bytes.starts_with(&[0xff, 0xd8, 0xff]) {
+            Some(Kind::Jpeg)
+        } else {
+            None
+        }
+    }
+
+    fn mime(self) -> &'static str {
+        match self {
+            Kind::Png => "image/png",
+            Kind::Jpeg => "image/jpeg",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum ImageError {
+    Unsupported,
+    Malformed,
+    TooLarge,
+}
+
+impl ImageError {
+    fn status(&self) -> StatusCode {
+        match self {
+            ImageError::Unsupported => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ImageError::Malformed => StatusCode::UNPROCESSABLE_ENTITY,
+            ImageError::TooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+        }
+    }
+}
+
+impl std::fmt::Display for ImageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImageError::Unsupported => f.write_str("Avatars must be PNG or JPEG images"),
+            ImageError::Malformed => f.write_str("The image is damaged"),
+            ImageError::TooLarge => write!(f, "Avatars are limited to {0}x{0} pixels", MAX_DIMENSION),
+        }
+    }
+}
+
+/// An image with its metadata removed. Only the container is rewritten;
+/// the pixel data is copied as is.
+struct Image {
+    bytes: Vec<u8>,
+    width: u32,
+    height: u32,
+}
+
+impl Image {
+    fn parse(bytes: &[u8]) -> Result<Image, ImageError> {
+        let image = match Kind::sniff(bytes).ok_or(ImageError::Unsupported)? {
+            Kind::Png => strip_png(bytes),
+            Kind::Jpeg => strip_jpeg(bytes),
+        }
+        .ok_or(ImageError::Malformed)?;
+        if image.width == 0 || image.height == 0 {
+            return Err(ImageError::Malformed);
+        }
+        if image.width > MAX_DIMENSION || image.height > MAX_DIMENSION {
+            return Err(ImageError::TooLarge);
+        }
+        Ok(image)
+    }
+}
+
+fn be_u16(bytes: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(bytes.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn be_u32(bytes: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(bytes.get(at..at + 4)?.try_into().ok()?))
+}
+
+/// Drops text, EXIF and timestamp chunks, and anything after `IEND`.
+fn strip_png(bytes: &[u8]) -> Option<Image> {
+    let mut out = bytes[..8].to_vec();
+    let mut size = None;
+    let mut at = 8;
+    loop {
+        let len = be_u32(bytes, at)? as usize;
+        let kind = bytes.get(at + 4..at + 8)?;
+        let end = at.checked_add(12)?.checked_add(len)?;
+        let chunk = bytes.get(at..end)?;
+        match kind {
+            b"IHDR" => size = Some((be_u32(bytes, at + 8)?, be_u32(bytes, at + 12)?)),
+            b"tEXt" | b"zTXt" | b"iTXt" | b"eXIf" | b"tIME" => {
+                at = end;
+                continue;
+            }
+            _ => {}
+        }
+        out.extend_from_slice(chunk);
+        at = end;
+        if kind == b"IEND" {
+            break;
+        }
+    }
+    let (width, height) = size?;
+    Some(Image {
+        bytes: out,
+        width,
+        height,
+    })
+}
+
+/// Drops comments and every application segment but JFIF and Adobe, which
+/// decoders need. The entropy-coded data from the first scan on is copied
+/// unchanged.
+fn strip_jpeg(bytes: &[u8]) -> Option<Image> {
+    let mut out = bytes[..2].to_vec();
+    let mut size = None;
+    let mut at = 2;
+    loop {
+        if *bytes.get(at)? != 0xff {
+            return None;
+        }
+        let marker = *bytes.get(at + 1)?;
+        match marker {
+            // Fill byte.
+            0xff => {
+                at += 1;
+                continue;
+            }
+            // Start of scan.
+            0xda => {
+                out.extend_from_slice(&bytes[at..]);
+                break;
+            }
+            0x01 | 0xd0..=0xd7 => {
+                out.extend_from_slice(&bytes[at..at + 2]);
+                at += 2;
+                continue;
+            }
+            _ => {}
+        }
+        let end = at + 2 + be_u16(bytes, at + 2)? as usize;
+        let segment = bytes.get(at..end)?;
+        match marker {
+            0xc0..=0xc3 | 0xc5..=0xc7 | 0xc9..=0xcb | 0xcd..=0xcf => {
+                size = Some((be_u16(segment, 7)? as u32, be_u16(segment, 5)? as u32));
+                out.extend_from_slice(segment);
+            }
+            0xe1..=0xed | 0xef | 0xfe => {}
+            _ => out.extend_from_slice(segment),
+        }
+        at = end;
+    }
+    let (width, height) = size?;
+    Some(Image {
+        bytes: out,
+        width,
+        height,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn png_chunk(kind: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+        chunk.extend_from_slice(kind);
+        chunk.extend_from_slice(data);
+        // The CRC is not checked when stripping.
+        chunk.extend_from_slice(&[0; 4]);
+        chunk
+    }
+
+    fn png() -> Vec<u8> {
+        let mut ihdr = 3u32.to_be_bytes().to_vec();
+        ihdr.extend_from_slice(&2u32.to_be_bytes());
+        ihdr.extend_from_slice(&[8, 6, 0, 0, 0]);
+        let mut bytes = b"\x89PNG\r\n\x1a\n".to_vec();
+        bytes.extend(png_chunk(b"IHDR", &ihdr));
+        bytes.extend(png_chunk(b"tEXt", b"Author\0someone"));
+        bytes.extend(png_chunk(b"IDAT", &[1, 2, 3]));
+        bytes.extend(png_chunk(b"IEND", &[]));
+        bytes
+    }
+
+    fn jpeg() -> Vec<u8> {
+        let mut bytes = vec![0xff, 0xd8];
+        bytes.extend_from_slice(&[0xff, 0xe1, 0x00, 0x08, b'E', b'x', b'i', b'f', 0, 0]);
+        bytes.extend_from_slice(&[0xff, 0xfe, 0x00, 0x04, b'h', b'i']);
+        // SOF0: 8 bits, 2 rows, 3 columns, one component.
+        bytes.extend_from_slice(&[0xff, 0xc0, 0x00, 0x0b, 8, 0, 2, 0, 3, 1, 1, 0x11, 0]);
+        bytes.extend_from_slice(&[0xff, 0xda, 0x00, 0x08, 1, 1, 0, 0, 63, 0, 0x12, 0x34, 0xff, 0xd9]);
+        bytes
+    }
+
+    #[test]
+    fn png_metadata_is_dropped() {
+        let image = strip_png(&png()).unwrap();
+        assert_eq!((image.width, image.height), (3, 2));
+        assert!(!image.bytes.windows(4).any(|w| w == b"tEXt"));
+        assert!(image.bytes.ends_with(&png_chunk(b"IEND", &[])));
+    }
+
+    #[test]
+    fn truncated_png_is_refused() {
+        let bytes = png();
+        for len in 8..bytes.len() {
+            assert!(strip_png(&bytes[..len]).is_none(), "accepted {} bytes", len);
+        }
+    }
+
+    #[test]
+    fn malformed_png_is_refused() {
+        let mut huge = b"\x89PNG\r\n\x1a\n".to_vec();
+        huge.extend_from_slice(&u32::MAX.to_be_bytes());
+        huge.extend_from_slice(b"IHDR");
+        assert!(strip_png(&huge).is_none());
+
+        let mut headless = b"\x89PNG\r\n\x1a\n".to_vec();
+        headless.extend(png_chunk(b"IEND", &[]));
+        assert!(strip_png(&headless).is_none());
+    }
+
+    #[test]
+    fn jpeg_metadata_is_dropped() {
+        let image = strip_jpeg(&jpeg()).unwrap();
+        assert_eq!((image.width, image.height), (3, 2));
+        assert!(!image.bytes.windows(4).any(|w| w == b"Exif"));
+        assert!(!image.bytes.windows(2).any(|w| w == [0xff, 0xfe]));
+    }
+
+    #[test]
+    fn truncated_jpeg_is_refused() {
+        let bytes = jpeg();
+        let scan = bytes.windows(2).position(|w| w == [0xff, 0xda]).unwrap();
+        for len in 2..scan {
+            assert!(strip_jpeg(&bytes[..len]).is_none(), "accepted {} bytes", len);
+        }
+    }
+
+    #[test]
+    fn malformed_jpeg_is_refused() {
+        // Garbage where a marker should be.
+        assert!(strip_jpeg(&[0xff, 0xd8, 0x12, 0x34]).is_none());
+        // A frame header too short to hold the size.
+        assert!(strip_jpeg(&[0xff, 0xd8, 0xff, 0xc0, 0x00, 0x03, 8, 0xff, 0xda, 0, 2]).is_none());
+        // A scan without a frame header.
+        assert!(strip_jpeg(&[0xff, 0xd8, 0xff, 0xda, 0x00, 0x02, 0xff, 0xd9]).is_none());
+    }
+
+    #[test]
+    fn size_comes_from_the_header() {
+        let mut ihdr = png();
+        ihdr[16..20].copy_from_slice(&(MAX_DIMENSION + 1).to_be_bytes());
+        assert_eq!(Image::parse(&ihdr).err(), Some(ImageError::TooLarge));
+
+        let mut sof = jpeg();
+        let at = sof.windows(2).position(|w| w == [0xff, 0xc0]).unwrap();
+        sof[at + 5..at + 7].copy_from_slice(&(MAX_DIMENSION as u16 + 1).to_be_bytes());
+        assert_eq!(Image::parse(&sof).err(), Some(ImageError::TooLarge));
+        sof[at + 5..at + 7].copy_from_slice(&0u16.to_be_bytes());
+        assert_eq!(Image::parse(&sof).err(), Some(ImageError::Malformed));
+
+        assert!(Image::parse(&png()).is_ok());
+        assert!(Image::parse(&jpeg()).is_ok());
+    }
+
+    #[test]
+    fn other_formats_are_refused() {
+        let webp = b"RIFF\x0c\0\0\0WEBPVP8L\0\0\0\0";
+        for bytes in [&webp[..], b"GIF89a\x01\0\x01\0", b"", b"\xff\xd8"] {
+            assert_eq!(Image::parse(bytes).err(), Some(ImageError::Unsupported));
+        }
+    }
+
+    #[tokio::test]
+    async fn unused_avatars_are_deleted() {
+        let dir = std::env::temp_dir().join(format!("rustrooms-avatars-{}", uuid::Uuid::new_v4().simple()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let shown = "a".repeat(64);
+        let unused = "b".repeat(64);
+        let fresh = "c".repeat(64);
+        let partial = format!("{}.1234.tmp", "d".repeat(64));
+        for name in [&shown, &unused, &fresh, &partial, &"unrelated".to_string()] {
+            std::fs::write(dir.join(name), b"x").unwrap();
+        }
+        // Everything was written just now; look at it once the grace period
+        // is over, by which time `fresh` has been uploaded again.
+        let later = SystemTime::now() + UNUSED_GRACE + Duration::from_secs(1);
+        std::fs::File::options().write(true).open(dir.join(&fresh)).unwrap().set_modified(later).unwrap();
+
+        let showing = HashSet::from([format!("{}{}", PATH_PREFIX, shown)]);
+        assert_eq!(sweep(&dir, &showing, later).await.unwrap(), 2);
+        let mut left: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        left.sort();
+        assert_eq!(left, [shown, fresh, "unrelated".to_string()]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
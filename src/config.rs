@@ -0,0 +1,555 @@
+use anyhow::{Context, Result};
+use clap::Parser;
+use ipnet::IpNet;
+use serde::Deserialize;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::turn_server::{PeerPolicy, Quotas, StreamLimits, TurnConfig, TurnTlsConfig, default_denied_peers};
+
+/// Raw settings as they come from the command line, the environment or the
+/// config file. Every field is optional so the three sources can be layered:
+/// command line flags win over environment variables, which win over the
+/// TOML file, which wins over the built-in defaults.
+#[derive(Parser, Deserialize, Debug, Default)]
+#[command(name = "rust_rooms", version, about = "Self-hosted video rooms with a built-in TURN server")]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+struct Options {
+    /// Path to a TOML config file
+    #[arg(long, short = 'c', env = "RUSTROOMS_CONFIG")]
+    #[serde(skip)]
+    config: Option<PathBuf>,
+
+    /// Address the HTTP server binds to [default: 0.0.0.0]
+    #[arg(long, env = "RUSTROOMS_HTTP_BIND")]
+    http_bind: Option<IpAddr>,
+
+    /// Port the HTTP server listens on [default: 3000]
+    #[arg(long, env = "RUSTROOMS_HTTP_PORT")]
+    http_port: Option<u16>,
+
+    /// PEM certificate chain. Serves HTTPS and WSS instead of plain HTTP when set with a key
+    #[arg(long, env = "RUSTROOMS_HTTP_TLS_CERT")]
+    http_tls_cert: Option<PathBuf>,
+
+    /// PEM private key for HTTPS
+    #[arg(long, env = "RUSTROOMS_HTTP_TLS_KEY")]
+    http_tls_key: Option<PathBuf>,
+
+    /// Plain HTTP port that redirects to HTTPS. Only used with HTTPS
+    #[arg(long, env = "RUSTROOMS_HTTP_REDIRECT_PORT")]
+    http_redirect_port: Option<u16>,
+
+    /// Address the TURN server binds to [default: 0.0.0.0]
+    #[arg(long, env = "RUSTROOMS_TURN_BIND")]
+    turn_bind: Option<IpAddr>,
+
+    /// UDP port the TURN server listens on [default: 3478]
+    #[arg(long, env = "RUSTROOMS_TURN_PORT")]
+    turn_port: Option<u16>,
+
+    /// Also accept TURN over TCP on the TURN port
+    #[arg(long, env = "RUSTROOMS_TURN_TCP", num_args = 0..=1, default_missing_value = "true")]
+    turn_tcp: Option<bool>,
+
+    /// Port for TURN over TLS (turns:). Only used when a certificate and key are set [default: 5349]
+    #[arg(long, env = "RUSTROOMS_TURN_TLS_PORT")]
+    turn_tls_port: Option<u16>,
+
+    /// PEM certificate chain for TURN over TLS
+    #[arg(long, env = "RUSTROOMS_TURN_TLS_CERT")]
+    turn_tls_cert: Option<PathBuf>,
+
+    /// PEM private key for TURN over TLS
+    #[arg(long, env = "RUSTROOMS_TURN_TLS_KEY")]
+    turn_tls_key: Option<PathBuf>,
+
+    /// Comma separated networks TURN clients may not relay to [default: private, loopback and link-local ranges]
+    #[arg(long, env = "RUSTROOMS_TURN_DENY_PEERS", value_delimiter = ',')]
+    turn_deny_peers: Option<Vec<IpNet>>,
+
+    /// Comma separated networks TURN clients may relay to even if they are denied
+    #[arg(long, env = "RUSTROOMS_TURN_ALLOW_PEERS", value_delimiter = ',')]
+    turn_allow_peers: Option<Vec<IpNet>>,
+
+    /// Concurrent TURN allocations per username, 0 for unlimited [default: 32]
+    #[arg(long, env = "RUSTROOMS_TURN_MAX_ALLOCATIONS_PER_USER")]
+    turn_max_allocations_per_user: Option<usize>,
+
+    /// Concurrent TURN allocations per client IP, 0 for unlimited [default: 64]
+    #[arg(long, env = "RUSTROOMS_TURN_MAX_ALLOCATIONS_PER_IP")]
+    turn_max_allocations_per_ip: Option<usize>,
+
+    /// Maximum age of a TURN allocation in seconds, refreshes included, 0 for unlimited [default: 86400]
+    #[arg(long, env = "RUSTROOMS_TURN_MAX_ALLOCATION_LIFETIME")]
+    turn_max_allocation_lifetime: Option<u64>,
+
+    /// Relay bandwidth per TURN allocation in kbit/s, 0 for unlimited [default: 0]
+    #[arg(long, env = "RUSTROOMS_TURN_ALLOCATION_BANDWIDTH")]
+    turn_allocation_bandwidth: Option<u64>,
+
+    /// Concurrent TURN connections over TCP and TLS, 0 for unlimited [default: 4096]
+    #[arg(long, env = "RUSTROOMS_TURN_MAX_CONNECTIONS")]
+    turn_max_connections: Option<usize>,
+
+    /// Concurrent TURN connections over TCP and TLS per client IP, 0 for unlimited [default: 64]
+    #[arg(long, env = "RUSTROOMS_TURN_MAX_CONNECTIONS_PER_IP")]
+    turn_max_connections_per_ip: Option<usize>,
+
+    /// Public IP advertised to clients as the TURN relay address. Detected from the bound interface when unset
+    #[arg(long, env = "RUSTROOMS_TURN_EXTERNAL_IP")]
+    turn_external_ip: Option<IpAddr>,
+
+    /// Lowest UDP port used for TURN relay allocations [default: 49152]
+    #[arg(long, env = "RUSTROOMS_TURN_RELAY_MIN_PORT")]
+    turn_relay_min_port: Option<u16>,
+
+    /// Highest UDP port used for TURN relay allocations [default: 65535]
+    #[arg(long, env = "RUSTROOMS_TURN_RELAY_MAX_PORT")]
+    turn_relay_max_port: Option<u16>,
+
+    /// Shared secret for signing ephemeral TURN credentials. A random one is generated when unset
+    #[arg(long, env = "RUSTROOMS_TURN_SECRET", hide_env_values = true)]
+    turn_secret: Option<String>,
+
+    /// Lifetime of issued TURN credentials in seconds [default: 3600]
+    #[arg(long, env = "RUSTROOMS_TURN_CREDENTIAL_TTL")]
+    turn_credential_ttl: Option<u64>,
+
+    /// TURN realm [default: rustrooms]
+    #[arg(long, env = "RUSTROOMS_REALM")]
+    realm: Option<String>,
+
+    /// Participants allowed in one room, 0 for unlimited. Hosts can lower it for their room [default: 8]
+    #[arg(long, env = "RUSTROOMS_MAX_PARTICIPANTS")]
+    max_participants: Option<usize>,
+
+    /// Seconds a participant whose connection dropped keeps their place in the room, 0 to remove them at once [default: 20]
+    #[arg(long, env = "RUSTROOMS_RESUME_GRACE")]
+    resume_grace: Option<u64>,
+
+    /// Chat messages each room keeps for participants who join later, 0 to keep none [default: 100]
+    #[arg(long, env = "RUSTROOMS_CHAT_HISTORY")]
+    chat_history: Option<usize>,
+
+    /// Directory uploaded avatars are stored in, created if missing [default: avatars]
+    #[arg(long, env = "RUSTROOMS_AVATAR_DIR")]
+    avatar_dir: Option<PathBuf>,
+
+    /// Directory files shared in rooms are kept in while their room is open. Leftovers are deleted at startup [default: files]
+    #[arg(long, env = "RUSTROOMS_FILE_DIR")]
+    file_dir: Option<PathBuf>,
+
+    /// Largest file participants can share, in megabytes, 0 to disable file sharing [default: 25]
+    #[arg(long, env = "RUSTROOMS_MAX_FILE_SIZE")]
+    max_file_size: Option<u64>,
+
+    /// Megabytes of shared files one room can hold at once [default: 100]
+    #[arg(long, env = "RUSTROOMS_ROOM_FILE_QUOTA")]
+    room_file_quota: Option<u64>,
+
+    /// Seconds a shared file is kept, 0 to keep it until the room closes [default: 3600]
+    #[arg(long, env = "RUSTROOMS_FILE_TTL")]
+    file_ttl: Option<u64>,
+
+    /// Seconds between WebSocket pings, 0 to disable them [default: 15]
+    #[arg(long, env = "RUSTROOMS_PING_INTERVAL")]
+    ping_interval: Option<u64>,
+
+    /// Unanswered pings in a row after which a connection counts as dead [default: 3]
+    #[arg(long, env = "RUSTROOMS_PING_MISSES")]
+    ping_misses: Option<u32>,
+
+    /// Seconds a new WebSocket gets to join a room before it is closed [default: 10]
+    #[arg(long, env = "RUSTROOMS_JOIN_TIMEOUT")]
+    join_timeout: Option<u64>,
+
+    /// Signaling messages queued per client before further ones are dropped [default: 500]
+    #[arg(long, env = "RUSTROOMS_SEND_QUEUE_SIZE")]
+    send_queue_size: Option<usize>,
+
+    /// Seconds a client's queue may stay full before it is disconnected [default: 10]
+    #[arg(long, env = "RUSTROOMS_SLOW_CLIENT_TIMEOUT")]
+    slow_client_timeout: Option<u64>,
+
+    /// Seconds to wait for participants to leave after a shutdown signal before closing the remaining connections [default: 10]
+    #[arg(long, env = "RUSTROOMS_SHUTDOWN_DRAIN")]
+    shutdown_drain: Option<u64>,
+
+    /// Seconds after which clients should try to reconnect when the server shuts down. Clients are not told to reconnect when unset
+    #[arg(long, env = "RUSTROOMS_SHUTDOWN_RECONNECT_AFTER")]
+    shutdown_reconnect_after: Option<u64>,
+
+    /// Bearer token for the /api/admin endpoints. They are disabled when unset
+    #[arg(long, env = "RUSTROOMS_ADMIN_TOKEN", hide_env_values = true)]
+    admin_token: Option<String>,
+
+    /// Port for a separate listener serving /metrics without the admin token. Without it, /metrics is served on the HTTP port to holders of the admin token
+    #[arg(long, env = "RUSTROOMS_METRICS_PORT")]
+    metrics_port: Option<u16>,
+
+    /// Address the metrics listener binds to [default: 127.0.0.1]
+    #[arg(long, env = "RUSTROOMS_METRICS_BIND")]
+    metrics_bind: Option<IpAddr>,
+
+    /// Hostname clients use to reach this server. Defaults to the host the page was loaded from
+    #[arg(long, env = "RUSTROOMS_PUBLIC_HOST")]
+    public_host: Option<String>,
+}
+
+impl Options {
+    /// Fills every unset field from `other`. The struct literal the macro
+    /// builds has to name every field, so one left out here does not
+    /// compile.
+    fn or(self, other: Options) -> Options {
+        macro_rules! merge {
+            ($($field:ident),* $(,)?) => {
+                Options {
+                    $($field: self.$field.or(other.$field),)*
+                }
+            };
+        }
+        merge!(
+            config, http_bind, http_port, http_tls_cert, http_tls_key, http_redirect_port, turn_bind, turn_port,
+            turn_tcp, turn_tls_port, turn_tls_cert, turn_tls_key, turn_deny_peers, turn_allow_peers,
+            turn_max_allocations_per_user, turn_max_allocations_per_ip, turn_max_allocation_lifetime,
+            turn_allocation_bandwidth, turn_max_connections, turn_max_connections_per_ip, turn_external_ip,
+            turn_relay_min_port, turn_relay_max_port, turn_secret, turn_credential_ttl, realm, max_participants,
+            resume_grace, chat_history, avatar_dir, file_dir, max_file_size, room_file_quota, file_ttl, ping_interval,
+            ping_misses, join_timeout, send_queue_size, slow_client_timeout, shutdown_drain, shutdown_reconnect_after,
+            admin_token, metrics_port, metrics_bind, public_host,
+        )
+    }
+}
+
+/// Fully resolved runtime configuration.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub http_addr: SocketAddr,
+    pub http_tls: Option<HttpTlsConfig>,
+    pub public_host: Option<String>,
+    pub admin_token: Option<String>,
+    /// Listener of its own for `/metrics`, if any.
+    pub metrics_addr: Option<SocketAddr>,
+    /// Default and upper bound for room sizes, 0 for unlimited.
+    pub max_participants: usize,
+    /// How long a dropped participant can come back with their resume token.
+    pub resume_grace: Duration,
+    /// Chat messages replayed to participants who join later.
+    pub chat_history: usize,
+    pub avatar_dir: PathBuf,
+    pub files: FileConfig,
+    pub keepalive: KeepaliveConfig,
+    pub outbox: OutboxConfig,
+    pub shutdown: ShutdownConfig,
+    pub turn: TurnConfig,
+}
+
+/// Certificate for serving HTTPS, and where to redirect plain HTTP from.
+#[derive(Debug, Clone)]
+pub struct HttpTlsConfig {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    pub redirect_port: Option<u16>,
+}
+
+/// Limits on files shared in rooms.
+#[derive(Debug, Clone)]
+pub struct FileConfig {
+    pub dir: PathBuf,
+    /// Largest single file in bytes, 0 when sharing is disabled.
+    pub max_file_size: u64,
+    /// Bytes of files one room can hold at once.
+    pub room_quota: u64,
+    /// `None` when files are kept until their room closes.
+    pub ttl: Option<Duration>,
+}
+
+/// How the server notices WebSocket connections that went away silently.
+#[derive(Debug, Clone)]
+pub struct KeepaliveConfig {
+    /// `None` when pings are disabled.
+    pub ping_interval: Option<Duration>,
+    /// Pings that may go unanswered before the connection is dropped.
+    pub max_missed_pings: u32,
+    /// Time a connection gets between upgrading and joining a room.
+    pub join_timeout: Duration,
+}
+
+/// Limits on messages waiting to be written to a client.
+#[derive(Debug, Clone)]
+pub struct OutboxConfig {
+    /// Capacity of a client's signal queue.
+    pub queue_size: usize,
+    /// How long a client may go without taking messages off a full queue.
+    pub stall_timeout: Duration,
+}
+
+/// What happens between a shutdown signal and the process exiting.
+#[derive(Debug, Clone)]
+pub struct ShutdownConfig {
+    /// How long participants get to leave before their sockets are closed.
+    pub drain: Duration,
+    /// Hint sent to clients on when the server is expected to be back.
+    pub reconnect_after: Option<Duration>,
+}
+
+impl Config {
+    /// Loads the configuration from the command line, the environment and,
+    /// if one was given, the config file.
+    pub fn load() -> Result<Config> {
+        Config::layered(Options::parse())
+    }
+
+    /// Resolves `cli`, which already holds the environment, on top of the
+    /// config file it names.
+    fn layered(cli: Options) -> Result<Config> {
+        let file = match &cli.config {
+            Some(path) => {
+                let raw = std::fs::read_to_string(path)
+                    .with_context(|| format!("failed to read config file {}", path.display()))?;
+                toml::from_str(&raw)
+                    .with_context(|| format!("failed to parse config file {}", path.display()))?
+            }
+            None => Options::default(),
+        };
+
+        Config::resolve(cli.or(file))
+    }
+
+    /// The built-in defaults, as if nothing was configured.
+    #[cfg(test)]
+    pub fn defaults() -> Config {
+        Config::resolve(Options::default()).expect("the defaults are valid")
+    }
+
+    fn resolve(opts: Options) -> Result<Config> {
+        let any = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+
+        let realm = opts.realm.unwrap_or_else(|| "rustrooms".to_string());
+        if realm.is_empty() {
+            anyhow::bail!("realm must not be empty");
+        }
+
+        let relay_min_port = opts.turn_relay_min_port.unwrap_or(49152);
+        let relay_max_port = opts.turn_relay_max_port.unwrap_or(65535);
+        if relay_min_port == 0 || relay_max_port < relay_min_port {
+            anyhow::bail!("invalid TURN relay port range {}-{}", relay_min_port, relay_max_port);
+        }
+
+        let http_tls = match (opts.http_tls_cert, opts.http_tls_key) {
+            (Some(cert_path), Some(key_path)) => Some(HttpTlsConfig {
+                cert_path,
+                key_path,
+                redirect_port: opts.http_redirect_port,
+            }),
+            (None, None) => {
+                if opts.http_redirect_port.is_some() {
+                    anyhow::bail!("redirecting to HTTPS needs a certificate and a private key");
+                }
+                None
+            }
+            _ => anyhow::bail!("HTTPS needs both a certificate and a private key"),
+        };
+
+        let tls = match (opts.turn_tls_cert, opts.turn_tls_key) {
+            (Some(cert_path), Some(key_path)) => Some(TurnTlsConfig {
+                port: opts.turn_tls_port.unwrap_or(5349),
+                cert_path,
+                key_path,
+            }),
+            (None, None) => None,
+            _ => anyhow::bail!("TURN over TLS needs both a certificate and a private key"),
+        };
+
+        let secret = opts.turn_secret.unwrap_or_else(|| Uuid::new_v4().simple().to_string());
+        if secret.is_empty() {
+            anyhow::bail!("TURN secret must not be empty");
+        }
+
+        let credential_ttl = opts.turn_credential_ttl.unwrap_or(3600);
+        if credential_ttl == 0 {
+            anyhow::bail!("TURN credential TTL must be at least one second");
+        }
+
+        let max_missed_pings = opts.ping_misses.unwrap_or(3);
+        if max_missed_pings == 0 {
+            anyhow::bail!("ping misses must be at least one");
+        }
+
+        let queue_size = opts.send_queue_size.unwrap_or(500);
+        if queue_size == 0 {
+            anyhow::bail!("send queue size must be at least one");
+        }
+
+        let public_host = opts.public_host.filter(|h| !h.is_empty());
+        if let Some(host) = &public_host
+            && !host.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | ':' | '[' | ']'))
+        {
+            anyhow::bail!("invalid public host {:?}", host);
+        }
+
+        Ok(Config {
+            http_addr: SocketAddr::new(opts.http_bind.unwrap_or(any), opts.http_port.unwrap_or(3000)),
+            http_tls,
+            public_host,
+            admin_token: opts.admin_token.filter(|t| !t.is_empty()),
+            metrics_addr: opts
+                .metrics_port
+                .map(|port| SocketAddr::new(opts.metrics_bind.unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST)), port)),
+            max_participants: opts.max_participants.unwrap_or(8),
+            resume_grace: Duration::from_secs(opts.resume_grace.unwrap_or(20)),
+            chat_history: opts.chat_history.unwrap_or(100),
+            avatar_dir: opts.avatar_dir.unwrap_or_else(|| PathBuf::from("avatars")),
+            files: FileConfig {
+                dir: opts.file_dir.unwrap_or_else(|| PathBuf::from("files")),
+                // Megabytes to bytes
+                max_file_size: opts.max_file_size.unwrap_or(25).saturating_mul(1024 * 1024),
+                room_quota: opts.room_file_quota.unwrap_or(100).saturating_mul(1024 * 1024),
+                ttl: Some(opts.file_ttl.unwrap_or(3600))
+                    .filter(|&secs| secs > 0)
+                    .map(Duration::from_secs),
+            },
+            keepalive: KeepaliveConfig {
+                ping_interval: Some(opts.ping_interval.unwrap_or(15))
+                    .filter(|&secs| secs > 0)
+                    .map(Duration::from_secs),
+                max_missed_pings,
+                join_timeout: Duration::from_secs(opts.join_timeout.unwrap_or(10)),
+            },
+            outbox: OutboxConfig {
+                queue_size,
+                stall_timeout: Duration::from_secs(opts.slow_client_timeout.unwrap_or(10)),
+            },
+            shutdown: ShutdownConfig {
+                drain: Duration::from_secs(opts.shutdown_drain.unwrap_or(10)),
+                reconnect_after: opts.shutdown_reconnect_after.map(Duration::from_secs),
+            },
+            turn: TurnConfig {
+                bind_addr: SocketAddr::new(opts.turn_bind.unwrap_or(any), opts.turn_port.unwrap_or(3478)),
+                realm,
+                secret,
+                credential_ttl: Duration::from_secs(credential_ttl),
+                external_ip: opts.turn_external_ip,
+                relay_min_port,
+                relay_max_port,
+                tcp: opts.turn_tcp.unwrap_or(false),
+                tls,
+                peer_policy: PeerPolicy {
+                    allow: opts.turn_allow_peers.unwrap_or_default(),
+                    deny: opts.turn_deny_peers.unwrap_or_else(default_denied_peers),
+                },
+                quotas: Quotas {
+                    max_allocations_per_user: opts.turn_max_allocations_per_user.unwrap_or(32),
+                    max_allocations_per_ip: opts.turn_max_allocations_per_ip.unwrap_or(64),
+                    max_allocation_lifetime: Some(opts.turn_max_allocation_lifetime.unwrap_or(86400))
+                        .filter(|&secs| secs > 0)
+                        .map(Duration::from_secs),
+                    // kbit/s to bytes per second
+                    allocation_bandwidth: Some(opts.turn_allocation_bandwidth.unwrap_or(0))
+                        .filter(|&kbps| kbps > 0)
+                        .map(|kbps| kbps * 1000 / 8),
+                },
+                stream_limits: StreamLimits {
+                    max_connections: opts.turn_max_connections.unwrap_or(4096),
+                    max_connections_per_ip: opts.turn_max_connections_per_ip.unwrap_or(64),
+                },
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Options {
+        Options::try_parse_from(["rust_rooms"].iter().chain(args)).unwrap()
+    }
+
+    fn config_file(contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("rustrooms-config-{}.toml", Uuid::new_v4().simple()));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn flags_beat_environment_beats_file_beats_defaults() {
+        let path = config_file(
+            r#"
+            http-port = 4000
+            realm = "from-file"
+            chat-history = 7
+            "#,
+        );
+        // SAFETY: no other test reads or writes this variable.
+        unsafe { std::env::set_var("RUSTROOMS_REALM", "from-env") };
+        let cli = parse(&["--config", path.to_str().unwrap(), "--http-port", "5000"]);
+        unsafe { std::env::remove_var("RUSTROOMS_REALM") };
+        let config = Config::layered(cli).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.http_addr.port(), 5000);
+        assert_eq!(config.turn.realm, "from-env");
+        assert_eq!(config.chat_history, 7);
+        assert_eq!(config.max_participants, 8);
+    }
+
+    #[test]
+    fn merge_keeps_every_field_that_is_set() {
+        let file: Options = toml::from_str(
+            r#"
+            turn-max-connections = 3
+            public-host = "rooms.example.com"
+            turn-deny-peers = ["10.0.0.0/8"]
+            "#,
+        )
+        .unwrap();
+        let merged = parse(&["--turn-max-connections", "9", "--admin-token", "secret"]).or(file);
+        assert_eq!(merged.turn_max_connections, Some(9));
+        assert_eq!(merged.admin_token.as_deref(), Some("secret"));
+        assert_eq!(merged.public_host.as_deref(), Some("rooms.example.com"));
+        assert_eq!(merged.turn_deny_peers, Some(vec!["10.0.0.0/8".parse().unwrap()]));
+    }
+
+    #[test]
+    fn unknown_file_keys_are_refused() {
+        let err = toml::from_str::<Options>("http-prot = 80").unwrap_err();
+        assert!(err.to_string().contains("unknown field"), "{err}");
+        // The path to the config file is only taken from the command line.
+        assert!(toml::from_str::<Options>("config = \"other.toml\"").is_err());
+    }
+
+    #[test]
+    fn broken_config_file_is_an_error() {
+        let path = config_file("http-port = \"eighty\"");
+        let err = Config::layered(parse(&["--config", path.to_str().unwrap()])).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert!(format!("{err:#}").contains("failed to parse config file"), "{err:#}");
+    }
+
+    #[test]
+    fn redirect_needs_https() {
+        let err = Config::resolve(parse(&["--http-redirect-port", "80"])).unwrap_err();
+        assert!(err.to_string().contains("needs a certificate"), "{err}");
+
+        let opts = parse(&["--http-tls-cert", "cert.pem", "--http-tls-key", "key.pem", "--http-redirect-port", "80"]);
+        let tls = Config::resolve(opts).unwrap().http_tls.unwrap();
+        assert_eq!(tls.redirect_port, Some(80));
+    }
+
+    #[test]
+    fn invalid_values_are_refused() {
+        for args in [
+            &["--turn-relay-min-port", "6000", "--turn-relay-max-port", "5000"][..],
+            &["--turn-credential-ttl", "0"],
+            &["--send-queue-size", "0"],
+            &["--public-host", "evil.example/<script>"],
+            &["--http-tls-cert", "cert.pem"],
+        ] {
+            assert!(Config::resolve(parse(args)).is_err(), "{args:?}");
+        }
+    }
+}
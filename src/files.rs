@@ -0,0 +1,477 @@
+//! Files shared in a room. Uploads and downloads go over HTTP and carry the
+//! participant's resume token as a bearer token, so only people in the room
+//! get at its files. The room keeps track of what was shared and announces
+//! it; the files themselves live on disk until they expire or the room
+//! closes.
+
+use axum::{
+    body::Body,
+    extract::{DefaultBodyLimit, Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use futures::StreamExt;
+use serde::Deserialize;
+use std::fmt::Write as _;
+use std::path::PathBuf;
+use tokio::io::AsyncWriteExt;
+use tokio_util::io::ReaderStream;
+use uuid::Uuid;
+
+use crate::admin::bearer_token;
+use crate::config::FileConfig;
+use crate::room::{FileRefusal, RoomHandle, Upload};
+use crate::AppState;
+
+const MAX_NAME_LEN: usize = 255;
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        // Uploads are streamed to disk and limited there.
+        .route("/api/rooms/:room_id/files", post(upload).layer(DefaultBodyLimit::disable()))
+        .route("/api/rooms/:room_id/files/:file_id", get(download))
+}
+
+/// Creates the file directory and deletes files left over from an earlier
+/// run, whose rooms are gone.
+pub fn prepare(config: &FileConfig) -> std::io::Result<()> {
+    std::fs::create_dir_all(&config.dir)?;
+    for entry in std::fs::read_dir(&config.dir)? {
+        let entry = entry?;
+        if entry.file_name().to_str().is_some_and(valid_id) {
+            std::fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+fn path(config: &FileConfig, id: &str) -> PathBuf {
+    config.dir.join(id)
+}
+
+pub async fn remove(config: &FileConfig, id: &str) {
+    if let Err(e) = tokio::fs::remove_file(path(config, id)).await {
+        eprintln!("failed to delete shared file {}: {}", id, e);
+    }
+}
+
+fn valid_id(id: &str) -> bool {
+    id.len() == 32 && id.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+fn refused(refusal: FileRefusal) -> Response {
+    match refusal {
+        FileRefusal::NotParticipant => (StatusCode::FORBIDDEN, "Only participants of the room can do that").into_response(),
+        FileRefusal::QuotaExceeded => {
+            (StatusCode::PAYLOAD_TOO_LARGE, "The room has no space left for this file").into_response()
+        }
+        FileRefusal::TooManyUploads => {
+            (StatusCode::TOO_MANY_REQUESTS, "Wait for your other uploads to finish").into_response()
+        }
+        FileRefusal::NoSuchFile => (StatusCode::NOT_FOUND, "The file has expired").into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct UploadQuery {
+    name: Option<String>,
+}
+
+/// Takes the file as the request body and its name from the `name` query
+/// parameter.
+async fn upload(
+    State(state): State<AppState>,
+    Path(room_id): Path<String>,
+    Query(query): Query<UploadQuery>,
+    headers: HeaderMap,
+    body: Body,
+) -> Response {
+    let config = &state.config.files;
+    if config.max_file_size == 0 {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    let Some(token) = bearer_token(&headers) else {
+        return refused(FileRefusal::NotParticipant);
+    };
+    let Some(room) = state.rooms.get(&room_id) else {
+        return refused(FileRefusal::NotParticipant);
+    };
+
+    // A body without a length may be as large as any file.
+    let limit = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(config.max_file_size);
+    if limit > config.max_file_size {
+        return too_large(config);
+    }
+    // The room sets the space aside before anything is written, so uploads
+    // running side by side cannot take more than the quota between them.
+    let id = Uuid::new_v4().simple().to_string();
+    if let Err(refusal) = room.reserve_upload(token, &id, limit).await {
+        return refused(refusal);
+    }
+    let _reservation = Reservation {
+        room: room.clone(),
+        id: id.clone(),
+    };
+
+    let size = match receive(body, &path(config, &id), limit).await {
+        Ok(size) => size,
+        Err(failure) => {
+            let _ = tokio::fs::remove_file(path(config, &id)).await;
+            return match failure {
+                Failure::TooLarge => too_large(config),
+                Failure::Empty => (StatusCode::BAD_REQUEST, "The file is empty").into_response(),
+                Failure::Aborted => StatusCode::BAD_REQUEST.into_response(),
+                Failure::Io(e) => {
+                    eprintln!("failed to store shared file {}: {}", id, e);
+                    StatusCode::INTERNAL_SERVER_ERROR.into_response()
+                }
+            };
+        }
+    };
+
+    let upload = Upload {
+        id: id.clone(),
+        name: clean_name(query.name.as_deref().unwrap_or("")),
+        size,
+    };
+    match room.add_file(token, upload).await {
+        Ok(file) => (StatusCode::CREATED, Json(file)).into_response(),
+        Err(refusal) => {
+            remove(config, &id).await;
+            refused(refusal)
+        }
+    }
+}
+
+/// Gives the room back the space set aside for an upload however the
+/// upload ends, including the client going away mid-request. Once the file
+/// has been added there is nothing left to give back.
+struct Reservation {
+    room: RoomHandle,
+    id: String,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        let room = self.room.clone();
+        let id = std::mem::take(&mut self.id);
+        tokio::spawn(async move { room.release_upload(&id).await });
+    }
+}
+
+fn too_large(config: &FileConfig) -> Response {
+    let message = format!("Files are limited to {} MB", config.max_file_size / (1024 * 1024));
+    (StatusCode::PAYLOAD_TOO_LARGE, message).into_response()
+}
+
+enum Failure {
+    TooLarge,
+    Empty,
+    /// The client stopped sending.
+    Aborted,
+    Io(std::io::Error),
+}
+
+impl From<std::io::Error> for Failure {
+    fn from(e: std::io::Error) -> Failure {
+        Failure::Io(e)
+    }
+}
+
+/// Writes the body to `path`, giving up once it grows past `limit`.
+/// Returns its size.
+async fn receive(body: Body, path: &std::path::Path, limit: u64) -> Result<u64, Failure> {
+    let mut file = tokio::fs::File::create(path).await?;
+    let mut stream = body.into_data_stream();
+    let mut size = 0u64;
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|_| Failure::Aborted)?;
+        size += chunk.len() as u64;
+        if size > limit {
+            return Err(Failure::TooLarge);
+        }
+        file.write_all(&chunk).await?;
+    }
+    file.flush().await?;
+    if size == 0 {
+        return Err(Failure::Empty);
+    }
+    Ok(size)
+}
+
+async fn download(
+    State(state): State<AppState>,
+    Path((room_id, file_id)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(token) = bearer_token(&headers) else {
+        return refused(FileRefusal::NotParticipant);
+    };
+    let Some(room) = state.rooms.get(&room_id) else {
+        return refused(FileRefusal::NotParticipant);
+    };
+    let file = match room.file(token, &file_id).await {
+        Ok(file) => file,
+        Err(refusal) => return refused(refusal),
+    };
+    let Ok(stored) = tokio::fs::File::open(path(&state.config.files, &file.id)).await else {
+        return refused(FileRefusal::NoSuchFile);
+    };
+
+    (
+        [
+            // Always downloaded, never shown by the browser.
+            (header::CONTENT_TYPE, "application/octet-stream".to_string()),
+            (header::CONTENT_DISPOSITION, content_disposition(&file.name)),
+            (header::CONTENT_LENGTH, file.size.to_string()),
+            (header::CACHE_CONTROL, "private, no-store".to_string()),
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+        ],
+        Body::from_stream(ReaderStream::new(stored)),
+    )
+        .into_response()
+}
+
+/// The last path component of a client's file name, without control
+/// characters and limited in length.
+fn clean_name(name: &str) -> String {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or("");
+    let name: String = name.chars().filter(|c| !c.is_control()).take(MAX_NAME_LEN).collect();
+    let name = name.trim();
+    if name.is_empty() || name == "." || name == ".." {
+        "file".to_string()
+    } else {
+        name.to_string()
+    }
+}
+
+/// An attachment header carrying the name as UTF-8 (RFC 6266).
+fn content_disposition(name: &str) -> String {
+    let mut value = String::from("attachment; filename*=UTF-8''");
+    for b in name.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            value.push(b as char);
+        } else {
+            let _ = write!(value, "%{:02X}", b);
+        }
+    }
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::metrics::Metrics;
+    use crate::outbox::{self, Outbox, SendStats};
+    use crate::password::AttemptThrottle;
+    use crate::protocol::Presence;
+    use crate::room::{JoinReply, JoinRequest, Participant, RoomMap};
+    use axum::body::Bytes;
+    use axum::extract::ws::Message;
+    use futures::channel::mpsc::UnboundedReceiver;
+    use serde_json::Value;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// A file directory of its own, deleted afterwards.
+    struct Dir(PathBuf);
+
+    impl Drop for Dir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// What the handlers run against, with TURN on a loopback port.
+    async fn state(configure: impl FnOnce(&mut FileConfig)) -> (AppState, Dir) {
+        let mut config = Config::defaults();
+        let dir = Dir(std::env::temp_dir().join(format!("rustrooms-files-{}", Uuid::new_v4().simple())));
+        config.files.dir = dir.0.clone();
+        configure(&mut config.files);
+        prepare(&config.files).unwrap();
+        config.turn.bind_addr = "127.0.0.1:0".parse().unwrap();
+        config.turn.external_ip = Some(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        config.turn.tcp = false;
+        config.turn.tls = None;
+
+        let config = Arc::new(config);
+        let metrics = Arc::new(Metrics::default());
+        let state = AppState {
+            config: config.clone(),
+            rooms: RoomMap::new(config.clone(), metrics.clone()),
+            turn: Arc::new(crate::turn_server::spawn(config.turn.clone()).await.unwrap()),
+            password_attempts: Arc::new(AttemptThrottle::default()),
+            send_stats: Arc::new(SendStats::default()),
+            metrics,
+        };
+        (state, dir)
+    }
+
+    /// One participant's connection and the resume token they were given.
+    struct Member {
+        token: String,
+        frames: UnboundedReceiver<Message>,
+        _participant: Participant,
+    }
+
+    impl Member {
+        /// The `type`s of everything sent to the participant so far.
+        async fn types(&mut self) -> Vec<String> {
+            let mut types = Vec::new();
+            while let Ok(Some(frame)) = tokio::time::timeout(Duration::from_millis(50), self.frames.next()).await {
+                if let Message::Text(text) = frame {
+                    let msg: Value = serde_json::from_str(&text).unwrap();
+                    types.push(msg["type"].as_str().unwrap().to_string());
+                }
+            }
+            types
+        }
+    }
+
+    /// Puts `user_id` in the room `room_id`, opening it if needed.
+    async fn enter(state: &AppState, room_id: &str, user_id: &str) -> Member {
+        let (outbox, queues) = Outbox::new(64, Duration::from_secs(60), Arc::new(SendStats::default()));
+        let (sink, mut frames) = futures::channel::mpsc::unbounded();
+        tokio::spawn(outbox::write(sink, queues, Arc::new(Metrics::default())));
+        let participant = Participant::new(Arc::new(outbox));
+        let request = JoinRequest {
+            user_id: user_id.to_string(),
+            password: None,
+            resume_token: None,
+            presence: Presence::default(),
+        };
+        match state.rooms.get(room_id) {
+            Some(room) => assert!(matches!(room.join(request, participant.clone(), None).await, Some(JoinReply::Admitted))),
+            None => assert!(state.rooms.create(room_id, request, participant.clone(), None).is_some()),
+        }
+        let Some(Message::Text(welcome)) = frames.next().await else {
+            panic!("expected the welcome first");
+        };
+        let welcome: Value = serde_json::from_str(&welcome).unwrap();
+        Member {
+            token: welcome["data"]["resumeToken"].as_str().unwrap().to_string(),
+            frames,
+            _participant: participant,
+        }
+    }
+
+    fn authorization(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, format!("Bearer {}", token).parse().unwrap());
+        headers
+    }
+
+    async fn upload_as(state: &AppState, room_id: &str, headers: HeaderMap, body: Body) -> Response {
+        let query = UploadQuery {
+            name: Some("notes.txt".to_string()),
+        };
+        upload(State(state.clone()), Path(room_id.to_string()), Query(query), headers, body).await
+    }
+
+    async fn download_as(state: &AppState, room_id: &str, file_id: &str, headers: HeaderMap) -> Response {
+        download(State(state.clone()), Path((room_id.to_string(), file_id.to_string())), headers).await
+    }
+
+    async fn file_id(response: Response) -> String {
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let file: Value = serde_json::from_slice(&body).unwrap();
+        file["id"].as_str().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn only_participants_of_the_room_get_at_its_files() {
+        let (state, _dir) = state(|_| {}).await;
+        let alice = enter(&state, "r", "alice").await;
+        let bob = enter(&state, "r", "bob").await;
+        let mallory = enter(&state, "elsewhere", "mallory").await;
+
+        let refused = upload_as(&state, "r", HeaderMap::new(), Body::from("hello")).await;
+        assert_eq!(refused.status(), StatusCode::FORBIDDEN);
+        let refused = upload_as(&state, "r", authorization("guess"), Body::from("hello")).await;
+        assert_eq!(refused.status(), StatusCode::FORBIDDEN);
+        // A token from another room does not carry over.
+        let refused = upload_as(&state, "r", authorization(&mallory.token), Body::from("hello")).await;
+        assert_eq!(refused.status(), StatusCode::FORBIDDEN);
+
+        let id = file_id(upload_as(&state, "r", authorization(&alice.token), Body::from("hello")).await).await;
+        for headers in [HeaderMap::new(), authorization("guess"), authorization(&mallory.token)] {
+            assert_eq!(download_as(&state, "r", &id, headers).await.status(), StatusCode::FORBIDDEN);
+        }
+        let response = download_as(&state, "r", &id, authorization(&bob.token)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_DISPOSITION], "attachment; filename*=UTF-8''notes.txt");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"hello");
+    }
+
+    #[tokio::test]
+    async fn failed_uploads_give_back_their_reservation() {
+        let (state, _dir) = state(|files| files.room_quota = 10).await;
+        let alice = enter(&state, "r", "alice").await;
+        let room = state.rooms.get("r").unwrap();
+
+        // The client goes away after sending part of the file.
+        let mut headers = authorization(&alice.token);
+        headers.insert(header::CONTENT_LENGTH, "10".parse().unwrap());
+        let chunks: Vec<Result<Bytes, std::io::Error>> =
+            vec![Ok(Bytes::from_static(b"hello")), Err(std::io::ErrorKind::ConnectionReset.into())];
+        let aborted = upload_as(&state, "r", headers, Body::from_stream(futures::stream::iter(chunks))).await;
+        assert_eq!(aborted.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(std::fs::read_dir(&state.config.files.dir).unwrap().count(), 0);
+
+        // The release is sent from the reservation's drop.
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(room.reserve_upload(&alice.token, "next", 10).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn reservation_is_released_when_dropped() {
+        let (state, _dir) = state(|files| files.room_quota = 10).await;
+        let alice = enter(&state, "r", "alice").await;
+        let room = state.rooms.get("r").unwrap();
+
+        assert!(room.reserve_upload(&alice.token, "first", 10).await.is_ok());
+        assert!(matches!(room.reserve_upload(&alice.token, "second", 1).await, Err(FileRefusal::QuotaExceeded)));
+        drop(Reservation {
+            room: room.clone(),
+            id: "first".to_string(),
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(room.reserve_upload(&alice.token, "second", 10).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn files_expire_after_their_ttl() {
+        let (state, _dir) = state(|files| files.ttl = Some(Duration::from_millis(100))).await;
+        let mut alice = enter(&state, "r", "alice").await;
+        let id = file_id(upload_as(&state, "r", authorization(&alice.token), Body::from("hello")).await).await;
+        assert!(path(&state.config.files, &id).exists());
+        assert!(alice.types().await.contains(&"file-shared".to_string()));
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert!(alice.types().await.contains(&"file-removed".to_string()));
+        let expired = download_as(&state, "r", &id, authorization(&alice.token)).await;
+        assert_eq!(expired.status(), StatusCode::NOT_FOUND);
+        assert!(!path(&state.config.files, &id).exists());
+    }
+
+    #[test]
+    fn names_are_cleaned() {
+        assert_eq!(clean_name("../../etc/passwd"), "passwd");
+        assert_eq!(clean_name("C:\\Users\\me\\report.pdf"), "report.pdf");
+        assert_eq!(clean_name("a\u{0}b\nc"), "abc");
+        assert_eq!(clean_name(".."), "file");
+        assert_eq!(clean_name("   "), "file");
+        assert_eq!(clean_name(&"x".repeat(1000)).len(), MAX_NAME_LEN);
+        assert_eq!(content_disposition("a b\"ü.txt"), "attachment; filename*=UTF-8''a%20b%22%C3%BC.txt");
+    }
+}
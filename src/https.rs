@@ -0,0 +1,89 @@
+use axum::{
+    extract::{ConnectInfo, Request},
+    http::{header, uri::Authority, HeaderMap, StatusCode, Uri},
+    response::{IntoResponse, Redirect, Response},
+    Extension, Router,
+};
+use hyper::server::conn::http1;
+use hyper_util::{rt::TokioIo, service::TowerToHyperService};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+
+use crate::tls::ReloadableConfig;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Serves `app` over TLS until `shutdown` is cancelled. Like `axum::serve`,
+/// connections that were upgraded to WebSockets are left to their handlers.
+pub async fn serve(listener: TcpListener, app: Router, tls: Arc<ReloadableConfig>, shutdown: CancellationToken) {
+    loop {
+        let (stream, remote_addr) = tokio::select! {
+            res = listener.accept() => match res {
+                Ok(v) => v,
+                Err(e) => {
+                    eprintln!("HTTPS accept error: {}", e);
+                    continue;
+                }
+            },
+            _ = shutdown.cancelled() => break,
+        };
+        let _ = stream.set_nodelay(true);
+
+        let acceptor = tls.acceptor();
+        let service = TowerToHyperService::new(app.clone().layer(Extension(ConnectInfo(remote_addr))));
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            // Failed handshakes are routine (scanners, clients rejecting the
+            // certificate) and are dropped without logging.
+            let Ok(Ok(stream)) = tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await else {
+                return;
+            };
+
+            let conn = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .with_upgrades();
+            tokio::pin!(conn);
+            tokio::select! {
+                _ = conn.as_mut() => {}
+                _ = shutdown.cancelled() => {
+                    conn.as_mut().graceful_shutdown();
+                    let _ = conn.await;
+                }
+            }
+        });
+    }
+}
+
+/// Router that sends every plain HTTP request to the same path on the HTTPS
+/// listener.
+pub fn redirect_router(https_port: u16, public_host: Option<String>) -> Router {
+    Router::new().fallback(move |headers: HeaderMap, req: Request| async move {
+        redirect_to_https(&headers, req.uri(), https_port, public_host.as_deref())
+    })
+}
+
+fn redirect_to_https(headers: &HeaderMap, uri: &Uri, https_port: u16, public_host: Option<&str>) -> Response {
+    let host = match public_host {
+        Some(host) => host.to_string(),
+        None => {
+            let authority = headers
+                .get(header::HOST)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse::<Authority>().ok());
+            match authority {
+                Some(authority) => authority.host().to_string(),
+                None => return (StatusCode::BAD_REQUEST, "Missing Host header").into_response(),
+            }
+        }
+    };
+
+    let authority = if https_port == 443 {
+        host
+    } else {
+        format!("{}:{}", host, https_port)
+    };
+    let path = uri.path_and_query().map(|pq| pq.as_str()).unwrap_or("/");
+    Redirect::permanent(&format!("https://{}{}", authority, path)).into_response()
+}
@@ -1,22 +1,40 @@
+mod admin;
+mod avatar;
+mod config;
+mod files;
+mod https;
+mod metrics;
+mod outbox;
+mod password;
+mod protocol;
+mod room;
+mod tls;
 mod turn_server;
 
 use axum::{
     extract::{
-        ws::{Message, WebSocket, WebSocketUpgrade},
-        Path, State,
+        ws::{close_code, Message, WebSocket, WebSocketUpgrade},
+        ConnectInfo, Path, State,
     },
     http::header,
     response::{Html, IntoResponse, Redirect},
     routing::get,
     Router,
 };
-use futures::{sink::SinkExt, stream::StreamExt};
-use serde::{Deserialize, Serialize};
+use futures::stream::StreamExt;
+use config::Config;
+use metrics::Metrics;
+use outbox::{Outbox, Priority, SendStats};
+use password::{AttemptThrottle, PasswordHash, MAX_PASSWORD_LEN};
+use protocol::{error_message, ClientMessage, ErrorCode, ServerMessage, ShutdownNotice, PROTOCOL_VERSION};
+use room::{JoinRefusal, JoinReply, JoinRequest, Participant, RoomHandle, RoomMap};
+use turn_server::{AllocationEvent, TurnConfig, TurnHandle};
 use std::{
-    collections::HashMap,
+    net::{IpAddr, SocketAddr},
     sync::Arc,
+    time::{Duration, Instant},
 };
-use tokio::sync::Mutex;
+use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 
 async fn rnnoise_js() -> impl IntoResponse {
@@ -33,7 +51,7 @@ async fn rnnoise_processor_js() -> impl IntoResponse {
     )
 }
 
-fn get_html_page(turn_user: &str, turn_pass: &str) -> String {
+fn get_html_page(config: &Config) -> String {
     let html = r###"
 <!DOCTYPE html>
 <html lang="en">
@@ -381,6 +399,11 @@ fn get_html_page(turn_user: &str, turn_pass: &str) -> String {
                             <label class="block text-xs font-medium text-slate-400 mb-1">Nickname</label>
                             <input type="text" id="nicknameInput" placeholder="Enter your name" class="w-full bg-slate-800 border border-slate-600 rounded-lg px-4 py-2 text-white placeholder-slate-500 focus:outline-none focus:ring-2 focus:ring-blue-500 transition-all" maxlength="32">
                         </div>
+
+                        <div>
+                            <label class="block text-xs font-medium text-slate-400 mb-1">Room Password</label>
+                            <input type="password" id="roomPasswordInput" placeholder="Optional" autocomplete="off" class="w-full bg-slate-800 border border-slate-600 rounded-lg px-4 py-2 text-white placeholder-slate-500 focus:outline-none focus:ring-2 focus:ring-blue-500 transition-all" maxlength="128">
+                        </div>
                         
                         <div class="grid grid-cols-1 gap-3">
                              <div>
@@ -427,6 +450,17 @@ fn get_html_page(turn_user: &str, turn_pass: &str) -> String {
         </div>
     </div>
 
+    <div id="passwordOverlay" class="fixed inset-0 z-[80] bg-black/80 flex items-center justify-center p-4 hidden">
+        <div class="glass-panel p-6 rounded-2xl max-w-sm w-full shadow-2xl space-y-4 border border-slate-700">
+            <h2 class="text-xl font-bold text-white">Password Required</h2>
+            <p id="passwordMessage" class="text-slate-400 text-sm"></p>
+            <input type="password" id="passwordRetryInput" placeholder="Room password" autocomplete="off" class="w-full bg-slate-800 border border-slate-600 rounded-lg px-4 py-2 text-white placeholder-slate-500 focus:outline-none focus:ring-2 focus:ring-blue-500 transition-all" maxlength="128" onkeydown="if (event.key === 'Enter') submitRoomPassword()">
+            <button onclick="submitRoomPassword()" class="w-full py-3 bg-blue-600 hover:bg-blue-500 text-white rounded-lg font-bold shadow-lg shadow-blue-500/30 transition-all">
+                Join
+            </button>
+        </div>
+    </div>
+
     <div id="settingsOverlay" class="fixed inset-0 z-[80] bg-black/80 flex items-center justify-center p-4 hidden">
         <div class="glass-panel p-6 rounded-2xl max-w-md w-full shadow-2xl space-y-6 border border-slate-700 relative max-h-[90vh] overflow-y-auto">
              <button onclick="closeSettings()" class="absolute top-4 right-4 text-slate-400 hover:text-white">
@@ -474,6 +508,11 @@ fn get_html_page(turn_user: &str, turn_pass: &str) -> String {
                         </select>
                     </div>
                 </div>
+
+                <div class="host-only hidden">
+                    <label class="block text-xs font-medium text-slate-400 mb-1">Room Capacity</label>
+                    <input type="number" id="settingsCapacityInput" min="0" placeholder="Unlimited" class="w-full bg-slate-800 border border-slate-600 rounded-lg px-4 py-2 text-white placeholder-slate-500 focus:outline-none focus:ring-2 focus:ring-blue-500 transition-all">
+                </div>
             </div>
 
             <div class="pt-2">
@@ -484,6 +523,27 @@ fn get_html_page(turn_user: &str, turn_pass: &str) -> String {
         </div>
     </div>
 
+    <div id="knockList" class="fixed top-16 right-3 md:right-4 z-[75] space-y-2 w-72 max-w-[calc(100vw-1.5rem)]"></div>
+
+    <div id="chatPanel" class="fixed top-16 bottom-24 md:bottom-28 right-3 md:right-4 z-[70] w-80 max-w-[calc(100vw-1.5rem)] glass-panel rounded-2xl border border-slate-700 shadow-2xl flex-col hidden">
+        <div class="flex items-center justify-between px-4 py-3 border-b border-slate-700">
+            <span class="text-sm font-bold text-white">Chat</span>
+            <button onclick="toggleChat()" class="text-slate-400 hover:text-white" title="Close Chat">✕</button>
+        </div>
+        <div id="chatMessages" class="flex-1 overflow-y-auto px-4 py-3 space-y-3 text-sm"></div>
+        <form onsubmit="sendChat(event)" class="p-3 border-t border-slate-700 space-y-2">
+            <select id="chatTarget" class="w-full bg-slate-800 border border-slate-700 text-white text-xs rounded-lg p-2 focus:ring-blue-500 focus:border-blue-500">
+                <option value="">Everyone</option>
+            </select>
+            <div class="flex gap-2">
+                <button type="button" id="btnShareFile" onclick="document.getElementById('shareFileInput').click()" class="px-2 rounded-lg bg-slate-700 hover:bg-slate-600 text-white text-sm" title="Share a File">📎</button>
+                <input type="file" id="shareFileInput" hidden onchange="shareFile(this)">
+                <input id="chatInput" type="text" maxlength="{{MAX_CHAT_LEN}}" autocomplete="off" placeholder="Message" class="flex-1 min-w-0 bg-slate-800 border border-slate-700 text-white text-sm rounded-lg p-2 focus:ring-blue-500 focus:border-blue-500">
+                <button type="submit" class="px-3 rounded-lg bg-blue-600 hover:bg-blue-500 text-white text-xs font-bold">Send</button>
+            </div>
+        </form>
+    </div>
+
     <div id="appLayout" class="hidden flex-col h-full w-full">
         <div class="flex-none p-3 md:p-4 z-40 flex justify-between items-center">
             <div class="glass-panel px-3 py-1.5 md:px-4 md:py-2 rounded-full flex items-center gap-2">
@@ -544,9 +604,22 @@ fn get_html_page(turn_user: &str, turn_pass: &str) -> String {
                 <button class="control-btn hover:text-blue-400" id="btnShare" onclick="toggleScreen()" title="Share Screen">
                     <svg xmlns="http://www.w3.org/2000/svg" width="20" height="20" viewBox="0 0 24 24" fill="none" stroke="currentColor" stroke-width="2" stroke-linecap="round" stroke-linejoin="round"><rect width="20" height="14" x="2" y="3" rx="2"/><line x1="8" x2="16" y1="21" y2="21"/><line x1="12" x2="12" y1="17" y2="21"/></svg>
                 </button>
+                <button class="control-btn hover:text-blue-400 relative" id="btnChat" onclick="toggleChat()" title="Chat">
+                    <svg xmlns="http://www.w3.org/2000/svg" width="20" height="20" viewBox="0 0 24 24" fill="none" stroke="currentColor" stroke-width="2" stroke-linecap="round" stroke-linejoin="round"><path d="M21 15a2 2 0 0 1-2 2H7l-4 4V5a2 2 0 0 1 2-2h14a2 2 0 0 1 2 2z"/></svg>
+                    <span id="chatBadge" class="hidden absolute -top-1 -right-1 min-w-[18px] h-[18px] px-1 rounded-full bg-red-500 text-[10px] font-bold leading-[18px] text-center"></span>
+                </button>
                 <button class="control-btn hover:text-blue-400" onclick="openSettings()" title="Settings">
                     <svg xmlns="http://www.w3.org/2000/svg" width="20" height="20" viewBox="0 0 24 24" fill="none" stroke="currentColor" stroke-width="2" stroke-linecap="round" stroke-linejoin="round"><circle cx="12" cy="12" r="3"></circle><path d="M19.4 15a1.65 1.65 0 0 0 .33 1.82l.06.06a2 2 0 0 1 0 2.83 2 2 0 0 1-2.83 0l-.06-.06a1.65 1.65 0 0 0-1.82-.33 1.65 1.65 0 0 0-1 1.51V21a2 2 0 0 1-2 2 2 2 0 0 1-2-2v-.09A1.65 1.65 0 0 0 9 19.4a1.65 1.65 0 0 0-1.82.33l-.06.06a2 2 0 0 1-2.83 0 2 2 0 0 1 0-2.83l.06.06a1.65 1.65 0 0 0 .33-1.82 1.65 1.65 0 0 0-1.51-1H3a2 2 0 0 1-2-2 2 2 0 0 1 2-2h.09A1.65 1.65 0 0 0 4.6 9a1.65 1.65 0 0 0-.33-1.82l-.06-.06a2 2 0 0 1 0-2.83 2 2 0 0 1 2.83 0l.06.06a1.65 1.65 0 0 0 1.82.33H9a1.65 1.65 0 0 0 1-1.51V3a2 2 0 0 1 2-2 2 2 0 0 1 2 2v.09a1.65 1.65 0 0 0 1 1.51 1.65 1.65 0 0 0 1.82-.33l.06-.06a2 2 0 0 1 2.83 0 2 2 0 0 1 0 2.83l-.06.06a1.65 1.65 0 0 0-.33 1.82V9a1.65 1.65 0 0 0 1.51 1H21a2 2 0 0 1 2 2 2 2 0 0 1-2 2h-.09a1.65 1.65 0 0 0-1.51 1z"></path></svg>
                 </button>
+                <button class="control-btn hover:text-blue-400 host-only hidden" id="btnLock" onclick="toggleRoomLock()" title="Lock Room">
+                    <svg xmlns="http://www.w3.org/2000/svg" width="20" height="20" viewBox="0 0 24 24" fill="none" stroke="currentColor" stroke-width="2" stroke-linecap="round" stroke-linejoin="round"><rect width="18" height="11" x="3" y="11" rx="2" ry="2"/><path d="M7 11V7a5 5 0 0 1 9.9-1"/></svg>
+                </button>
+                <button class="control-btn hover:text-blue-400 host-only hidden" id="btnWaitingRoom" onclick="toggleKnockMode()" title="Enable Waiting Room">
+                    <svg xmlns="http://www.w3.org/2000/svg" width="20" height="20" viewBox="0 0 24 24" fill="none" stroke="currentColor" stroke-width="2" stroke-linecap="round" stroke-linejoin="round"><path d="M13 4h3a2 2 0 0 1 2 2v14"/><path d="M2 20h3"/><path d="M13 20h9"/><path d="M10 12v.01"/><path d="M13 4.562v16.157a1 1 0 0 1-1.242.97L5 20V5.562a2 2 0 0 1 1.515-1.94l4-1A2 2 0 0 1 13 4.561Z"/></svg>
+                </button>
+                <button class="control-btn hover:text-red-400 host-only hidden" onclick="endMeeting()" title="End Meeting for All">
+                    <svg xmlns="http://www.w3.org/2000/svg" width="20" height="20" viewBox="0 0 24 24" fill="none" stroke="currentColor" stroke-width="2" stroke-linecap="round" stroke-linejoin="round"><circle cx="12" cy="12" r="10"/><line x1="15" y1="9" x2="9" y2="15"/><line x1="9" y1="9" x2="15" y2="15"/></svg>
+                </button>
                 <div class="w-px bg-slate-600 mx-1"></div>
                 <button class="control-btn active-red" onclick="leaveRoom()" title="Leave Room">
                     <svg xmlns="http://www.w3.org/2000/svg" width="20" height="20" viewBox="0 0 24 24" fill="none" stroke="currentColor" stroke-width="2" stroke-linecap="round" stroke-linejoin="round"><path d="M9 21H5a2 2 0 0 1-2-2V5a2 2 0 0 1 2-2h4"/><polyline points="16 17 21 12 16 7"/><line x1="21" x2="9" y1="12" y2="12"/></svg>
@@ -566,8 +639,17 @@ fn get_html_page(turn_user: &str, turn_pass: &str) -> String {
         let peers = {}; 
         let peerCamStatus = {};
         let peerScreenStatus = {};
+        // Kept after people leave, to name them in the chat.
+        let peerNames = {};
+        let chatSeen = new Set();
+        // Chat entries of shared files by id, to mark them once they expire.
+        let sharedFiles = {};
+        const MAX_FILE_SIZE = {{MAX_FILE_SIZE}};
+        if (!MAX_FILE_SIZE) document.getElementById('btnShareFile').classList.add('hidden');
+        let chatUnread = 0;
         let userNickname = "Guest";
         let userAvatar = null;
+        let roomPassword = '';
         let isConfigured = false;
         let audioContext;
         let wakeLock = null;
@@ -576,17 +658,40 @@ fn get_html_page(turn_user: &str, turn_pass: &str) -> String {
         let reconnectionAttempts = 0;
         const maxReconnectionAttempts = 5;
         const reconnectionDelay = 3000;
+        let serverShutdown = null;
+        let sessionEnded = null;
+        let hostId = null;
+        let roomLocked = false;
+        let knockMode = false;
+        let roomCapacity = 0;
+        let resumeToken = null;
         
+        const turnHost = "{{PUBLIC_HOST}}" || window.location.hostname;
         const rtcConfig = {
             iceServers: [
-                { urls: `stun:${window.location.hostname}:3478` },
-                {
-                    urls: `turn:${window.location.hostname}:3478`,
-                    username: "{{TURN_USER}}", 
-                    credential: "{{TURN_PASS}}" 
-                }
+                { urls: `stun:${turnHost}:{{TURN_PORT}}` }
             ]
         };
+        let turnRefreshTimer = null;
+
+        // The server hands out TURN credentials once we are in the room, and
+        // new ones when we ask before the old ones expire.
+        function applyTurnCredentials(creds) {
+            clearTimeout(turnRefreshTimer);
+            rtcConfig.iceServers = [
+                { urls: `stun:${turnHost}:{{TURN_PORT}}` },
+                {
+                    urls: [{{TURN_URLS}}],
+                    username: creds.username,
+                    credential: creds.credential
+                }
+            ];
+            turnRefreshTimer = setTimeout(() => {
+                if (ws && ws.readyState === WebSocket.OPEN) {
+                    ws.send(JSON.stringify({ type: 'turn-credentials' }));
+                }
+            }, Math.max(creds.ttl * 0.8, 30) * 1000);
+        }
 
         const localVideo = document.getElementById('localVideo');
         const previewVideo = document.getElementById('previewVideo');
@@ -933,11 +1038,16 @@ fn get_html_page(turn_user: &str, turn_pass: &str) -> String {
                 try {
                     const data = JSON.parse(stored);
                     if (data.nickname) nicknameInput.value = data.nickname;
-                    if (data.avatar) {
-                        userAvatar = data.avatar;
-                        avatarPreview.src = userAvatar;
-                        avatarPreview.classList.remove('hidden');
-                        avatarPlaceholder.classList.add('hidden');
+                    if (data.avatar && data.avatar.startsWith('data:')) {
+                        // Profiles saved by older versions may hold a full-size image.
+                        scaleAvatar(data.avatar).then(image => {
+                            if (userAvatar) return;
+                            userAvatar = image;
+                            avatarPreview.src = userAvatar;
+                            avatarPreview.classList.remove('hidden');
+                            avatarPlaceholder.classList.add('hidden');
+                            localStorage.setItem('iroh_profile', JSON.stringify({ ...data, avatar: image }));
+                        }).catch(e => console.error("Avatar load error", e));
                     }
                     if (data.audioOutputId) {
                         currentAudioOutputId = data.audioOutputId;
@@ -1052,45 +1162,79 @@ fn get_html_page(turn_user: &str, turn_pass: &str) -> String {
             draw();
         }
 
-        function handleAvatarUpload(input) {
-            const file = input.files[0];
-            if (!file) return;
+        // Avatars are scaled down here and kept in the profile as a data
+        // URL. Once we are in the room the image is uploaded with our resume
+        // token, and only the path the server returns is sent to the room.
+        const AVATAR_SIZE = 256;
+        let avatarUrl = null;
 
-            const reader = new FileReader();
-            reader.onload = function(e) {
+        function scaleAvatar(src) {
+            return new Promise((resolve, reject) => {
                 const img = new Image();
+                img.onerror = () => reject(new Error("Could not read the image"));
                 img.onload = function() {
+                    const scale = Math.min(1, AVATAR_SIZE / Math.max(img.width, img.height));
                     const canvas = document.createElement('canvas');
-                    const ctx = canvas.getContext('2d');
-                    
-                    const MAX_SIZE = 2048;
-                    let width = img.width;
-                    let height = img.height;
-                    
-                    if (width > height) {
-                        if (width > MAX_SIZE) {
-                            height *= MAX_SIZE / width;
-                            width = MAX_SIZE;
-                        }
-                    } else {
-                        if (height > MAX_SIZE) {
-                            width *= MAX_SIZE / height;
-                            height = MAX_SIZE;
-                        }
-                    }
-                    
-                    canvas.width = width;
-                    canvas.height = height;
-                    ctx.drawImage(img, 0, 0, width, height);
-                    
-                    userAvatar = canvas.toDataURL('image/jpeg', 0.8);
-                    avatarPreview.src = userAvatar;
-                    avatarPreview.classList.remove('hidden');
-                    avatarPlaceholder.classList.add('hidden');
+                    canvas.width = Math.max(1, Math.round(img.width * scale));
+                    canvas.height = Math.max(1, Math.round(img.height * scale));
+                    canvas.getContext('2d').drawImage(img, 0, 0, canvas.width, canvas.height);
+                    resolve(canvas.toDataURL('image/jpeg', 0.85));
                 };
-                img.src = e.target.result;
-            };
-            reader.readAsDataURL(file);
+                img.src = src;
+            });
+        }
+
+        async function scaleAvatarFile(file) {
+            const src = URL.createObjectURL(file);
+            try {
+                return await scaleAvatar(src);
+            } catch (e) {
+                alert("Failed to load avatar: " + e.message);
+                return null;
+            } finally {
+                URL.revokeObjectURL(src);
+            }
+        }
+
+        function dataUrlToBlob(url) {
+            const [head, data] = url.split(',');
+            const bytes = Uint8Array.from(atob(data), c => c.charCodeAt(0));
+            return new Blob([bytes], { type: head.slice(5).split(';')[0] });
+        }
+
+        // Uploads our avatar and tells the room about it.
+        async function publishAvatar() {
+            if (!userAvatar || !resumeToken) return;
+            try {
+                const res = await fetch(`/api/rooms/${encodeURIComponent(roomId)}/avatar`, {
+                    method: 'POST',
+                    headers: { 'Authorization': `Bearer ${resumeToken}`, 'Content-Type': 'image/jpeg' },
+                    body: dataUrlToBlob(userAvatar)
+                });
+                if (!res.ok) throw new Error(await res.text() || res.statusText);
+                avatarUrl = (await res.json()).url;
+            } catch (e) {
+                console.error("Avatar upload error", e);
+                return;
+            }
+            if (ws && ws.readyState === WebSocket.OPEN) {
+                ws.send(JSON.stringify({
+                    type: "update-user",
+                    data: { nickname: userNickname, avatar: avatarUrl }
+                }));
+            }
+        }
+
+        async function handleAvatarUpload(input) {
+            const file = input.files[0];
+            if (!file) return;
+
+            const image = await scaleAvatarFile(file);
+            if (!image) return;
+            userAvatar = image;
+            avatarPreview.src = userAvatar;
+            avatarPreview.classList.remove('hidden');
+            avatarPlaceholder.classList.add('hidden');
         }
 
         async function startPreview() {
@@ -1329,6 +1473,7 @@ fn get_html_page(turn_user: &str, turn_pass: &str) -> String {
 
         async function joinRoom() {
             userNickname = nicknameInput.value.trim() || "Guest";
+            roomPassword = document.getElementById('roomPasswordInput').value;
             savePreferences();
             
             if (!audioContext) {
@@ -1461,6 +1606,355 @@ fn get_html_page(turn_user: &str, turn_pass: &str) -> String {
             welcomeOverlay.style.display = 'flex';
         }
 
+        function sendJoin() {
+            const camEnabled = localStream && localStream.getVideoTracks()[0] && localStream.getVideoTracks()[0].enabled;
+            const screenEnabled = !!screenStream;
+            const screenHasAudio = screenStream && screenStream.getAudioTracks().length > 0;
+            const myId = getPersistentId();
+            ws.send(JSON.stringify({
+                type: "join", 
+                userId: myId,
+                version: {{PROTOCOL_VERSION}},
+                resumeToken: resumeToken,
+                data: {
+                    nickname: userNickname,
+                    avatar: avatarUrl,
+                    camEnabled: camEnabled,
+                    screenEnabled: screenEnabled,
+                    screenAudio: screenHasAudio,
+                    password: roomPassword
+                }
+            }));
+        }
+
+        function handleServerError(error) {
+            if (!error) return;
+            switch (error.code) {
+                case 'password-required':
+                case 'wrong-password':
+                case 'invalid-password':
+                case 'rate-limited':
+                    document.getElementById('passwordMessage').innerText = error.message;
+                    document.getElementById('passwordOverlay').classList.remove('hidden');
+                    document.getElementById('passwordRetryInput').focus();
+                    break;
+                case 'room-locked':
+                case 'unsupported-version':
+                    endSession(error.message);
+                    break;
+                case 'duplicate-id':
+                    // Usually a duplicated tab, which copies sessionStorage.
+                    sessionStorage.removeItem('room_user_id');
+                    resumeToken = null;
+                    sendJoin();
+                    break;
+                case 'not-joined':
+                    // Expected while the host has not let us in yet.
+                    break;
+                case 'room-full':
+                    if (isHost()) {
+                        // The host tried to admit a guest into a full room.
+                        alert(`${error.message} (${error.maxParticipants} participants).`);
+                    } else {
+                        endSession(`${error.message} (${error.maxParticipants} participants)`);
+                    }
+                    break;
+                default:
+                    console.error('Server error:', error.code, error.message);
+            }
+        }
+
+        function submitRoomPassword() {
+            const input = document.getElementById('passwordRetryInput');
+            roomPassword = input.value;
+            input.value = '';
+            document.getElementById('passwordOverlay').classList.add('hidden');
+            if (ws && ws.readyState === WebSocket.OPEN) {
+                sendJoin();
+            } else {
+                // The server closes sockets that take too long to join.
+                connectWs();
+            }
+        }
+
+        function isHost() {
+            return hostId !== null && hostId === getPersistentId();
+        }
+
+        function updateHostControls() {
+            document.querySelectorAll('.host-only').forEach(el => el.classList.toggle('hidden', !isHost()));
+            const btnLock = document.getElementById('btnLock');
+            btnLock.classList.toggle('active-red', roomLocked);
+            btnLock.title = roomLocked ? 'Unlock Room' : 'Lock Room';
+            const btnWaitingRoom = document.getElementById('btnWaitingRoom');
+            btnWaitingRoom.classList.toggle('text-blue-400', knockMode);
+            btnWaitingRoom.title = knockMode ? 'Disable Waiting Room' : 'Enable Waiting Room';
+            if (!isHost()) {
+                document.getElementById('knockList').innerHTML = '';
+            }
+        }
+
+        function toggleKnockMode() {
+            if (!isHost() || !ws || ws.readyState !== WebSocket.OPEN) return;
+            ws.send(JSON.stringify({ type: 'set-knock-mode', data: { enabled: !knockMode } }));
+        }
+
+        function addKnock(userId, data) {
+            if (!isHost() || document.getElementById(`knock-${userId}`)) return;
+            playNotificationSound('join');
+
+            const entry = document.createElement('div');
+            entry.id = `knock-${userId}`;
+            entry.className = 'glass-panel rounded-xl p-3 flex items-center gap-3 border border-slate-700 shadow-xl';
+
+            const avatar = document.createElement('div');
+            avatar.className = 'w-10 h-10 rounded-full bg-slate-700 flex-shrink-0 overflow-hidden flex items-center justify-center';
+            if (data && data.avatar) {
+                const img = document.createElement('img');
+                img.src = data.avatar;
+                img.className = 'w-full h-full object-cover';
+                avatar.appendChild(img);
+            } else {
+                avatar.innerText = '👤';
+            }
+
+            const name = document.createElement('div');
+            name.className = 'flex-1 min-w-0 text-sm text-white truncate';
+            name.innerText = (data && data.nickname) || 'Guest';
+            name.title = 'Wants to join';
+
+            const admit = document.createElement('button');
+            admit.className = 'px-3 py-1 rounded-lg bg-blue-600 hover:bg-blue-500 text-white text-xs font-bold';
+            admit.innerText = 'Admit';
+            admit.onclick = () => answerKnock(userId, 'admit');
+
+            const deny = document.createElement('button');
+            deny.className = 'px-3 py-1 rounded-lg bg-slate-700 hover:bg-red-600 text-white text-xs font-bold';
+            deny.innerText = 'Deny';
+            deny.onclick = () => answerKnock(userId, 'deny');
+
+            entry.append(avatar, name, admit, deny);
+            document.getElementById('knockList').appendChild(entry);
+        }
+
+        function removeKnock(userId) {
+            const entry = document.getElementById(`knock-${userId}`);
+            if (entry) entry.remove();
+        }
+
+        function answerKnock(userId, answer) {
+            removeKnock(userId);
+            if (ws && ws.readyState === WebSocket.OPEN) {
+                ws.send(JSON.stringify({ type: answer, target: userId }));
+            }
+        }
+
+        function kickParticipant(userId) {
+            if (!isHost() || !ws || ws.readyState !== WebSocket.OPEN) return;
+            const label = document.querySelector(`#wrapper-${userId} .absolute.bottom-3.left-3`);
+            const name = label ? label.innerText : 'this participant';
+            if (!confirm(`Remove ${name} from the room?`)) return;
+            ws.send(JSON.stringify({ type: 'kick', target: userId }));
+        }
+
+        function toggleRoomLock() {
+            if (!isHost() || !ws || ws.readyState !== WebSocket.OPEN) return;
+            ws.send(JSON.stringify({ type: 'lock-room', data: { locked: !roomLocked } }));
+        }
+
+        function endMeeting() {
+            if (!isHost() || !ws || ws.readyState !== WebSocket.OPEN) return;
+            if (!confirm('End the meeting for everyone?')) return;
+            ws.send(JSON.stringify({ type: 'end-meeting' }));
+        }
+
+        function toggleChat() {
+            const panel = document.getElementById('chatPanel');
+            const open = panel.classList.contains('hidden');
+            panel.classList.toggle('hidden', !open);
+            panel.classList.toggle('flex', open);
+            if (open) {
+                chatUnread = 0;
+                updateChatBadge();
+                document.getElementById('chatInput').focus();
+            }
+        }
+
+        function updateChatBadge() {
+            const badge = document.getElementById('chatBadge');
+            badge.innerText = chatUnread > 99 ? '99+' : chatUnread;
+            badge.classList.toggle('hidden', chatUnread === 0);
+        }
+
+        function clearChat() {
+            chatSeen.clear();
+            sharedFiles = {};
+            chatUnread = 0;
+            document.getElementById('chatMessages').innerHTML = '';
+            updateChatBadge();
+        }
+
+        function refreshChatTargets() {
+            const select = document.getElementById('chatTarget');
+            const current = select.value;
+            select.innerHTML = '';
+            select.add(new Option('Everyone', ''));
+            Object.keys(peers).forEach(id => {
+                select.add(new Option(`Only ${peerNames[id] || 'User ' + id.substr(0, 4)}`, id));
+            });
+            select.value = peers[current] ? current : '';
+        }
+
+        function sendChat(event) {
+            event.preventDefault();
+            const input = document.getElementById('chatInput');
+            const text = input.value.trim();
+            if (!text || !ws || ws.readyState !== WebSocket.OPEN) return;
+            const target = document.getElementById('chatTarget').value;
+            ws.send(JSON.stringify({ type: 'chat', target: target || undefined, data: { text } }));
+            input.value = '';
+        }
+
+        // Text with its web links made clickable, built without innerHTML.
+        function linkify(el, text) {
+            text.split(/(https?:\/\/[^\s]+)/).forEach((part, i) => {
+                if (i % 2 === 1) {
+                    const a = document.createElement('a');
+                    a.href = part;
+                    a.target = '_blank';
+                    a.rel = 'noopener noreferrer';
+                    a.className = 'text-blue-400 underline break-all';
+                    a.innerText = part;
+                    el.appendChild(a);
+                } else if (part) {
+                    el.appendChild(document.createTextNode(part));
+                }
+            });
+        }
+
+        function appendChat(userId, m) {
+            // History may repeat messages we already have after a resume.
+            if (chatSeen.has(m.id)) return;
+            chatSeen.add(m.id);
+
+            const body = document.createElement('div');
+            body.className = 'text-white whitespace-pre-wrap break-words';
+            linkify(body, m.text);
+            addChatEntry(userId, m.sentAt, m.target, body);
+        }
+
+        function addChatEntry(userId, sentAt, target, body) {
+            const mine = userId === getPersistentId();
+            const name = mine ? 'You' : (peerNames[userId] || `User ${userId.substr(0, 4)}`);
+            let header = name;
+            if (target) {
+                header += mine ? ` → ${peerNames[target] || 'User ' + target.substr(0, 4)} (private)` : ' (private)';
+            }
+
+            const entry = document.createElement('div');
+            const meta = document.createElement('div');
+            meta.className = 'text-[11px] text-slate-400 flex justify-between gap-2';
+            const who = document.createElement('span');
+            who.className = target ? 'text-purple-300 truncate' : 'truncate';
+            who.innerText = header;
+            const when = document.createElement('span');
+            when.innerText = new Date(sentAt).toLocaleTimeString([], { hour: '2-digit', minute: '2-digit' });
+            meta.append(who, when);
+            entry.append(meta, body);
+
+            const list = document.getElementById('chatMessages');
+            const atBottom = list.scrollHeight - list.scrollTop - list.clientHeight < 40;
+            list.appendChild(entry);
+            if (atBottom || mine) list.scrollTop = list.scrollHeight;
+
+            if (!mine && document.getElementById('chatPanel').classList.contains('hidden')) {
+                chatUnread++;
+                updateChatBadge();
+            }
+        }
+
+        function formatSize(bytes) {
+            if (bytes < 1024) return `${bytes} B`;
+            if (bytes < 1024 * 1024) return `${(bytes / 1024).toFixed(1)} KB`;
+            return `${(bytes / (1024 * 1024)).toFixed(1)} MB`;
+        }
+
+        // Files are uploaded over HTTP with our resume token, which the
+        // server accepts as proof that we are in the room.
+        async function shareFile(input) {
+            const file = input.files[0];
+            input.value = '';
+            if (!file || !resumeToken) return;
+            if (file.size > MAX_FILE_SIZE) {
+                alert(`Files are limited to ${formatSize(MAX_FILE_SIZE)}.`);
+                return;
+            }
+            const button = document.getElementById('btnShareFile');
+            button.disabled = true;
+            try {
+                const res = await fetch(`/api/rooms/${encodeURIComponent(roomId)}/files?name=${encodeURIComponent(file.name)}`, {
+                    method: 'POST',
+                    headers: { 'Authorization': `Bearer ${resumeToken}` },
+                    body: file
+                });
+                if (!res.ok) throw new Error(await res.text() || res.statusText);
+            } catch (e) {
+                alert("Failed to share file: " + e.message);
+            } finally {
+                button.disabled = false;
+            }
+        }
+
+        async function downloadFile(file) {
+            try {
+                const res = await fetch(`/api/rooms/${encodeURIComponent(roomId)}/files/${file.id}`, {
+                    headers: { 'Authorization': `Bearer ${resumeToken}` }
+                });
+                if (!res.ok) throw new Error(await res.text() || res.statusText);
+                const url = URL.createObjectURL(await res.blob());
+                const a = document.createElement('a');
+                a.href = url;
+                a.download = file.name;
+                a.click();
+                setTimeout(() => URL.revokeObjectURL(url), 1000);
+            } catch (e) {
+                alert("Failed to download file: " + e.message);
+            }
+        }
+
+        function appendFile(userId, file) {
+            // Files still available are announced again after a resume.
+            if (sharedFiles[file.id]) return;
+
+            const body = document.createElement('div');
+            body.className = 'flex items-center gap-2 bg-slate-800 rounded-lg p-2';
+            const link = document.createElement('button');
+            link.className = 'text-blue-400 underline break-all text-left flex-1 min-w-0';
+            link.innerText = `📎 ${file.name}`;
+            link.onclick = () => downloadFile(file);
+            const size = document.createElement('span');
+            size.className = 'text-[11px] text-slate-400 flex-shrink-0';
+            size.innerText = formatSize(file.size);
+            body.append(link, size);
+            sharedFiles[file.id] = body;
+            addChatEntry(userId, file.sharedAt, null, body);
+        }
+
+        function removeFile(id) {
+            const body = sharedFiles[id];
+            if (!body) return;
+            const link = body.querySelector('button');
+            link.disabled = true;
+            link.className = 'text-slate-500 line-through break-all text-left flex-1 min-w-0';
+            link.title = 'This file has expired';
+        }
+
+        // The server removed us for good; stay disconnected.
+        function endSession(message) {
+            sessionEnded = message;
+            if (ws) ws.close();
+        }
+
         function connectWs() {
             updateStatus('connecting', 'Connecting...');
             ws = new WebSocket(wsUrl);
@@ -1469,21 +1963,7 @@ fn get_html_page(turn_user: &str, turn_pass: &str) -> String {
                             playNotificationSound('join');
                             reconnectionAttempts = 0;
                             updateStatus('connected', 'Connected');
-                            const camEnabled = localStream && localStream.getVideoTracks()[0] && localStream.getVideoTracks()[0].enabled;
-                            const screenEnabled = !!screenStream;
-                            const screenHasAudio = screenStream && screenStream.getAudioTracks().length > 0;
-                            const myId = getPersistentId();
-                            ws.send(JSON.stringify({
-                                type: "join", 
-                                userId: myId,
-                                data: {
-                                    nickname: userNickname,
-                                    avatar: userAvatar,
-                                    camEnabled: camEnabled,
-                                    screenEnabled: screenEnabled,
-                                    screenAudio: screenHasAudio
-                                }
-                            }));
+                            sendJoin();
                             checkEmpty();
                         };
             
@@ -1491,6 +1971,15 @@ fn get_html_page(turn_user: &str, turn_pass: &str) -> String {
                             const msg = JSON.parse(event.data);
                             
                             switch (msg.type) {
+                                case 'welcome':
+                                    resumeToken = msg.data.resumeToken;
+                                    publishAvatar();
+                                    if (!msg.data.resumed) {
+                                        // Our old place is gone, and so are the peers' connections to it.
+                                        Object.keys(peers).forEach(removePeer);
+                                        clearChat();
+                                    }
+                                    break;
                                 case 'user-joined':
                                     playNotificationSound('join');
                                     if (peers[msg.userId]) {
@@ -1505,26 +1994,47 @@ fn get_html_page(turn_user: &str, turn_pass: &str) -> String {
                                     }
                                     initPeer(msg.userId, true, msg.data?.nickname, msg.data?.avatar);
                                     
-                                    const myCamEnabled = localStream && localStream.getVideoTracks()[0] && localStream.getVideoTracks()[0].enabled;
-                                    const myScreenEnabled = !!screenStream;
-                                    const myScreenHasAudio = screenStream && screenStream.getAudioTracks().length > 0;
-                                    ws.send(JSON.stringify({
-                                        type: 'identify',
-                                        target: msg.userId,
-                                        data: {
-                                            nickname: userNickname, 
-                                            avatar: userAvatar,
-                                            camEnabled: myCamEnabled,
-                                            screenEnabled: myScreenEnabled,
-                                            screenAudio: myScreenHasAudio
+                                    // The server tells them who we are.
+                                    ws.send(JSON.stringify({ type: 'identify', target: msg.userId }));
+                                    break;
+                                case 'room-state': {
+                                    // After a resume, drop whoever left while we were away.
+                                    const present = new Set(msg.data.participants.map(p => p.userId));
+                                    Object.keys(peers).filter(id => !present.has(id)).forEach(id => {
+                                        removePeer(id);
+                                        delete peerCamStatus[id];
+                                        delete peerScreenStatus[id];
+                                    });
+                                    msg.data.participants.forEach(p => {
+                                        if (p.userId === getPersistentId()) return;
+                                        peerNames[p.userId] = p.nickname;
+                                        peerCamStatus[p.userId] = p.camEnabled;
+                                        peerScreenStatus[p.userId] = p.screenEnabled;
+                                        if (peers[p.userId]) {
+                                            updatePeerInfo(p.userId, p.nickname, p.avatar);
                                         }
-                                    }));
+                                    });
+                                    refreshChatTargets();
+                                    break;
+                                }
+                                case 'chat':
+                                    appendChat(msg.userId, msg.data);
+                                    break;
+                                case 'chat-history':
+                                    msg.data.messages.forEach(m => appendChat(m.userId, m));
+                                    break;
+                                case 'file-shared':
+                                    appendFile(msg.userId, msg.data);
+                                    break;
+                                case 'file-removed':
+                                    removeFile(msg.data.id);
                                     break;
                                 case 'user-left':
                                     playNotificationSound('leave');
                                     removePeer(msg.userId);
                                     delete peerCamStatus[msg.userId];
                                     delete peerScreenStatus[msg.userId];
+                                    refreshChatTargets();
                                     break;
                                 case 'user-update':
                                      updatePeerInfo(msg.userId, msg.data.nickname, msg.data.avatar);
@@ -1564,10 +2074,89 @@ fn get_html_page(turn_user: &str, turn_pass: &str) -> String {
                                 case 'signal':
                                     handleSignal(msg.userId, msg.data);
                                     break;
+                                case 'error':
+                                    handleServerError(msg.data);
+                                    break;
+                                case 'host-changed':
+                                    hostId = msg.userId;
+                                    if (msg.data) {
+                                        roomLocked = !!msg.data.locked;
+                                        knockMode = !!msg.data.knockMode;
+                                        roomCapacity = msg.data.maxParticipants || 0;
+                                    }
+                                    updateHostControls();
+                                    break;
+                                case 'room-locked':
+                                    roomLocked = !!(msg.data && msg.data.locked);
+                                    updateHostControls();
+                                    break;
+                                case 'knock-mode':
+                                    knockMode = !!(msg.data && msg.data.enabled);
+                                    if (!knockMode) document.getElementById('knockList').innerHTML = '';
+                                    updateHostControls();
+                                    break;
+                                case 'capacity':
+                                    roomCapacity = (msg.data && msg.data.maxParticipants) || 0;
+                                    break;
+                                case 'knock':
+                                    addKnock(msg.userId, msg.data);
+                                    break;
+                                case 'knock-cancelled':
+                                    removeKnock(msg.userId);
+                                    break;
+                                case 'waiting':
+                                    updateStatus('connecting', 'Waiting for the host to let you in...');
+                                    break;
+                                case 'admitted':
+                                    updateStatus('connected', 'Connected');
+                                    break;
+                                case 'turn-credentials':
+                                    applyTurnCredentials(msg.data);
+                                    break;
+                                case 'denied':
+                                    endSession('The host did not let you in');
+                                    break;
+                                case 'kicked':
+                                    endSession('You were removed by the host');
+                                    break;
+                                case 'meeting-ended':
+                                    endSession('The host ended the meeting');
+                                    break;
+                                case 'server-shutdown':
+                                    serverShutdown = { reconnectAfter: msg.data?.reconnectAfter };
+                                    ws.close();
+                                    break;
                             }
                         };
                         
                         ws.onclose = () => {
+                            if (sessionEnded) {
+                                Object.keys(peers).forEach(removePeer);
+                                hostId = null;
+                                updateHostControls();
+                                updateStatus('disconnected', sessionEnded);
+                                return;
+                            }
+                            if (!document.getElementById('passwordOverlay').classList.contains('hidden')) {
+                                // Reconnects once the password is submitted.
+                                updateStatus('disconnected', 'Password required');
+                                return;
+                            }
+                            if (serverShutdown) {
+                                const after = serverShutdown.reconnectAfter;
+                                serverShutdown = null;
+                                reconnectionAttempts = 0;
+                                if (after !== undefined) {
+                                    updateStatus('connecting', `Server restarting, reconnecting in ${after}s...`);
+                                    // Spread the rejoins so the restarted server is not hit all at once.
+                                    setTimeout(connectWs, after * 1000 + Math.random() * 2000);
+                                } else {
+                                    updateStatus('disconnected', 'Server shut down');
+                                    const btn = document.getElementById('btnReconnect');
+                                    if (btn) btn.classList.remove('hidden');
+                                }
+                                return;
+                            }
                             reconnectionAttempts++;
                             if (reconnectionAttempts >= maxReconnectionAttempts) {
                                 updateStatus('disconnected', 'Disconnected');
@@ -1634,6 +2223,8 @@ fn get_html_page(turn_user: &str, turn_pass: &str) -> String {
         }
 
         function updatePeerInfo(userId, nickname, avatar) {
+            peerNames[userId] = nickname;
+            refreshChatTargets();
             const wrapper = document.getElementById(`wrapper-${userId}`);
             if (wrapper) {
                 const label = wrapper.querySelector('.absolute.bottom-3.left-3');
@@ -1718,6 +2309,7 @@ fn get_html_page(turn_user: &str, turn_pass: &str) -> String {
             if (peers[userId]) return; 
             
             const displayName = nickname || `User ${userId.substr(0,4)}`;
+            peerNames[userId] = nickname;
 
             const pc = new RTCPeerConnection(rtcConfig);
             peers[userId] = pc;
@@ -1801,7 +2393,15 @@ fn get_html_page(turn_user: &str, turn_pass: &str) -> String {
                     container.appendChild(avatarLayer);
                     container.appendChild(label);
                     container.appendChild(volControls);
+                    const kickBtn = document.createElement('button');
+                    kickBtn.className = 'host-only absolute top-3 left-3 p-2 rounded-xl bg-black/40 hover:bg-red-600 text-white backdrop-blur-md transition-all opacity-0 group-hover:opacity-100 scale-90 hover:scale-100 z-30';
+                    kickBtn.innerHTML = '<svg xmlns="http://www.w3.org/2000/svg" width="20" height="20" viewBox="0 0 24 24" fill="none" stroke="currentColor" stroke-width="2" stroke-linecap="round" stroke-linejoin="round"><path d="M16 21v-2a4 4 0 0 0-4-4H6a4 4 0 0 0-4 4v2"/><circle cx="9" cy="7" r="4"/><line x1="17" y1="8" x2="22" y2="13"/><line x1="22" y1="8" x2="17" y2="13"/></svg>';
+                    kickBtn.onclick = () => kickParticipant(userId);
+                    kickBtn.title = "Remove from Room";
+                    kickBtn.classList.toggle('hidden', !isHost());
+
                     container.appendChild(fsBtn);
+                    container.appendChild(kickBtn);
                     remoteGrid.appendChild(container);
                     checkEmpty();
                 }
@@ -2283,6 +2883,8 @@ fn get_html_page(turn_user: &str, turn_pass: &str) -> String {
                 settingsAvatarPlaceholder.classList.remove('hidden');
             }
             
+            document.getElementById('settingsCapacityInput').value = roomCapacity || '';
+
             populateSettingsDeviceList();
             settingsOverlay.classList.remove('hidden');
             if (localStream) {
@@ -2295,44 +2897,16 @@ fn get_html_page(turn_user: &str, turn_pass: &str) -> String {
             if (settingsMeterFrameId) cancelAnimationFrame(settingsMeterFrameId);
         }
 
-        function handleSettingsAvatarUpload(input) {
+        async function handleSettingsAvatarUpload(input) {
             const file = input.files[0];
             if (!file) return;
 
-            const reader = new FileReader();
-            reader.onload = function(e) {
-                const img = new Image();
-                img.onload = function() {
-                    const canvas = document.createElement('canvas');
-                    const ctx = canvas.getContext('2d');
-                    const MAX_SIZE = 2048;
-                    let width = img.width;
-                    let height = img.height;
-                    
-                    if (width > height) {
-                        if (width > MAX_SIZE) {
-                            height *= MAX_SIZE / width;
-                            width = MAX_SIZE;
-                        }
-                    } else {
-                        if (height > MAX_SIZE) {
-                            width *= MAX_SIZE / height;
-                            height = MAX_SIZE;
-                        }
-                    }
-                    
-                    canvas.width = width;
-                    canvas.height = height;
-                    ctx.drawImage(img, 0, 0, width, height);
-                    
-                    newAvatarCandidate = canvas.toDataURL('image/jpeg', 0.8);
-                    settingsAvatarPreview.src = newAvatarCandidate;
-                    settingsAvatarPreview.classList.remove('hidden');
-                    settingsAvatarPlaceholder.classList.add('hidden');
-                };
-                img.src = e.target.result;
-            };
-            reader.readAsDataURL(file);
+            const image = await scaleAvatarFile(file);
+            if (!image) return;
+            newAvatarCandidate = image;
+            settingsAvatarPreview.src = newAvatarCandidate;
+            settingsAvatarPreview.classList.remove('hidden');
+            settingsAvatarPlaceholder.classList.add('hidden');
         }
 
         async function saveSettings() {
@@ -2355,7 +2929,9 @@ fn get_html_page(turn_user: &str, turn_pass: &str) -> String {
             }
 
             userNickname = settingsNicknameInput.value.trim() || "Guest";
+            const avatarChanged = newAvatarCandidate !== userAvatar;
             userAvatar = newAvatarCandidate;
+            if (!userAvatar) avatarUrl = null;
             savePreferences();
             
             updateLocalLabel();
@@ -2366,9 +2942,15 @@ fn get_html_page(turn_user: &str, turn_pass: &str) -> String {
                     type: "update-user", 
                     data: {
                         nickname: userNickname,
-                        avatar: userAvatar 
+                        avatar: avatarUrl
                     } 
                 }));
+                if (avatarChanged) publishAvatar();
+
+                const capacity = parseInt(document.getElementById('settingsCapacityInput').value, 10) || 0;
+                if (isHost() && capacity !== roomCapacity) {
+                    ws.send(JSON.stringify({ type: 'set-capacity', data: { maxParticipants: capacity } }));
+                }
             }
             
             closeSettings();
@@ -2557,72 +3139,284 @@ fn get_html_page(turn_user: &str, turn_pass: &str) -> String {
 </body>
 </html>
 "###;
-    html.replace("{{TURN_USER}}", turn_user).replace("{{TURN_PASS}}", turn_pass)
+    html.replace("{{TURN_URLS}}", &turn_urls(&config.turn))
+        .replace("{{TURN_PORT}}", &config.turn.bind_addr.port().to_string())
+        .replace("{{PUBLIC_HOST}}", config.public_host.as_deref().unwrap_or(""))
+        .replace("{{PROTOCOL_VERSION}}", &PROTOCOL_VERSION.to_string())
+        .replace("{{MAX_CHAT_LEN}}", &protocol::MAX_CHAT_LEN.to_string())
+        .replace("{{MAX_FILE_SIZE}}", &config.files.max_file_size.to_string())
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct SignalMessage {
-    #[serde(rename = "type")]
-    msg_type: String,     
-    target: Option<String>,
-    data: Option<serde_json::Value>, 
-    #[serde(rename = "userId")]
-    user_id: Option<String>, 
+/// JavaScript template literals for every TURN listener that is enabled,
+/// resolved against `turnHost` in the page.
+fn turn_urls(turn: &TurnConfig) -> String {
+    let port = turn.bind_addr.port();
+    let mut urls = vec![format!("`turn:${{turnHost}}:{}`", port)];
+    if turn.tcp {
+        urls.push(format!("`turn:${{turnHost}}:{}?transport=tcp`", port));
+    }
+    if let Some(tls) = &turn.tls {
+        urls.push(format!("`turns:${{turnHost}}:{}?transport=tcp`", tls.port));
+    }
+    urls.join(", ")
 }
 
-type UserTx = tokio::sync::mpsc::Sender<Result<Message, axum::Error>>;
-type RoomMap = Arc<Mutex<HashMap<String, HashMap<String, UserTx>>>>;
-
 #[derive(Clone)]
 struct AppState {
+    config: Arc<Config>,
     rooms: RoomMap,
-    turn_user: String,
-    turn_pass: String,
+    turn: Arc<TurnHandle>,
+    password_attempts: Arc<AttemptThrottle>,
+    send_stats: Arc<SendStats>,
+    metrics: Arc<Metrics>,
 }
 
 #[tokio::main]
 async fn main() {
-    let rooms: RoomMap = Arc::new(Mutex::new(HashMap::new()));
-    
-    let turn_user = Uuid::new_v4().to_string();
-    let turn_pass = Uuid::new_v4().to_string();
-    let realm = "rustrooms";
+    let config = match Config::load() {
+        Ok(c) => Arc::new(c),
+        Err(e) => {
+            eprintln!("ERROR: {:#}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let turn = match turn_server::spawn(config.turn.clone()).await {
+        Ok(t) => Arc::new(t),
+        Err(e) => {
+            eprintln!("ERROR: Failed to start TURN server: {:#}", e);
+            std::process::exit(1);
+        }
+    };
+    for (transport, addr) in turn.local_addrs() {
+        println!("TURN SERVER LISTENING ON {} {}", transport, addr);
+    }
+    println!(
+        "TURN RELAY ADDRESS {} (PORTS {}-{})",
+        turn.relay_ip(),
+        config.turn.relay_min_port,
+        config.turn.relay_max_port
+    );
+
+    tokio::spawn(log_allocations(turn.clone()));
+
+    if let Err(e) = std::fs::create_dir_all(&config.avatar_dir) {
+        eprintln!("ERROR: Failed to create avatar directory {}: {}", config.avatar_dir.display(), e);
+        std::process::exit(1);
+    }
+    if let Err(e) = files::prepare(&config.files) {
+        eprintln!("ERROR: Failed to prepare file directory {}: {}", config.files.dir.display(), e);
+        std::process::exit(1);
+    }
+
+    let metrics = Arc::new(Metrics::default());
+    let rooms = RoomMap::new(config.clone(), metrics.clone());
+    tokio::spawn(avatar::collect_garbage(rooms.clone(), config.avatar_dir.clone()));
 
     let state = AppState {
-        rooms,
-        turn_user: turn_user.clone(),
-        turn_pass: turn_pass.clone(),
+        config: config.clone(),
+        rooms: rooms.clone(),
+        turn: turn.clone(),
+        password_attempts: Arc::new(AttemptThrottle::default()),
+        send_stats: Arc::new(SendStats::default()),
+        metrics,
     };
 
-    let app = Router::new()
+    if let Some(metrics_addr) = config.metrics_addr {
+        let metrics_listener = match tokio::net::TcpListener::bind(metrics_addr).await {
+            Ok(l) => l,
+            Err(e) => {
+                eprintln!("ERROR: Failed to bind to {}: {}", metrics_addr, e);
+                std::process::exit(1);
+            }
+        };
+        println!("METRICS ON http://{}/metrics", metrics_addr);
+        // Left running through the shutdown drain, which is worth watching.
+        let metrics_app = metrics::listener_router().with_state(state.clone());
+        tokio::spawn(async move {
+            if let Err(e) = axum::serve(metrics_listener, metrics_app).await {
+                eprintln!("ERROR: Metrics server failed: {}", e);
+            }
+        });
+    }
+
+    let mut app = Router::new()
         .route("/", get(index))
         .route("/new", get(new_room))
         .route("/room/:room_id", get(index))
         .route("/rnnoise.js", get(rnnoise_js))
         .route("/rnnoise_processor.js", get(rnnoise_processor_js))
         .route("/ws/:room_id", get(ws_handler))
-        .with_state(state);
+        .merge(avatar::router())
+        .merge(files::router())
+        .merge(admin::router());
+    if config.metrics_addr.is_none() {
+        app = app.merge(metrics::router());
+    }
+    let app = app.with_state(state);
 
-    let t_user = turn_user.clone();
-    let t_pass = turn_pass.clone();
-    
-    tokio::spawn(async move {
-        if let Err(e) = turn_server::start(3478, t_user, t_pass, realm.to_string()).await {
-            eprintln!("failed to start TURN server: {}", e);
+    let certs = config.http_tls.as_ref().map(|tls| {
+        match tls::ReloadableConfig::load(tls.cert_path.clone(), tls.key_path.clone()) {
+            Ok(certs) => certs,
+            Err(e) => {
+                eprintln!("ERROR: Failed to load HTTPS certificate: {:#}", e);
+                std::process::exit(1);
+            }
         }
     });
 
-    let port = 3000;
-    let listener = match tokio::net::TcpListener::bind(format!("0.0.0.0:{}", port)).await {
+    let addr = config.http_addr;
+    let listener = match tokio::net::TcpListener::bind(addr).await {
         Ok(l) => l,
         Err(e) => {
-            eprintln!("ERROR: Failed to bind to port {}: {}", port, e);
+            eprintln!("ERROR: Failed to bind to {}: {}", addr, e);
             eprintln!("Is the server already running? Try killing the process using this port.");
             std::process::exit(1);
         }
     };
-    println!("SERVER RUNNING ON PORT {}", port);
-    axum::serve(listener, app).await.unwrap();
+
+    let shutdown = CancellationToken::new();
+    tokio::spawn({
+        let shutdown = shutdown.clone();
+        let rooms = rooms.clone();
+        let reconnect_after = config.shutdown.reconnect_after;
+        async move {
+            shutdown_signal().await;
+            println!("SHUTTING DOWN, NOTIFYING PARTICIPANTS");
+            broadcast_shutdown(&rooms, reconnect_after).await;
+            shutdown.cancel();
+        }
+    });
+
+    match certs {
+        Some(certs) => {
+            if let Some(port) = config.http_tls.as_ref().and_then(|tls| tls.redirect_port) {
+                let redirect_addr = SocketAddr::new(addr.ip(), port);
+                let redirect_listener = match tokio::net::TcpListener::bind(redirect_addr).await {
+                    Ok(l) => l,
+                    Err(e) => {
+                        eprintln!("ERROR: Failed to bind to {}: {}", redirect_addr, e);
+                        std::process::exit(1);
+                    }
+                };
+                println!("REDIRECTING HTTP ON {} TO HTTPS", redirect_addr);
+                let redirect = https::redirect_router(addr.port(), config.public_host.clone());
+                let shutdown = shutdown.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = axum::serve(redirect_listener, redirect)
+                        .with_graceful_shutdown(shutdown.cancelled_owned())
+                        .await
+                    {
+                        eprintln!("ERROR: HTTP redirect server failed: {}", e);
+                    }
+                });
+            }
+
+            certs.clone().watch();
+            println!("SERVER RUNNING ON https://{}", addr);
+            https::serve(listener, app, certs, shutdown).await;
+        }
+        None => {
+            println!("SERVER RUNNING ON {}", addr);
+            if let Err(e) = axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+                .with_graceful_shutdown(shutdown.cancelled_owned())
+                .await
+            {
+                eprintln!("ERROR: HTTP server failed: {}", e);
+            }
+        }
+    }
+
+    drain_rooms(&rooms, config.shutdown.drain).await;
+
+    match turn.stats().await {
+        Ok(stats) => println!("STOPPING TURN SERVER ({} active allocations)", stats.allocations),
+        Err(e) => eprintln!("failed to query TURN stats: {}", e),
+    }
+    if let Err(e) = turn.shutdown().await {
+        eprintln!("failed to stop TURN server: {}", e);
+    }
+}
+
+async fn log_allocations(turn: Arc<TurnHandle>) {
+    let mut events = turn.subscribe();
+    loop {
+        match events.recv().await {
+            Ok(AllocationEvent::Created(a)) => println!(
+                "TURN allocation {} for {} from {} over {}",
+                a.relay_addr, a.username, a.client_addr, a.transport
+            ),
+            Ok(AllocationEvent::Closed(a)) => println!(
+                "TURN allocation {} closed after {}s, {} bytes relayed",
+                a.relay_addr, a.age_secs, a.bytes_relayed
+            ),
+            Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+        }
+    }
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            eprintln!("failed to listen for Ctrl-C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut sig) => {
+                sig.recv().await;
+            }
+            Err(e) => {
+                eprintln!("failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+/// Tells every participant the server is going away, optionally with the
+/// number of seconds after which it should be back.
+async fn broadcast_shutdown(rooms: &RoomMap, reconnect_after: Option<Duration>) {
+    let notify_msg = ServerMessage::ServerShutdown {
+        data: reconnect_after.map(|d| ShutdownNotice {
+            reconnect_after: d.as_secs(),
+        }),
+    };
+
+    for room in rooms.handles() {
+        room.broadcast(notify_msg.clone()).await;
+    }
+}
+
+/// Waits until every participant has left or `period` has passed, then
+/// closes the sockets that are still open.
+async fn drain_rooms(rooms: &RoomMap, period: Duration) {
+    let deadline = tokio::time::Instant::now() + period;
+    while tokio::time::Instant::now() < deadline {
+        if rooms.is_empty() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    let mut remaining = 0;
+    for room in rooms.handles() {
+        remaining += room.close(close_code::AWAY, "server shutdown").await;
+    }
+    println!("CLOSING {} REMAINING CONNECTIONS", remaining);
+    // Give the writer tasks a moment to flush the close frames.
+    tokio::time::sleep(Duration::from_millis(200)).await;
 }
 
 async fn new_room() -> Redirect {
@@ -2631,7 +3425,7 @@ async fn new_room() -> Redirect {
 }
 
 async fn index(State(state): State<AppState>) -> impl IntoResponse {
-    let html = get_html_page(&state.turn_user, &state.turn_pass);
+    let html = get_html_page(&state.config);
     (
         [(
             header::CONTENT_SECURITY_POLICY, 
@@ -2644,173 +3438,233 @@ async fn index(State(state): State<AppState>) -> impl IntoResponse {
 async fn ws_handler(
     Path(room_id): Path<String>,
     ws: WebSocketUpgrade,
+    ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
     State(state): State<AppState>,
 ) -> impl IntoResponse {
     if room_id.len() > 64 || !room_id.chars().all(|c| c.is_alphanumeric() || c == '-') {
         return (axum::http::StatusCode::BAD_REQUEST, "Invalid room ID").into_response();
     }
-    ws.on_upgrade(move |socket| handle_socket(socket, room_id, state.rooms))
+    ws.on_upgrade(move |socket| handle_socket(socket, room_id, state, client_addr.ip()))
 }
 
-async fn handle_socket(socket: WebSocket, room_id: String, rooms: RoomMap) {
-    let (mut user_ws_tx, mut user_ws_rx) = socket.split();
-    let (tx, mut rx) = tokio::sync::mpsc::channel(500);
-    
-    let mut user_id = String::new(); 
-    let mut is_joined = false;
+/// Adds the user to the room, creating the room if it does not exist yet.
+/// The first joiner becomes the host, and their password, if any, protects
+/// the room from then on. Passwords are hashed and checked here rather than
+/// on the room's task; if the room changes in the meantime it asks again.
+async fn join_room(
+    state: &AppState,
+    room_id: &str,
+    request: &JoinRequest,
+    participant: &Participant,
+    client_ip: IpAddr,
+) -> Result<RoomHandle, JoinRefusal> {
+    if request.password.as_ref().is_some_and(|p| p.len() > MAX_PASSWORD_LEN) {
+        return Err(JoinRefusal::InvalidPassword);
+    }
 
-    tokio::spawn(async move {
-        while let Some(result) = rx.recv().await {
-            if let Ok(msg) = result {
-                if user_ws_tx.send(msg).await.is_err() {
-                    break;
+    let mut verified = None;
+    loop {
+        let Some(room) = state.rooms.get(room_id) else {
+            let hash = match request.password.clone() {
+                Some(password) => {
+                    // Hashing is as slow as checking, so it holds an attempt
+                    // while it runs, but setting a password is no guess and
+                    // gives it back afterwards.
+                    let Some(attempt) = state.password_attempts.attempt(client_ip) else {
+                        return Err(JoinRefusal::RateLimited);
+                    };
+                    let hash = tokio::task::spawn_blocking(move || PasswordHash::new(&password))
+                        .await
+                        .expect("password hashing panicked");
+                    attempt.succeeded();
+                    Some(Arc::new(hash))
                 }
+                None => None,
+            };
+            if let Some(room) = state.rooms.create(room_id, request.clone(), participant.clone(), hash) {
+                return Ok(room);
             }
-        }
-    });
+            continue;
+        };
 
-    while let Some(result) = user_ws_rx.next().await {
-        if let Ok(msg) = result {
-            if let Message::Text(text) = msg {
-                if let Ok(parsed) = serde_json::from_str::<SignalMessage>(&text) {
-                    if !is_joined {
-                        if parsed.msg_type == "join" {
-                             let raw_id = parsed.user_id.unwrap_or_default();
-                             user_id = if !raw_id.is_empty() && raw_id.chars().all(|c| c.is_alphanumeric() || c == '-') && raw_id.len() < 64 {
-                                 raw_id
-                             } else {
-                                 Uuid::new_v4().to_string()
-                             };
-                             
-                             {
-                                let mut rooms_lock = rooms.lock().await;
-                                let room = rooms_lock.entry(room_id.clone()).or_insert_with(HashMap::new);
-                                
-                                if room.contains_key(&user_id) {
-                                    break; 
-                                }
-                                
-                                room.insert(user_id.clone(), tx.clone());
-                             }
-                             is_joined = true;
-                             
-                             let mut notify_data = parsed.data.clone();
-                             if let Some(serde_json::Value::Object(ref mut map)) = notify_data {
-                                 if let Some(serde_json::Value::String(avatar)) = map.get("avatar") {
-                                     if avatar.len() > 7_000_000 {
-                                         map.remove("avatar");
-                                     }
-                                 }
-                             }
-
-                             let notify_msg = serde_json::to_string(&SignalMessage {
-                                msg_type: "user-joined".into(),
-                                user_id: Some(user_id.clone()),
-                                target: None,
-                                data: notify_data,
-                            }).unwrap();
-
-                            let rooms_lock = rooms.lock().await;
-                            if let Some(room) = rooms_lock.get(&room_id) {
-                                for (uid, tx) in room.iter() {
-                                    if *uid != user_id {
-                                        let _ = tx.try_send(Ok(Message::Text(notify_msg.clone().into())));
-                                    }
-                                }
-                            }
-                        }
-                    } else {
-                        let rooms_lock = rooms.lock().await;
-                        if let Some(room) = rooms_lock.get(&room_id) {
-                            if parsed.msg_type == "update-user" {
-                                let mut notify_data = parsed.data.clone();
-                                if let Some(serde_json::Value::Object(ref mut map)) = notify_data {
-                                    if let Some(serde_json::Value::String(avatar)) = map.get("avatar") {
-                                        if avatar.len() > 7_000_000 {
-                                            map.remove("avatar");
-                                        }
-                                    }
-                                }
+        // No reply means the room closed in the meantime.
+        let Some(reply) = room.join(request.clone(), participant.clone(), verified.take()).await else {
+            continue;
+        };
+        match reply {
+            JoinReply::Admitted => return Ok(room),
+            JoinReply::Refused(refusal) => return Err(refusal),
+            JoinReply::CheckPassword(hash) => {
+                let Some(password) = request.password.clone() else {
+                    return Err(JoinRefusal::PasswordRequired);
+                };
+                let Some(attempt) = state.password_attempts.attempt(client_ip) else {
+                    return Err(JoinRefusal::RateLimited);
+                };
+                let verifier = hash.clone();
+                let valid = tokio::task::spawn_blocking(move || verifier.verify(&password))
+                    .await
+                    .unwrap_or(false);
+                if !valid {
+                    return Err(JoinRefusal::WrongPassword);
+                }
+                attempt.succeeded();
+                verified = Some(hash);
+            }
+        }
+    }
+}
 
-                                let notify_msg = serde_json::to_string(&SignalMessage {
-                                    msg_type: "user-update".into(),
-                                    user_id: Some(user_id.clone()),
-                                    target: None,
-                                    data: notify_data,
-                                }).unwrap();
+async fn handle_socket(socket: WebSocket, room_id: String, state: AppState, client_ip: IpAddr) {
+    let connected = Instant::now();
+    let (user_ws_tx, mut user_ws_rx) = socket.split();
+    let (outbox, queues) = Outbox::new(
+        state.config.outbox.queue_size,
+        state.config.outbox.stall_timeout,
+        state.send_stats.clone(),
+    );
+    let participant = Participant::new(Arc::new(outbox));
 
-                                for (uid, tx) in room.iter() {
-                                    if *uid != user_id {
-                                        let _ = tx.try_send(Ok(Message::Text(notify_msg.clone().into())));
-                                    }
-                                }
-                            } else if parsed.msg_type == "cam-toggle" {
-                                let notify_data = parsed.data.clone();
-                                let notify_msg = serde_json::to_string(&SignalMessage {
-                                    msg_type: "cam-toggle".into(),
-                                    user_id: Some(user_id.clone()),
-                                    target: None,
-                                    data: notify_data,
-                                }).unwrap();
-
-                                for (uid, tx) in room.iter() {
-                                    if *uid != user_id {
-                                        let _ = tx.try_send(Ok(Message::Text(notify_msg.clone().into())));
-                                    }
-                                }
-                            } else if parsed.msg_type == "screen-toggle" {
-                                let notify_data = parsed.data.clone();
-                                let notify_msg = serde_json::to_string(&SignalMessage {
-                                    msg_type: "screen-toggle".into(),
-                                    user_id: Some(user_id.clone()),
-                                    target: None,
-                                    data: notify_data,
-                                }).unwrap();
-
-                                for (uid, tx) in room.iter() {
-                                    if *uid != user_id {
-                                        let _ = tx.try_send(Ok(Message::Text(notify_msg.clone().into())));
-                                    }
-                                }
-                            } else if let Some(ref target_id) = parsed.target {
-                                if let Some(target_tx) = room.get(target_id) {
-                                    let mut forwarded_msg = parsed.clone();
-                                    forwarded_msg.user_id = Some(user_id.clone());
-                                    let forwarded_text = serde_json::to_string(&forwarded_msg).unwrap();
-                                    let _ = target_tx.try_send(Ok(Message::Text(forwarded_text.into())));
-                                }
-                            }
-                        }
-                    }
+    let mut user_id = String::new(); 
+    // Set once the room has taken the join, as a participant or as a guest
+    // waiting for the host.
+    let mut room: Option<RoomHandle> = None;
+    // Set when the connection ended without a close frame, which is what
+    // a network drop looks like. Such participants may come back.
+    let mut dropped = false;
+
+    let keepalive = &state.config.keepalive;
+    let join_deadline = tokio::time::Instant::now() + keepalive.join_timeout;
+    let mut ping = keepalive
+        .ping_interval
+        .map(|period| tokio::time::interval_at(tokio::time::Instant::now() + period, period));
+    let mut missed_pings = 0;
+
+    let writer = tokio::spawn(outbox::write(user_ws_tx, queues, state.metrics.clone()));
+
+    loop {
+        let result = tokio::select! {
+            result = user_ws_rx.next() => match result {
+                Some(result) => result,
+                None => {
+                    dropped = true;
+                    break;
                 }
-            } else if let Message::Close(_) = msg {
+            },
+            _ = participant.closed.cancelled() => break,
+            _ = participant.outbox.stalled() => {
+                // The queues are full, so not even a close frame would get
+                // through. The client sees the connection drop and can
+                // resume with empty queues.
+                println!(
+                    "DISCONNECTING SLOW CLIENT {} IN ROOM {} ({} MESSAGES DROPPED)",
+                    user_id,
+                    room_id,
+                    participant.outbox.dropped()
+                );
+                writer.abort();
+                dropped = true;
                 break;
             }
-        } else {
+            _ = tokio::time::sleep_until(join_deadline), if room.is_none() => {
+                participant.send(error_message(ErrorCode::JoinTimeout, "No join received in time"));
+                participant.disconnect(close_code::POLICY, "join timeout");
+                break;
+            }
+            _ = next_tick(&mut ping) => {
+                if missed_pings >= keepalive.max_missed_pings {
+                    // Likely a half-open connection. Nothing written to it
+                    // would arrive, so the writer is stopped as well.
+                    writer.abort();
+                    dropped = true;
+                    break;
+                }
+                missed_pings += 1;
+                participant.outbox.send_frame(Message::Ping(Vec::new()), Priority::Signal);
+                continue;
+            }
+        };
+        let Ok(msg) = result else {
+            dropped = true;
+            break;
+        };
+        // Any frame, not only a pong, shows the client is still there.
+        missed_pings = 0;
+        state.metrics.received(&msg);
+        let text = match msg {
+            Message::Text(text) => text,
+            Message::Binary(_) => {
+                participant.send(error_message(ErrorCode::InvalidMessage, "Messages must be JSON text"));
+                continue;
+            }
+            Message::Close(_) => break,
+            _ => continue,
+        };
+        let parsed = match serde_json::from_str::<ClientMessage>(&text) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                state.metrics.message("invalid");
+                participant.send(error_message(ErrorCode::InvalidMessage, e.to_string()));
+                continue;
+            }
+        };
+        state.metrics.message(parsed.kind());
+        if let Err(reason) = parsed.validate() {
+            participant.send(error_message(ErrorCode::InvalidMessage, reason));
+            continue;
+        }
+
+        let Some(joined) = &room else {
+            let ClientMessage::Join {
+                user_id: raw_id,
+                version,
+                resume_token,
+                data,
+            } = parsed
+            else {
+                participant.send(error_message(ErrorCode::NotJoined, "Send join first"));
+                continue;
+            };
+            if version != PROTOCOL_VERSION {
+                participant.send(protocol::unsupported_version());
+                participant.disconnect(close_code::PROTOCOL, "unsupported protocol version");
+                break;
+            }
+            let request = JoinRequest {
+                user_id: raw_id
+                    .filter(|id| protocol::valid_user_id(id))
+                    .unwrap_or_else(|| Uuid::new_v4().to_string()),
+                password: data.password.filter(|p| !p.is_empty()),
+                resume_token,
+                presence: data.presence.sanitize(),
+            };
+            match join_room(&state, &room_id, &request, &participant, client_ip).await {
+                Ok(handle) => {
+                    room = Some(handle);
+                    user_id = request.user_id;
+                }
+                Err(refusal) => participant.send(refusal.message()),
+            }
+            continue;
+        };
+
+        if !joined.send(&user_id, &participant, parsed).await {
             break;
         }
     }
 
-    {
-        let mut rooms_lock = rooms.lock().await;
-        if let Some(room) = rooms_lock.get_mut(&room_id) {
-            if is_joined {
-                room.remove(&user_id);
-                if room.is_empty() {
-                    rooms_lock.remove(&room_id);
-                } else {
-                    let notify_msg = serde_json::to_string(&SignalMessage {
-                        msg_type: "user-left".into(),
-                        user_id: Some(user_id.clone()),
-                        target: None,
-                        data: None,
-                    }).unwrap();
-
-                    for (_, tx) in room.iter() {
-                        let _ = tx.try_send(Ok(Message::Text(notify_msg.clone().into())));
-                    }
-                }
-            }
+    if let Some(room) = room {
+        room.leave(user_id, participant, dropped).await;
+    }
+    state.metrics.connection_closed(connected.elapsed());
+}
+
+/// Completes at the next tick of `interval`, or never if there is none.
+async fn next_tick(interval: &mut Option<tokio::time::Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
         }
+        None => std::future::pending().await,
     }
-}
\ No newline at end of file
+}
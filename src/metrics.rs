@@ -0,0 +1,291 @@
+//! Counters for `/metrics`, in the Prometheus text format. Most are updated
+//! where things happen; room sizes, dropped messages and TURN figures are
+//! read from their owners when scraped.
+
+use axum::{
+    extract::{ws::Message, State},
+    http::{header, HeaderMap},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex as StdMutex;
+use std::time::Duration;
+
+use crate::admin;
+use crate::outbox::SendStatsSnapshot;
+use crate::turn_server::AllocationTotals;
+use crate::AppState;
+
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Ranges rooms are counted in by size, as the largest size in each and its
+/// label.
+const ROOM_SIZES: &[(usize, &str)] = &[(0, "0"), (1, "1"), (2, "2"), (4, "3-4"), (8, "5-8"), (16, "9-16"), (usize::MAX, "17+")];
+const DURATION_BUCKETS: &[f64] = &[1.0, 10.0, 30.0, 60.0, 300.0, 900.0, 1800.0, 3600.0, 7200.0, 14400.0];
+
+/// `/metrics` on the main port, for holders of the admin token.
+pub fn router() -> Router<AppState> {
+    Router::new().route("/metrics", get(guarded))
+}
+
+/// `/metrics` for a listener of its own, which is left open: whoever can
+/// reach that port can read the metrics.
+pub fn listener_router() -> Router<AppState> {
+    Router::new().route("/metrics", get(metrics))
+}
+
+async fn guarded(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if let Some(denied) = admin::denial(&state, &headers) {
+        return denied;
+    }
+    metrics(State(state)).await
+}
+
+async fn metrics(State(state): State<AppState>) -> Response {
+    ([(header::CONTENT_TYPE, CONTENT_TYPE)], state.metrics.render(&state)).into_response()
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    joins: AtomicU64,
+    leaves: AtomicU64,
+    /// Messages received from clients by `type`, `invalid` for those that
+    /// could not be parsed.
+    messages: StdMutex<BTreeMap<&'static str, u64>>,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    connection_durations: StdMutex<Histogram>,
+}
+
+impl Metrics {
+    /// Someone got into a room, not counting resumed connections.
+    pub fn joined(&self) {
+        self.joins.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn left(&self, count: usize) {
+        self.leaves.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn message(&self, msg_type: &'static str) {
+        *self.messages.lock().unwrap().entry(msg_type).or_default() += 1;
+    }
+
+    pub fn received(&self, frame: &Message) {
+        self.bytes_in.fetch_add(frame_len(frame), Ordering::Relaxed);
+    }
+
+    pub fn sent(&self, frame: &Message) {
+        self.bytes_out.fetch_add(frame_len(frame), Ordering::Relaxed);
+    }
+
+    pub fn connection_closed(&self, lasted: Duration) {
+        self.connection_durations
+            .lock()
+            .unwrap()
+            .observe(DURATION_BUCKETS, lasted.as_secs_f64());
+    }
+
+    fn render(&self, state: &AppState) -> String {
+        self.render_with(&state.rooms.sizes(), &state.send_stats.snapshot(), &state.turn.totals())
+    }
+
+    /// The exposition, with what is owned elsewhere passed in.
+    fn render_with(&self, sizes: &[usize], send: &SendStatsSnapshot, turn: &AllocationTotals) -> String {
+        let mut out = String::new();
+
+        // Room sizes go up and down, so they are gauges of the moment rather
+        // than a histogram, whose counts may only grow.
+        gauge(&mut out, "rustrooms_rooms", "Open rooms.", sizes.len() as u64);
+        gauge(&mut out, "rustrooms_participants", "Participants in all open rooms.", sizes.iter().sum::<usize>() as u64);
+        gauge(
+            &mut out,
+            "rustrooms_room_participants_max",
+            "Participants in the largest open room.",
+            sizes.iter().copied().max().unwrap_or(0) as u64,
+        );
+        header(&mut out, "rustrooms_rooms_by_size", "gauge", "Open rooms by how many participants they have.");
+        let mut by_size = vec![0; ROOM_SIZES.len()];
+        for &size in sizes {
+            by_size[ROOM_SIZES.iter().position(|&(max, _)| size <= max).unwrap()] += 1;
+        }
+        for ((_, label), count) in ROOM_SIZES.iter().zip(by_size) {
+            let _ = writeln!(out, "rustrooms_rooms_by_size{{size=\"{}\"}} {}", label, count);
+        }
+        counter(&mut out, "rustrooms_joins_total", "Participants who got into a room.", self.joins.load(Ordering::Relaxed));
+        counter(&mut out, "rustrooms_leaves_total", "Participants who left a room.", self.leaves.load(Ordering::Relaxed));
+
+        header(&mut out, "rustrooms_ws_messages_total", "counter", "Messages received from clients, by type.");
+        for (msg_type, count) in self.messages.lock().unwrap().iter() {
+            let _ = writeln!(out, "rustrooms_ws_messages_total{{msg_type=\"{}\"}} {}", msg_type, count);
+        }
+        counter(&mut out, "rustrooms_ws_received_bytes_total", "WebSocket payload bytes received.", self.bytes_in.load(Ordering::Relaxed));
+        counter(&mut out, "rustrooms_ws_sent_bytes_total", "WebSocket payload bytes sent.", self.bytes_out.load(Ordering::Relaxed));
+
+        counter(&mut out, "rustrooms_ws_send_dropped_total", "Messages dropped because a client's signal queue was full.", send.dropped_signal);
+        counter(&mut out, "rustrooms_ws_presence_superseded_total", "Presence updates replaced by a newer one before a client took them.", send.superseded_presence);
+        counter(&mut out, "rustrooms_ws_slow_disconnects_total", "Clients disconnected for not keeping up.", send.slow_disconnects);
+
+        self.connection_durations.lock().unwrap().write(
+            &mut out,
+            "rustrooms_ws_connection_duration_seconds",
+            "How long WebSocket connections lasted.",
+            DURATION_BUCKETS,
+        );
+
+        gauge(&mut out, "rustrooms_turn_allocations", "Live TURN allocations.", turn.live as u64);
+        counter(&mut out, "rustrooms_turn_allocations_total", "TURN allocations created.", turn.created);
+        counter(&mut out, "rustrooms_turn_relayed_bytes_total", "Bytes relayed by TURN allocations.", turn.bytes_relayed);
+
+        out
+    }
+}
+
+fn frame_len(frame: &Message) -> u64 {
+    let len = match frame {
+        Message::Text(text) => text.len(),
+        Message::Binary(data) | Message::Ping(data) | Message::Pong(data) => data.len(),
+        Message::Close(frame) => frame.as_ref().map_or(0, |f| 2 + f.reason.len()),
+    };
+    len as u64
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    header(out, name, "counter", help);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: u64) {
+    header(out, name, "gauge", help);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+/// Observations per bucket, the last one being `+Inf`.
+#[derive(Debug, Default)]
+struct Histogram {
+    counts: Vec<u64>,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, buckets: &[f64], value: f64) {
+        if self.counts.is_empty() {
+            self.counts = vec![0; buckets.len() + 1];
+        }
+        let bucket = buckets.iter().position(|&le| value <= le).unwrap_or(buckets.len());
+        self.counts[bucket] += 1;
+        self.sum += value;
+    }
+
+    fn write(&self, out: &mut String, name: &str, help: &str, buckets: &[f64]) {
+        header(out, name, "histogram", help);
+        let mut cumulative = 0;
+        for (i, le) in buckets.iter().map(|le| le.to_string()).chain(["+Inf".to_string()]).enumerate() {
+            cumulative += self.counts.get(i).copied().unwrap_or(0);
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, le, cumulative);
+        }
+        let _ = writeln!(out, "{}_sum {}", name, self.sum);
+        let _ = writeln!(out, "{}_count {}", name, cumulative);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(metrics: &Metrics, sizes: &[usize]) -> String {
+        let send = SendStatsSnapshot {
+            dropped_signal: 3,
+            superseded_presence: 0,
+            slow_disconnects: 1,
+        };
+        let turn = AllocationTotals {
+            live: 2,
+            created: 5,
+            bytes_relayed: 1200,
+        };
+        metrics.render_with(sizes, &send, &turn)
+    }
+
+    /// The value of the sample written as `series`.
+    fn sample(out: &str, series: &str) -> f64 {
+        out.lines()
+            .find_map(|line| line.strip_prefix(series)?.strip_prefix(' '))
+            .unwrap_or_else(|| panic!("no sample {series}"))
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn every_sample_has_its_metric_declared() {
+        let metrics = Metrics::default();
+        metrics.message("join");
+        metrics.connection_closed(Duration::from_secs(42));
+        let out = render(&metrics, &[1, 3]);
+
+        let mut declared = Vec::new();
+        for line in out.lines() {
+            if let Some(rest) = line.strip_prefix("# TYPE ") {
+                let (name, kind) = rest.split_once(' ').unwrap();
+                assert!(["counter", "gauge", "histogram"].contains(&kind), "{line}");
+                declared.push(name);
+            } else if !line.starts_with("# HELP ") {
+                let (series, value) = line.rsplit_once(' ').unwrap();
+                value.parse::<f64>().unwrap();
+                let name = series.split('{').next().unwrap();
+                let family = ["_bucket", "_sum", "_count"]
+                    .iter()
+                    .find_map(|suffix| name.strip_suffix(suffix).filter(|base| declared.contains(base)));
+                assert!(declared.contains(&name) || family.is_some(), "undeclared sample {line}");
+            }
+        }
+        assert_eq!(sample(&out, "rustrooms_ws_messages_total{msg_type=\"join\"}"), 1.0);
+        assert_eq!(sample(&out, "rustrooms_ws_send_dropped_total"), 3.0);
+        assert_eq!(sample(&out, "rustrooms_turn_relayed_bytes_total"), 1200.0);
+    }
+
+    #[test]
+    fn room_sizes_are_gauges() {
+        let metrics = Metrics::default();
+        let out = render(&metrics, &[1, 2, 2, 7, 40]);
+        assert_eq!(sample(&out, "rustrooms_rooms"), 5.0);
+        assert_eq!(sample(&out, "rustrooms_participants"), 52.0);
+        assert_eq!(sample(&out, "rustrooms_room_participants_max"), 40.0);
+        assert_eq!(sample(&out, "rustrooms_rooms_by_size{size=\"2\"}"), 2.0);
+        assert_eq!(sample(&out, "rustrooms_rooms_by_size{size=\"5-8\"}"), 1.0);
+        assert_eq!(sample(&out, "rustrooms_rooms_by_size{size=\"17+\"}"), 1.0);
+        assert!(out.contains("# TYPE rustrooms_rooms_by_size gauge"));
+
+        // Rooms emptying out lower the gauges; no histogram is left behind
+        // whose counts would go backwards.
+        let out = render(&metrics, &[]);
+        assert_eq!(sample(&out, "rustrooms_participants"), 0.0);
+        assert_eq!(sample(&out, "rustrooms_rooms_by_size{size=\"2\"}"), 0.0);
+        assert!(!out.contains("rustrooms_room_participants_bucket"));
+    }
+
+    #[test]
+    fn histograms_are_cumulative() {
+        let metrics = Metrics::default();
+        for secs in [5, 5, 100, 100_000] {
+            metrics.connection_closed(Duration::from_secs(secs));
+        }
+        let out = render(&metrics, &[]);
+        let name = "rustrooms_ws_connection_duration_seconds";
+        assert_eq!(sample(&out, &format!("{name}_bucket{{le=\"1\"}}")), 0.0);
+        assert_eq!(sample(&out, &format!("{name}_bucket{{le=\"10\"}}")), 2.0);
+        assert_eq!(sample(&out, &format!("{name}_bucket{{le=\"300\"}}")), 3.0);
+        assert_eq!(sample(&out, &format!("{name}_bucket{{le=\"+Inf\"}}")), 4.0);
+        assert_eq!(sample(&out, &format!("{name}_count")), 4.0);
+        assert_eq!(sample(&out, &format!("{name}_sum")), 100_110.0);
+    }
+}
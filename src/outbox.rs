@@ -0,0 +1,368 @@
+use axum::extract::ws::Message;
+use futures::{Sink, SinkExt};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::sync::Notify;
+
+use crate::metrics::Metrics;
+use crate::protocol::ServerMessage;
+
+/// Which queue a message goes through. Signaling and room control go first;
+/// presence updates wait behind them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Priority {
+    Signal,
+    /// The latest state of one kind for one user. It replaces an update with
+    /// the same key that is still waiting, so a slow client skips straight
+    /// to the current state instead of missing it.
+    Presence(PresenceKey),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PresenceKey {
+    kind: &'static str,
+    user_id: String,
+}
+
+impl ServerMessage {
+    pub fn priority(&self) -> Priority {
+        let (kind, user_id) = match self {
+            ServerMessage::UserUpdate { user_id, .. } => ("user-update", user_id),
+            ServerMessage::CamToggle { user_id, .. } => ("cam-toggle", user_id),
+            ServerMessage::ScreenToggle { user_id, .. } => ("screen-toggle", user_id),
+            _ => return Priority::Signal,
+        };
+        Priority::Presence(PresenceKey {
+            kind,
+            user_id: user_id.clone(),
+        })
+    }
+}
+
+/// Messages dropped or skipped because a client did not keep up, across all
+/// clients.
+#[derive(Debug, Default)]
+pub struct SendStats {
+    dropped_signal: AtomicU64,
+    superseded_presence: AtomicU64,
+    slow_disconnects: AtomicU64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SendStatsSnapshot {
+    pub dropped_signal: u64,
+    pub superseded_presence: u64,
+    pub slow_disconnects: u64,
+}
+
+impl SendStats {
+    pub fn snapshot(&self) -> SendStatsSnapshot {
+        SendStatsSnapshot {
+            dropped_signal: self.dropped_signal.load(Ordering::Relaxed),
+            superseded_presence: self.superseded_presence.load(Ordering::Relaxed),
+            slow_disconnects: self.slow_disconnects.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Outgoing queues of one connection. Sending never waits: when the signal
+/// queue is full the message is dropped and counted, and a client whose
+/// signal queue stays full for longer than the stall timeout is given up on.
+pub struct Outbox {
+    signal: mpsc::Sender<Message>,
+    presence: Arc<PresenceQueue>,
+    stall_timeout: Duration,
+    /// Since when the signal queue has been full without the client taking
+    /// anything off it.
+    full_since: StdMutex<Option<Instant>>,
+    /// Wakes [`Outbox::stalled`] when the signal queue fills up.
+    filled: Notify,
+    dropped: AtomicU64,
+    stats: Arc<SendStats>,
+}
+
+/// Presence updates waiting to be written, at most one per key. That bounds
+/// it by the size of the room, so it never has to drop anything.
+#[derive(Default)]
+struct PresenceQueue {
+    frames: StdMutex<VecDeque<(PresenceKey, Message)>>,
+    ready: Notify,
+}
+
+impl PresenceQueue {
+    /// Queues `frame`, or replaces the waiting one with the same key in its
+    /// place. Returns true if one was replaced.
+    fn push(&self, key: PresenceKey, frame: Message) -> bool {
+        let mut frames = self.frames.lock().unwrap();
+        if let Some(waiting) = frames.iter_mut().find(|(k, _)| *k == key) {
+            waiting.1 = frame;
+            return true;
+        }
+        frames.push_back((key, frame));
+        self.ready.notify_one();
+        false
+    }
+
+    async fn pop(&self) -> Message {
+        loop {
+            let ready = self.ready.notified();
+            if let Some((_, frame)) = self.frames.lock().unwrap().pop_front() {
+                return frame;
+            }
+            ready.await;
+        }
+    }
+}
+
+/// Receiving ends of an [`Outbox`], drained by [`write`].
+pub struct Queues {
+    signal: mpsc::Receiver<Message>,
+    presence: Arc<PresenceQueue>,
+}
+
+impl Outbox {
+    pub fn new(capacity: usize, stall_timeout: Duration, stats: Arc<SendStats>) -> (Outbox, Queues) {
+        let (signal, signal_rx) = mpsc::channel(capacity);
+        let presence = Arc::new(PresenceQueue::default());
+        let outbox = Outbox {
+            signal,
+            presence: presence.clone(),
+            stall_timeout,
+            full_since: StdMutex::new(None),
+            filled: Notify::new(),
+            dropped: AtomicU64::new(0),
+            stats,
+        };
+        let queues = Queues {
+            signal: signal_rx,
+            presence,
+        };
+        (outbox, queues)
+    }
+
+    pub fn send(&self, msg: ServerMessage) {
+        let priority = msg.priority();
+        self.send_frame(msg.into(), priority);
+    }
+
+    /// Queues a raw frame. Returns false if it was dropped.
+    pub fn send_frame(&self, frame: Message, priority: Priority) -> bool {
+        let key = match priority {
+            Priority::Signal => return self.send_signal(frame),
+            Priority::Presence(key) => key,
+        };
+        if self.presence.push(key, frame) {
+            self.stats.superseded_presence.fetch_add(1, Ordering::Relaxed);
+        }
+        true
+    }
+
+    fn send_signal(&self, frame: Message) -> bool {
+        match self.signal.try_send(frame) {
+            Ok(()) => {
+                // There was room, so the client has taken something off the
+                // queue since it was last full.
+                let now_full = self.signal.capacity() == 0;
+                *self.full_since.lock().unwrap() = now_full.then(Instant::now);
+                if now_full {
+                    self.filled.notify_one();
+                }
+                true
+            }
+            Err(TrySendError::Full(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                self.stats.dropped_signal.fetch_add(1, Ordering::Relaxed);
+                let mut full_since = self.full_since.lock().unwrap();
+                if full_since.is_none() {
+                    *full_since = Some(Instant::now());
+                    self.filled.notify_one();
+                }
+                false
+            }
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
+
+    /// Completes once the client has left the signal queue full for the
+    /// stall timeout, whether or not anything else is sent to it meanwhile.
+    pub async fn stalled(&self) {
+        loop {
+            let filled = self.filled.notified();
+            tokio::pin!(filled);
+            filled.as_mut().enable();
+            let full_since = {
+                let mut full_since = self.full_since.lock().unwrap();
+                if self.signal.capacity() > 0 {
+                    *full_since = None;
+                }
+                *full_since
+            };
+            let Some(full_since) = full_since else {
+                filled.await;
+                continue;
+            };
+            let deadline = full_since + self.stall_timeout;
+            if Instant::now() >= deadline {
+                self.stats.slow_disconnects.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            tokio::time::sleep_until(deadline.into()).await;
+        }
+    }
+
+    /// Messages dropped for this connection.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// Writes queued frames to the socket, signal queue first, until the outbox
+/// is gone or the socket fails.
+pub async fn write<S>(mut sink: S, mut queues: Queues, metrics: Arc<Metrics>)
+where
+    S: Sink<Message> + Unpin,
+{
+    loop {
+        let frame = tokio::select! {
+            biased;
+            frame = queues.signal.recv() => match frame {
+                Some(frame) => frame,
+                None => break,
+            },
+            frame = queues.presence.pop() => frame,
+        };
+        metrics.sent(&frame);
+        if sink.send(frame).await.is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::CamToggle;
+    use futures::channel::mpsc as sink;
+    use futures::StreamExt;
+
+    fn outbox(capacity: usize, stall_timeout: Duration) -> (Outbox, Queues) {
+        Outbox::new(capacity, stall_timeout, Arc::new(SendStats::default()))
+    }
+
+    fn cam(user_id: &str, enabled: bool) -> ServerMessage {
+        ServerMessage::CamToggle {
+            user_id: user_id.to_string(),
+            data: CamToggle { enabled },
+        }
+    }
+
+    fn text(frame: Message) -> String {
+        match frame {
+            Message::Text(text) => text,
+            other => panic!("expected a text frame, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn signal_queue_is_written_first() {
+        let (outbox, queues) = outbox(8, Duration::from_secs(60));
+        outbox.send(cam("a", true));
+        outbox.send_frame(Message::Text("first".into()), Priority::Signal);
+        outbox.send_frame(Message::Text("second".into()), Priority::Signal);
+        drop(outbox);
+
+        let (tx, rx) = sink::unbounded();
+        write(tx, queues, Arc::new(Metrics::default())).await;
+        let written: Vec<String> = rx.map(text).collect().await;
+        // The writer stops once the outbox is gone, so the presence update
+        // that was still waiting is not written.
+        assert_eq!(written, ["first", "second"]);
+    }
+
+    #[tokio::test]
+    async fn presence_goes_out_when_no_signal_waits() {
+        let (outbox, queues) = outbox(8, Duration::from_secs(60));
+        let (tx, mut rx) = sink::unbounded();
+        let writer = tokio::spawn(write(tx, queues, Arc::new(Metrics::default())));
+
+        outbox.send(cam("a", true));
+        assert!(text(rx.next().await.unwrap()).contains("cam-toggle"));
+        drop(outbox);
+        writer.await.unwrap();
+    }
+
+    #[test]
+    fn presence_updates_are_coalesced_per_user_and_kind() {
+        let stats = Arc::new(SendStats::default());
+        let (outbox, queues) = Outbox::new(8, Duration::from_secs(60), stats.clone());
+        outbox.send(cam("a", true));
+        outbox.send(cam("b", true));
+        outbox.send(cam("a", false));
+        outbox.send(ServerMessage::ScreenToggle {
+            user_id: "a".into(),
+            data: crate::protocol::ScreenToggle {
+                enabled: true,
+                has_audio: false,
+            },
+        });
+
+        let frames: Vec<String> = queues.presence.frames.lock().unwrap().drain(..).map(|(_, frame)| text(frame)).collect();
+        assert_eq!(frames.len(), 3);
+        assert!(frames[0].contains("\"a\"") && frames[0].contains("false"));
+        assert!(frames[1].contains("\"b\""));
+        assert!(frames[2].contains("screen-toggle"));
+        assert_eq!(stats.snapshot().superseded_presence, 1);
+    }
+
+    #[tokio::test]
+    async fn full_signal_queue_drops_messages() {
+        let stats = Arc::new(SendStats::default());
+        let (outbox, _queues) = Outbox::new(2, Duration::from_secs(60), stats.clone());
+        for _ in 0..2 {
+            assert!(outbox.send_frame(Message::Ping(Vec::new()), Priority::Signal));
+        }
+        assert!(!outbox.send_frame(Message::Ping(Vec::new()), Priority::Signal));
+        assert_eq!(outbox.dropped(), 1);
+        assert_eq!(stats.snapshot().dropped_signal, 1);
+    }
+
+    #[tokio::test]
+    async fn full_queue_stalls_without_further_sends() {
+        let stats = Arc::new(SendStats::default());
+        let (outbox, _queues) = Outbox::new(2, Duration::from_millis(50), stats.clone());
+        // Filling the queue is enough; nothing is ever dropped.
+        outbox.send_frame(Message::Ping(Vec::new()), Priority::Signal);
+        outbox.send_frame(Message::Ping(Vec::new()), Priority::Signal);
+        tokio::time::timeout(Duration::from_secs(5), outbox.stalled()).await.expect("stall detected");
+        assert_eq!(outbox.dropped(), 0);
+        assert_eq!(stats.snapshot().slow_disconnects, 1);
+    }
+
+    #[tokio::test]
+    async fn client_taking_messages_is_not_stalled() {
+        let (outbox, mut queues) = outbox(2, Duration::from_millis(50));
+        outbox.send_frame(Message::Ping(Vec::new()), Priority::Signal);
+        outbox.send_frame(Message::Ping(Vec::new()), Priority::Signal);
+        queues.signal.recv().await.unwrap();
+        let stalled = tokio::time::timeout(Duration::from_millis(200), outbox.stalled()).await;
+        assert!(stalled.is_err());
+    }
+
+    #[tokio::test]
+    async fn stall_is_noticed_once_the_queue_fills_later() {
+        let (outbox, _queues) = outbox(1, Duration::from_millis(50));
+        let stalled = async {
+            tokio::time::timeout(Duration::from_secs(5), outbox.stalled()).await.expect("stall detected");
+        };
+        let fill = async {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            outbox.send_frame(Message::Ping(Vec::new()), Priority::Signal);
+        };
+        tokio::join!(stalled, fill);
+    }
+}
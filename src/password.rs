@@ -0,0 +1,154 @@
+use ring::digest::SHA256_OUTPUT_LEN;
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::num::NonZeroU32;
+use std::sync::Mutex as StdMutex;
+use std::time::{Duration, Instant};
+
+pub const MAX_PASSWORD_LEN: usize = 128;
+
+const ITERATIONS: NonZeroU32 = NonZeroU32::new(100_000).unwrap();
+const SALT_LEN: usize = 16;
+
+/// Attempts an IP may make before it has to wait out the lockout. Attempts
+/// in progress count, so this also bounds how many run at once.
+const MAX_ATTEMPTS: u32 = 5;
+const LOCKOUT: Duration = Duration::from_secs(60);
+/// Size above which stale entries are pruned from the throttle.
+const PRUNE_THRESHOLD: usize = 4096;
+
+/// Salted PBKDF2-HMAC-SHA256 hash of a room password. The password itself
+/// is never kept. Hashing is deliberately slow, so both constructing and
+/// verifying belong on a blocking thread.
+pub struct PasswordHash {
+    salt: [u8; SALT_LEN],
+    hash: [u8; SHA256_OUTPUT_LEN],
+}
+
+impl PasswordHash {
+    pub fn new(password: &str) -> PasswordHash {
+        let mut salt = [0u8; SALT_LEN];
+        SystemRandom::new().fill(&mut salt).expect("system random number generator failed");
+        let mut hash = [0u8; SHA256_OUTPUT_LEN];
+        pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, ITERATIONS, &salt, password.as_bytes(), &mut hash);
+        PasswordHash { salt, hash }
+    }
+
+    /// Constant-time comparison against `password`.
+    pub fn verify(&self, password: &str) -> bool {
+        pbkdf2::verify(pbkdf2::PBKDF2_HMAC_SHA256, ITERATIONS, &self.salt, password.as_bytes(), &self.hash).is_ok()
+    }
+}
+
+struct Attempts {
+    count: u32,
+    last: Instant,
+}
+
+impl Attempts {
+    fn stale(&self, now: Instant) -> bool {
+        now.saturating_duration_since(self.last) >= LOCKOUT
+    }
+}
+
+/// Counts password attempts per client IP. An attempt counts from the moment
+/// it starts until it turns out to be right, so attempts made at the same
+/// time cannot get past the limit. An IP that runs out is locked out until
+/// it has stopped trying for a while.
+///
+/// The IP is the one the connection came from. Behind a reverse proxy that
+/// is the proxy's, so everyone shares a single lockout.
+#[derive(Default)]
+pub struct AttemptThrottle {
+    attempts: StdMutex<HashMap<IpAddr, Attempts>>,
+}
+
+impl AttemptThrottle {
+    /// Takes one of `ip`'s attempts, or refuses if it has none left.
+    pub fn attempt(&self, ip: IpAddr) -> Option<Attempt<'_>> {
+        self.attempt_at(ip, Instant::now())
+    }
+
+    fn attempt_at(&self, ip: IpAddr, now: Instant) -> Option<Attempt<'_>> {
+        let mut attempts = self.attempts.lock().unwrap();
+        if attempts.len() >= PRUNE_THRESHOLD {
+            attempts.retain(|_, a| !a.stale(now));
+        }
+        let entry = attempts.entry(ip).or_insert(Attempts { count: 0, last: now });
+        if entry.stale(now) {
+            entry.count = 0;
+        }
+        if entry.count >= MAX_ATTEMPTS {
+            return None;
+        }
+        entry.count += 1;
+        entry.last = now;
+        Some(Attempt { throttle: self, ip })
+    }
+}
+
+/// A password attempt in progress. It keeps counting against the IP unless
+/// it turns out to be right.
+#[must_use]
+pub struct Attempt<'a> {
+    throttle: &'a AttemptThrottle,
+    ip: IpAddr,
+}
+
+impl Attempt<'_> {
+    /// Gives the attempt back, for a right password or one that was not
+    /// being checked at all.
+    pub fn succeeded(self) {
+        let mut attempts = self.throttle.attempts.lock().unwrap();
+        if let Some(entry) = attempts.get_mut(&self.ip) {
+            entry.count = entry.count.saturating_sub(1);
+            if entry.count == 0 {
+                attempts.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IP: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 1));
+
+    #[test]
+    fn attempts_in_progress_count() {
+        let throttle = AttemptThrottle::default();
+        let pending: Vec<_> = (0..MAX_ATTEMPTS).map(|_| throttle.attempt(IP).unwrap()).collect();
+        assert!(throttle.attempt(IP).is_none());
+        let other = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 2));
+        assert!(throttle.attempt(other).is_some());
+        drop(pending);
+        assert!(throttle.attempt(IP).is_none());
+    }
+
+    #[test]
+    fn right_passwords_give_attempts_back() {
+        let throttle = AttemptThrottle::default();
+        for _ in 0..MAX_ATTEMPTS * 2 {
+            throttle.attempt(IP).unwrap().succeeded();
+        }
+        let _wrong = throttle.attempt(IP).unwrap();
+        // A right password does not wipe out earlier wrong ones.
+        throttle.attempt(IP).unwrap().succeeded();
+        assert_eq!(throttle.attempts.lock().unwrap()[&IP].count, 1);
+    }
+
+    #[test]
+    fn lockout_wears_off() {
+        let throttle = AttemptThrottle::default();
+        let start = Instant::now();
+        for _ in 0..MAX_ATTEMPTS {
+            let _ = throttle.attempt_at(IP, start);
+        }
+        assert!(throttle.attempt_at(IP, start).is_none());
+        assert!(throttle.attempt_at(IP, start + LOCKOUT / 2).is_none());
+        assert!(throttle.attempt_at(IP, start + LOCKOUT).is_some());
+    }
+}
@@ -0,0 +1,758 @@
+//! Messages exchanged over the room WebSocket. Every message is a JSON
+//! object with a `type`, and depending on the type a `target` (the user a
+//! client addresses), a `userId` (the user the server speaks about) and a
+//! `data` payload.
+
+use axum::extract::ws::Message;
+use serde::{Deserialize, Serialize};
+
+use crate::avatar;
+use crate::turn_server::TurnCredentials;
+
+/// Version of this protocol. Clients send theirs with `join` and are turned
+/// away when it differs.
+pub const PROTOCOL_VERSION: u32 = 3;
+
+pub const MAX_USER_ID_LEN: usize = 63;
+pub const MAX_NICKNAME_LEN: usize = 64;
+pub const MAX_CHAT_LEN: usize = 2000;
+const MAX_SDP_LEN: usize = 100_000;
+const MAX_CANDIDATE_LEN: usize = 2048;
+
+/// Messages a client sends.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case", rename_all_fields = "camelCase")]
+pub enum ClientMessage {
+    Join {
+        user_id: Option<String>,
+        /// Missing on clients that predate versioning.
+        #[serde(default)]
+        version: u32,
+        /// From the `welcome` of an earlier connection, to take its place.
+        resume_token: Option<String>,
+        data: JoinData,
+    },
+    UpdateUser { data: Profile },
+    CamToggle { data: CamToggle },
+    ScreenToggle { data: ScreenToggle },
+    /// Introduces the sender to a participant who just joined. The server
+    /// passes on what it knows about the sender; older clients also send
+    /// their state, which is ignored.
+    Identify { target: String },
+    Signal { target: String, data: SignalPayload },
+    /// A chat message to the room, or only to `target`.
+    Chat { target: Option<String>, data: ChatText },
+    Kick { target: String },
+    LockRoom { data: LockRoom },
+    Admit { target: String },
+    Deny { target: String },
+    SetKnockMode { data: SetKnockMode },
+    SetCapacity { data: Capacity },
+    EndMeeting {},
+    /// Asks for new TURN credentials before the current ones expire.
+    TurnCredentials {},
+}
+
+impl ClientMessage {
+    /// Checks the limits serde cannot express.
+    pub fn validate(&self) -> Result<(), &'static str> {
+        match self {
+            ClientMessage::Join { data, .. } => data.presence.profile.validate(),
+            ClientMessage::UpdateUser { data } => data.validate(),
+            ClientMessage::Signal { data, .. } => data.validate(),
+            ClientMessage::Chat { data, .. } => data.validate(),
+            _ => Ok(()),
+        }
+    }
+
+    /// The message's `type`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ClientMessage::Join { .. } => "join",
+            ClientMessage::UpdateUser { .. } => "update-user",
+            ClientMessage::CamToggle { .. } => "cam-toggle",
+            ClientMessage::ScreenToggle { .. } => "screen-toggle",
+            ClientMessage::Identify { .. } => "identify",
+            ClientMessage::Signal { .. } => "signal",
+            ClientMessage::Chat { .. } => "chat",
+            ClientMessage::Kick { .. } => "kick",
+            ClientMessage::LockRoom { .. } => "lock-room",
+            ClientMessage::Admit { .. } => "admit",
+            ClientMessage::Deny { .. } => "deny",
+            ClientMessage::SetKnockMode { .. } => "set-knock-mode",
+            ClientMessage::SetCapacity { .. } => "set-capacity",
+            ClientMessage::EndMeeting {} => "end-meeting",
+            ClientMessage::TurnCredentials {} => "turn-credentials",
+        }
+    }
+}
+
+/// User ids are chosen by the client, so they are kept to something that is
+/// safe to embed in element ids. Joins with any other id get a random one.
+pub fn valid_user_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_USER_ID_LEN && id.chars().all(|c| c.is_alphanumeric() || c == '-')
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Profile {
+    pub nickname: Option<String>,
+    /// Path of an uploaded image, `/avatars/<hash>`.
+    pub avatar: Option<String>,
+}
+
+impl Profile {
+    fn validate(&self) -> Result<(), &'static str> {
+        if self.nickname.as_ref().is_some_and(|n| n.chars().count() > MAX_NICKNAME_LEN) {
+            return Err("nickname too long");
+        }
+        Ok(())
+    }
+
+    /// Drops an avatar that is not an uploaded image.
+    pub fn sanitize(mut self) -> Profile {
+        if self.avatar.as_ref().is_some_and(|a| !avatar::valid_path(a)) {
+            self.avatar = None;
+        }
+        self
+    }
+}
+
+/// Who a participant is and what they are sending.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Presence {
+    #[serde(flatten)]
+    pub profile: Profile,
+    #[serde(default)]
+    pub cam_enabled: bool,
+    #[serde(default)]
+    pub screen_enabled: bool,
+    #[serde(default)]
+    pub screen_audio: bool,
+}
+
+impl Presence {
+    pub fn sanitize(self) -> Presence {
+        Presence {
+            profile: self.profile.sanitize(),
+            ..self
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct JoinData {
+    #[serde(flatten)]
+    pub presence: Presence,
+    /// Creates a protected room, or unlocks one. Never passed on.
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CamToggle {
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScreenToggle {
+    pub enabled: bool,
+    #[serde(default)]
+    pub has_audio: bool,
+}
+
+/// WebRTC negotiation between two participants, relayed as is.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum SignalPayload {
+    Offer { sdp: SessionDescription },
+    Answer { sdp: SessionDescription },
+    Candidate { candidate: IceCandidate },
+}
+
+impl SignalPayload {
+    fn validate(&self) -> Result<(), &'static str> {
+        match self {
+            SignalPayload::Offer { sdp } | SignalPayload::Answer { sdp } if sdp.sdp.len() > MAX_SDP_LEN => {
+                Err("session description too long")
+            }
+            SignalPayload::Candidate { candidate } if candidate.candidate.len() > MAX_CANDIDATE_LEN => {
+                Err("candidate too long")
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionDescription {
+    #[serde(rename = "type")]
+    pub kind: SdpType,
+    pub sdp: String,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SdpType {
+    Offer,
+    Answer,
+    Pranswer,
+    Rollback,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IceCandidate {
+    pub candidate: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sdp_mid: Option<String>,
+    #[serde(rename = "sdpMLineIndex", skip_serializing_if = "Option::is_none")]
+    pub sdp_m_line_index: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username_fragment: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChatText {
+    pub text: String,
+}
+
+impl ChatText {
+    fn validate(&self) -> Result<(), &'static str> {
+        if self.text.trim().is_empty() {
+            return Err("empty chat message");
+        }
+        if self.text.chars().count() > MAX_CHAT_LEN {
+            return Err("chat message too long");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LockRoom {
+    pub locked: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SetKnockMode {
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Capacity {
+    /// 0 for unlimited.
+    pub max_participants: usize,
+}
+
+/// Messages the server sends. `user_id` names the participant the message
+/// is about, or who caused it.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "kebab-case", rename_all_fields = "camelCase")]
+pub enum ServerMessage {
+    /// Confirms a join, with the id the server settled on.
+    Welcome { user_id: String, data: Welcome },
+    UserJoined { user_id: String, data: Presence },
+    UserLeft { user_id: String },
+    UserUpdate { user_id: String, data: Profile },
+    CamToggle { user_id: String, data: CamToggle },
+    ScreenToggle { user_id: String, data: ScreenToggle },
+    Identify { user_id: String, data: Presence },
+    /// Everyone in the room, sent to a participant when they get in.
+    RoomState { data: RoomState },
+    Signal { user_id: String, data: SignalPayload },
+    Chat { user_id: String, data: ChatMessage },
+    /// Earlier chat messages, sent to a participant when they get in.
+    ChatHistory { data: ChatHistory },
+    /// A participant uploaded a file. Files still available are also sent
+    /// to a participant when they get in.
+    FileShared { user_id: String, data: SharedFile },
+    /// A shared file expired and can no longer be downloaded.
+    FileRemoved { data: FileRemoved },
+    Error { data: ErrorBody },
+    HostChanged { user_id: String, data: RoomSettings },
+    RoomLocked { user_id: String, data: LockRoom },
+    KnockMode { user_id: String, data: SetKnockMode },
+    Capacity { user_id: String, data: Capacity },
+    /// A guest asking the host to be let in.
+    Knock { user_id: String, data: Profile },
+    KnockCancelled { user_id: String },
+    Waiting {},
+    Admitted {},
+    Denied {},
+    Kicked {},
+    MeetingEnded { user_id: String },
+    /// Credentials for the TURN server, issued to a participant when they
+    /// get in and whenever they ask for new ones.
+    TurnCredentials { data: TurnCredentials },
+    ServerShutdown {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        data: Option<ShutdownNotice>,
+    },
+}
+
+impl From<ServerMessage> for Message {
+    fn from(msg: ServerMessage) -> Message {
+        Message::Text(serde_json::to_string(&msg).expect("server messages serialize"))
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Welcome {
+    pub version: u32,
+    /// Lets the client take over its place if this connection drops.
+    pub resume_token: String,
+    /// Whether the join resumed an earlier connection. If so, the others
+    /// were not told and existing peer connections stay valid.
+    pub resumed: bool,
+}
+
+/// Why the server rejected something a client sent. Serialized as the
+/// `code` of an `error` message; the text next to it is for people.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorCode {
+    /// Not JSON, not a known message type, or a payload outside the limits.
+    InvalidMessage,
+    /// Anything but `join` before joining, or anything at all while waiting
+    /// for the host.
+    NotJoined,
+    /// A second `join` on the same connection.
+    AlreadyJoined,
+    /// No `join` arrived in time. The connection is closed.
+    JoinTimeout,
+    /// The client speaks another protocol version. The connection is closed.
+    UnsupportedVersion,
+    /// Another connection in the room uses the requested user id.
+    DuplicateId,
+    PasswordRequired,
+    WrongPassword,
+    /// The password is longer than the server accepts.
+    InvalidPassword,
+    /// Too many failed attempts from this IP.
+    RateLimited,
+    RoomLocked,
+    RoomFull,
+    /// Only the host may do that.
+    NotHost,
+    /// The `target` is not in the room, or not waiting to get in.
+    UnknownTarget,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ErrorBody {
+    pub code: ErrorCode,
+    pub message: String,
+    /// Size of the room that turned the client away.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_participants: Option<usize>,
+    /// Protocol version the server speaks, sent with a version mismatch.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<u32>,
+}
+
+impl ErrorBody {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> ErrorBody {
+        ErrorBody {
+            code,
+            message: message.into(),
+            max_participants: None,
+            version: None,
+        }
+    }
+}
+
+pub fn error_message(code: ErrorCode, message: impl Into<String>) -> ServerMessage {
+    ServerMessage::Error {
+        data: ErrorBody::new(code, message),
+    }
+}
+
+/// Turns away a client speaking another protocol version, telling it which
+/// one the server speaks.
+pub fn unsupported_version() -> ServerMessage {
+    let data = ErrorBody {
+        version: Some(PROTOCOL_VERSION),
+        ..ErrorBody::new(ErrorCode::UnsupportedVersion, "This page is out of date, reload it to join")
+    };
+    ServerMessage::Error { data }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RoomState {
+    /// In the order they joined, the recipient included.
+    pub participants: Vec<RosterEntry>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RosterEntry {
+    pub user_id: String,
+    #[serde(flatten)]
+    pub presence: Presence,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChatMessage {
+    /// Counts up within a room.
+    pub id: u64,
+    pub text: String,
+    /// Milliseconds since the Unix epoch, by the server's clock.
+    pub sent_at: u64,
+    /// The one participant a private message went to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChatHistory {
+    /// Oldest first. Private messages are not kept.
+    pub messages: Vec<ChatEntry>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChatEntry {
+    pub user_id: String,
+    #[serde(flatten)]
+    pub message: ChatMessage,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SharedFile {
+    pub id: String,
+    pub name: String,
+    /// In bytes.
+    pub size: u64,
+    /// Milliseconds since the Unix epoch, by the server's clock.
+    pub shared_at: u64,
+    /// When the file will be deleted, unless the room closes first.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FileRemoved {
+    pub id: String,
+}
+
+/// The settings the host controls.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomSettings {
+    pub locked: bool,
+    pub knock_mode: bool,
+    pub max_participants: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ShutdownNotice {
+    /// Seconds until clients should try to reconnect.
+    pub reconnect_after: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    /// The page served to browsers, whose script has to agree with the tags
+    /// below.
+    const PAGE: &str = include_str!("main.rs");
+
+    fn client(msg: Value) -> ClientMessage {
+        serde_json::from_value(msg).unwrap()
+    }
+
+    fn server(msg: ServerMessage) -> Value {
+        let Message::Text(text) = Message::from(msg) else {
+            panic!("server messages are text");
+        };
+        serde_json::from_str(&text).unwrap()
+    }
+
+    /// One message of every type, as the page sends it.
+    fn client_messages() -> Vec<Value> {
+        let sdp = json!({ "type": "offer", "sdp": "v=0" });
+        vec![
+            json!({ "type": "join", "userId": "a", "version": PROTOCOL_VERSION, "resumeToken": null, "data": { "nickname": "A", "avatar": null, "camEnabled": true, "password": "pw" } }),
+            json!({ "type": "update-user", "data": { "nickname": "A", "avatar": null } }),
+            json!({ "type": "cam-toggle", "data": { "enabled": true } }),
+            json!({ "type": "screen-toggle", "data": { "enabled": true, "hasAudio": true } }),
+            json!({ "type": "identify", "target": "b" }),
+            json!({ "type": "signal", "target": "b", "data": { "type": "offer", "sdp": sdp } }),
+            json!({ "type": "signal", "target": "b", "data": { "type": "answer", "sdp": { "type": "answer", "sdp": "v=0" } } }),
+            json!({ "type": "signal", "target": "b", "data": { "type": "candidate", "candidate": { "candidate": "candidate:1", "sdpMid": "0", "sdpMLineIndex": 0 } } }),
+            json!({ "type": "chat", "target": null, "data": { "text": "hi" } }),
+            json!({ "type": "kick", "target": "b" }),
+            json!({ "type": "lock-room", "data": { "locked": true } }),
+            json!({ "type": "admit", "target": "b" }),
+            json!({ "type": "deny", "target": "b" }),
+            json!({ "type": "set-knock-mode", "data": { "enabled": true } }),
+            json!({ "type": "set-capacity", "data": { "maxParticipants": 4 } }),
+            json!({ "type": "end-meeting" }),
+            json!({ "type": "turn-credentials" }),
+        ]
+    }
+
+    #[test]
+    fn client_messages_parse_by_their_tag() {
+        for raw in client_messages() {
+            let tag = raw["type"].as_str().unwrap().to_string();
+            let msg = client(raw);
+            assert_eq!(msg.kind(), tag);
+            assert_eq!(msg.validate(), Ok(()), "{tag}");
+            assert!(PAGE.contains(&format!("'{tag}'")) || PAGE.contains(&format!("\"{tag}\"")), "the page never sends {tag}");
+        }
+
+        let ClientMessage::Join { user_id, version, data, .. } = client(client_messages().remove(0)) else {
+            panic!("not a join");
+        };
+        assert_eq!(user_id.as_deref(), Some("a"));
+        assert_eq!(version, PROTOCOL_VERSION);
+        assert_eq!(data.password.as_deref(), Some("pw"));
+        assert!(data.presence.cam_enabled);
+        assert_eq!(data.presence.profile.nickname.as_deref(), Some("A"));
+    }
+
+    #[test]
+    fn unknown_and_malformed_messages_are_refused() {
+        for raw in [
+            json!({ "type": "teleport" }),
+            json!({ "type": "kick" }),
+            json!({ "type": "chat", "data": { "text": 5 } }),
+            json!({ "type": "signal", "target": "b", "data": { "type": "bye" } }),
+            json!({ "data": { "enabled": true } }),
+        ] {
+            assert!(serde_json::from_value::<ClientMessage>(raw.clone()).is_err(), "{raw}");
+        }
+    }
+
+    /// The tag each server message is sent with. Matching on every variant
+    /// means a new one does not compile until it is listed here.
+    fn server_tag(msg: &ServerMessage) -> &'static str {
+        match msg {
+            ServerMessage::Welcome { .. } => "welcome",
+            ServerMessage::UserJoined { .. } => "user-joined",
+            ServerMessage::UserLeft { .. } => "user-left",
+            ServerMessage::UserUpdate { .. } => "user-update",
+            ServerMessage::CamToggle { .. } => "cam-toggle",
+            ServerMessage::ScreenToggle { .. } => "screen-toggle",
+            ServerMessage::Identify { .. } => "identify",
+            ServerMessage::RoomState { .. } => "room-state",
+            ServerMessage::Signal { .. } => "signal",
+            ServerMessage::Chat { .. } => "chat",
+            ServerMessage::ChatHistory { .. } => "chat-history",
+            ServerMessage::FileShared { .. } => "file-shared",
+            ServerMessage::FileRemoved { .. } => "file-removed",
+            ServerMessage::Error { .. } => "error",
+            ServerMessage::HostChanged { .. } => "host-changed",
+            ServerMessage::RoomLocked { .. } => "room-locked",
+            ServerMessage::KnockMode { .. } => "knock-mode",
+            ServerMessage::Capacity { .. } => "capacity",
+            ServerMessage::Knock { .. } => "knock",
+            ServerMessage::KnockCancelled { .. } => "knock-cancelled",
+            ServerMessage::Waiting {} => "waiting",
+            ServerMessage::Admitted {} => "admitted",
+            ServerMessage::Denied {} => "denied",
+            ServerMessage::Kicked {} => "kicked",
+            ServerMessage::MeetingEnded { .. } => "meeting-ended",
+            ServerMessage::TurnCredentials { .. } => "turn-credentials",
+            ServerMessage::ServerShutdown { .. } => "server-shutdown",
+        }
+    }
+
+    fn server_messages() -> Vec<ServerMessage> {
+        let user_id = || "a".to_string();
+        let profile = Profile {
+            nickname: Some("A".into()),
+            avatar: None,
+        };
+        let presence = Presence {
+            profile: profile.clone(),
+            cam_enabled: true,
+            ..Presence::default()
+        };
+        let chat = ChatMessage {
+            id: 1,
+            text: "hi".into(),
+            sent_at: 1000,
+            target: None,
+        };
+        let file = SharedFile {
+            id: "f".into(),
+            name: "notes.txt".into(),
+            size: 5,
+            shared_at: 1000,
+            expires_at: None,
+        };
+        let settings = RoomSettings {
+            locked: false,
+            knock_mode: true,
+            max_participants: 8,
+        };
+        vec![
+            ServerMessage::Welcome {
+                user_id: user_id(),
+                data: Welcome {
+                    version: PROTOCOL_VERSION,
+                    resume_token: "t".into(),
+                    resumed: false,
+                },
+            },
+            ServerMessage::UserJoined { user_id: user_id(), data: presence.clone() },
+            ServerMessage::UserLeft { user_id: user_id() },
+            ServerMessage::UserUpdate { user_id: user_id(), data: profile.clone() },
+            ServerMessage::CamToggle { user_id: user_id(), data: CamToggle { enabled: true } },
+            ServerMessage::ScreenToggle {
+                user_id: user_id(),
+                data: ScreenToggle {
+                    enabled: true,
+                    has_audio: false,
+                },
+            },
+            ServerMessage::Identify { user_id: user_id(), data: presence.clone() },
+            ServerMessage::RoomState {
+                data: RoomState {
+                    participants: vec![RosterEntry { user_id: user_id(), presence }],
+                },
+            },
+            ServerMessage::Signal {
+                user_id: user_id(),
+                data: SignalPayload::Candidate {
+                    candidate: IceCandidate {
+                        candidate: "candidate:1".into(),
+                        sdp_mid: None,
+                        sdp_m_line_index: Some(0),
+                        username_fragment: None,
+                    },
+                },
+            },
+            ServerMessage::Chat { user_id: user_id(), data: chat.clone() },
+            ServerMessage::ChatHistory {
+                data: ChatHistory {
+                    messages: vec![ChatEntry { user_id: user_id(), message: chat }],
+                },
+            },
+            ServerMessage::FileShared { user_id: user_id(), data: file },
+            ServerMessage::FileRemoved { data: FileRemoved { id: "f".into() } },
+            error_message(ErrorCode::NotHost, "Only the host can do that"),
+            ServerMessage::HostChanged { user_id: user_id(), data: settings },
+            ServerMessage::RoomLocked { user_id: user_id(), data: LockRoom { locked: true } },
+            ServerMessage::KnockMode { user_id: user_id(), data: SetKnockMode { enabled: true } },
+            ServerMessage::Capacity { user_id: user_id(), data: Capacity { max_participants: 4 } },
+            ServerMessage::Knock { user_id: user_id(), data: profile },
+            ServerMessage::KnockCancelled { user_id: user_id() },
+            ServerMessage::Waiting {},
+            ServerMessage::Admitted {},
+            ServerMessage::Denied {},
+            ServerMessage::Kicked {},
+            ServerMessage::MeetingEnded { user_id: user_id() },
+            ServerMessage::TurnCredentials {
+                data: TurnCredentials {
+                    username: "1:a".into(),
+                    credential: "c".into(),
+                    ttl: 3600,
+                },
+            },
+            ServerMessage::ServerShutdown {
+                data: Some(ShutdownNotice { reconnect_after: 5 }),
+            },
+        ]
+    }
+
+    #[test]
+    fn server_messages_carry_their_tag() {
+        let messages = server_messages();
+        assert_eq!(messages.len(), 27, "one of every type");
+        for msg in messages {
+            let tag = server_tag(&msg);
+            let json = server(msg);
+            assert_eq!(json["type"], tag);
+            // Payloads are camelCase, as the page reads them.
+            assert!(!json.to_string().contains('_'), "{json}");
+            assert!(PAGE.contains(&format!("case '{tag}'")), "the page does not handle {tag}");
+        }
+    }
+
+    #[test]
+    fn server_message_shapes() {
+        let json = server(ServerMessage::UserJoined {
+            user_id: "a".into(),
+            data: Presence::default(),
+        });
+        assert_eq!(
+            json,
+            json!({ "type": "user-joined", "userId": "a", "data": { "nickname": null, "avatar": null, "camEnabled": false, "screenEnabled": false, "screenAudio": false } })
+        );
+        assert_eq!(server(ServerMessage::Waiting {}), json!({ "type": "waiting" }));
+        assert_eq!(server(ServerMessage::ServerShutdown { data: None }), json!({ "type": "server-shutdown" }));
+    }
+
+    #[test]
+    fn unsupported_version_names_the_server_version() {
+        assert_eq!(
+            server(unsupported_version()),
+            json!({
+                "type": "error",
+                "data": { "code": "unsupported-version", "message": "This page is out of date, reload it to join", "version": PROTOCOL_VERSION },
+            })
+        );
+        // Clients from before versioning send none, which never matches.
+        let ClientMessage::Join { version, .. } = client(json!({ "type": "join", "data": {} })) else {
+            panic!("not a join");
+        };
+        assert_ne!(version, PROTOCOL_VERSION);
+    }
+
+    fn chat(text: &str) -> ClientMessage {
+        client(json!({ "type": "chat", "data": { "text": text } }))
+    }
+
+    fn sdp(len: usize) -> ClientMessage {
+        client(json!({ "type": "signal", "target": "b", "data": { "type": "answer", "sdp": { "type": "answer", "sdp": "v".repeat(len) } } }))
+    }
+
+    fn candidate(len: usize) -> ClientMessage {
+        client(json!({ "type": "signal", "target": "b", "data": { "type": "candidate", "candidate": { "candidate": "c".repeat(len) } } }))
+    }
+
+    fn nickname(nickname: &str) -> ClientMessage {
+        client(json!({ "type": "update-user", "data": { "nickname": nickname } }))
+    }
+
+    #[test]
+    fn length_limits() {
+        // Counted in characters, not bytes.
+        assert!(chat(&"é".repeat(MAX_CHAT_LEN)).validate().is_ok());
+        assert!(chat(&"é".repeat(MAX_CHAT_LEN + 1)).validate().is_err());
+        assert!(chat(" \n ").validate().is_err());
+
+        assert!(sdp(MAX_SDP_LEN).validate().is_ok());
+        assert!(sdp(MAX_SDP_LEN + 1).validate().is_err());
+        assert!(candidate(MAX_CANDIDATE_LEN).validate().is_ok());
+        assert!(candidate(MAX_CANDIDATE_LEN + 1).validate().is_err());
+
+        assert!(nickname(&"ü".repeat(MAX_NICKNAME_LEN)).validate().is_ok());
+        assert!(nickname(&"ü".repeat(MAX_NICKNAME_LEN + 1)).validate().is_err());
+        let join = json!({ "type": "join", "version": PROTOCOL_VERSION, "data": { "nickname": "n".repeat(MAX_NICKNAME_LEN + 1) } });
+        assert!(client(join).validate().is_err());
+    }
+
+    #[test]
+    fn user_ids() {
+        assert!(valid_user_id("abc-123"));
+        assert!(valid_user_id(&"a".repeat(MAX_USER_ID_LEN)));
+        assert!(!valid_user_id(&"a".repeat(MAX_USER_ID_LEN + 1)));
+        assert!(!valid_user_id(""));
+        assert!(!valid_user_id("a b"));
+        assert!(!valid_user_id("a\"><script>"));
+    }
+}
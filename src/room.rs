@@ -0,0 +1,1487 @@
+//! Rooms and the participants in them. Every room runs as its own task that
+//! owns the room's state and handles the messages of its participants one at
+//! a time, so a busy room never holds up another. The [`RoomMap`] only
+//! finds the task for a room id and is not touched per message.
+
+use axum::extract::ws::{close_code, CloseCode, CloseFrame, Message};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::{mpsc, oneshot};
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use crate::admin;
+use crate::config::Config;
+use crate::files;
+use crate::metrics::Metrics;
+use crate::outbox::{Outbox, Priority};
+use crate::password::PasswordHash;
+use crate::protocol::{
+    error_message, CamToggle, Capacity, ChatEntry, ChatHistory, ChatMessage, ClientMessage, ErrorBody, ErrorCode, FileRemoved, LockRoom, Presence,
+    RoomSettings, RoomState, RosterEntry, ScreenToggle, ServerMessage, SetKnockMode, SharedFile, Welcome, PROTOCOL_VERSION,
+};
+
+/// Commands a room accepts before its participants have to wait.
+const COMMAND_QUEUE: usize = 256;
+
+/// Uploads one participant may have in progress at once.
+const MAX_UPLOADS: usize = 2;
+
+/// One connection's place in a room.
+#[derive(Clone)]
+pub struct Participant {
+    pub outbox: Arc<Outbox>,
+    /// Cancelled to drop the connection from the server side.
+    pub closed: CancellationToken,
+    joined_at: Instant,
+    /// Secret that lets a new connection take over this one's place.
+    resume_token: String,
+}
+
+impl Participant {
+    pub fn new(outbox: Arc<Outbox>) -> Participant {
+        Participant {
+            outbox,
+            closed: CancellationToken::new(),
+            joined_at: Instant::now(),
+            resume_token: Uuid::new_v4().simple().to_string(),
+        }
+    }
+
+    pub fn send(&self, msg: ServerMessage) {
+        self.outbox.send(msg);
+    }
+
+    /// Whether both are the same connection.
+    fn is(&self, other: &Participant) -> bool {
+        Arc::ptr_eq(&self.outbox, &other.outbox)
+    }
+
+    /// Sends a close frame and stops reading from the socket.
+    pub fn disconnect(&self, code: CloseCode, reason: &'static str) {
+        let frame = Message::Close(Some(CloseFrame {
+            code,
+            reason: reason.into(),
+        }));
+        self.outbox.send_frame(frame, Priority::Signal);
+        self.closed.cancel();
+    }
+}
+
+/// What a connection asked for with `join`.
+#[derive(Clone)]
+pub struct JoinRequest {
+    pub user_id: String,
+    pub password: Option<String>,
+    pub resume_token: Option<String>,
+    pub presence: Presence,
+}
+
+/// A room's answer to a join request.
+pub enum JoinReply {
+    /// In the room, or on its waiting list.
+    Admitted,
+    Refused(JoinRefusal),
+    /// The room is protected. The caller checks the password against the
+    /// hash, off the room's task, and asks again with the hash it verified.
+    CheckPassword(Arc<PasswordHash>),
+}
+
+/// Why a join request was turned down.
+pub enum JoinRefusal {
+    /// Another connection already uses the requested user id.
+    DuplicateId,
+    PasswordRequired,
+    WrongPassword,
+    InvalidPassword,
+    /// Too many password attempts from this IP.
+    RateLimited,
+    /// The host locked the room.
+    Locked,
+    /// The room already holds its maximum number of participants.
+    Full(usize),
+}
+
+impl JoinRefusal {
+    pub fn message(&self) -> ServerMessage {
+        let (code, message) = match self {
+            JoinRefusal::DuplicateId => (ErrorCode::DuplicateId, "This user is already in the room"),
+            JoinRefusal::PasswordRequired => (ErrorCode::PasswordRequired, "This room is protected by a password"),
+            JoinRefusal::WrongPassword => (ErrorCode::WrongPassword, "Wrong room password"),
+            JoinRefusal::InvalidPassword => (ErrorCode::InvalidPassword, "Room passwords are limited to 128 characters"),
+            JoinRefusal::RateLimited => (ErrorCode::RateLimited, "Too many password attempts, try again in a minute"),
+            JoinRefusal::Locked => (ErrorCode::RoomLocked, "The host has locked this room"),
+            JoinRefusal::Full(max) => {
+                let data = ErrorBody {
+                    max_participants: Some(*max),
+                    ..ErrorBody::new(ErrorCode::RoomFull, "This room is full")
+                };
+                return ServerMessage::Error { data };
+            }
+        };
+        error_message(code, message)
+    }
+}
+
+/// A file written to disk, waiting for the room to accept it.
+pub struct Upload {
+    pub id: String,
+    pub name: String,
+    pub size: u64,
+}
+
+/// Why a file (or an avatar) could not be uploaded or downloaded.
+pub enum FileRefusal {
+    /// Nobody in the room holds the token, or the room is gone.
+    NotParticipant,
+    /// The room's files would take more than its quota.
+    QuotaExceeded,
+    /// The participant already has [`MAX_UPLOADS`] uploads in progress.
+    TooManyUploads,
+    NoSuchFile,
+}
+
+/// Capacity a host may give their room: anything up to the server-wide
+/// maximum, which is also what asking for 0 (unlimited) gets them.
+fn room_capacity(requested: usize, server_max: usize) -> usize {
+    match (requested, server_max) {
+        (requested, 0) => requested,
+        (0, server_max) => server_max,
+        (requested, server_max) => requested.min(server_max),
+    }
+}
+
+enum Command {
+    Join {
+        request: JoinRequest,
+        participant: Participant,
+        /// The room's password hash, if the caller checked the password.
+        verified: Option<Arc<PasswordHash>>,
+        reply: oneshot::Sender<JoinReply>,
+    },
+    Message {
+        user_id: String,
+        participant: Participant,
+        msg: ClientMessage,
+    },
+    /// The connection ended. A participant whose connection `dropped` keeps
+    /// their place for the resume grace period.
+    Leave {
+        user_id: String,
+        participant: Participant,
+        dropped: bool,
+    },
+    /// The resume grace period of a dropped participant is over.
+    Expire {
+        user_id: String,
+        participant: Participant,
+    },
+    Broadcast(ServerMessage),
+    /// Sets `size` bytes of the quota aside for the upload `id` by the
+    /// participant with the resume token `token`.
+    ReserveUpload {
+        token: String,
+        id: String,
+        size: u64,
+        reply: oneshot::Sender<Result<(), FileRefusal>>,
+    },
+    /// Gives back what an upload that did not make it had set aside.
+    ReleaseUpload { id: String },
+    /// Shares an uploaded file with the room.
+    AddFile {
+        token: String,
+        upload: Upload,
+        reply: oneshot::Sender<Result<SharedFile, FileRefusal>>,
+    },
+    GetFile {
+        token: String,
+        id: String,
+        reply: oneshot::Sender<Result<SharedFile, FileRefusal>>,
+    },
+    /// A shared file's time is up.
+    ExpireFile { id: String },
+    /// Answers with the user id of the participant holding `token`.
+    Authorize {
+        token: String,
+        reply: oneshot::Sender<Result<String, FileRefusal>>,
+    },
+    /// Answers with the avatars shown in the room, waiting guests' included.
+    Avatars { reply: oneshot::Sender<Vec<String>> },
+    /// Disconnects everyone, answering with how many were connected.
+    Close {
+        code: CloseCode,
+        reason: &'static str,
+        reply: oneshot::Sender<usize>,
+    },
+}
+
+/// Sends commands to a room's task. Once the room has closed, sending fails
+/// and the caller looks the room up again.
+#[derive(Clone)]
+pub struct RoomHandle {
+    commands: mpsc::Sender<Command>,
+    /// Kept up to date by the room, for metrics.
+    participants: Arc<AtomicUsize>,
+}
+
+impl RoomHandle {
+    /// Asks the room to let `participant` in. Returns `None` if the room
+    /// closed in the meantime.
+    pub async fn join(
+        &self,
+        request: JoinRequest,
+        participant: Participant,
+        verified: Option<Arc<PasswordHash>>,
+    ) -> Option<JoinReply> {
+        let (reply, answer) = oneshot::channel();
+        let cmd = Command::Join {
+            request,
+            participant,
+            verified,
+            reply,
+        };
+        self.commands.send(cmd).await.ok()?;
+        answer.await.ok()
+    }
+
+    /// Hands a message from `user_id` to the room. Returns false if the
+    /// room has closed.
+    pub async fn send(&self, user_id: &str, participant: &Participant, msg: ClientMessage) -> bool {
+        let cmd = Command::Message {
+            user_id: user_id.to_string(),
+            participant: participant.clone(),
+            msg,
+        };
+        self.commands.send(cmd).await.is_ok()
+    }
+
+    pub async fn leave(&self, user_id: String, participant: Participant, dropped: bool) {
+        let _ = self
+            .commands
+            .send(Command::Leave {
+                user_id,
+                participant,
+                dropped,
+            })
+            .await;
+    }
+
+    pub async fn broadcast(&self, msg: ServerMessage) {
+        let _ = self.commands.send(Command::Broadcast(msg)).await;
+    }
+
+    /// Sets aside room for the holder of `token` to upload the file `id`
+    /// of at most `size` bytes. Until the file is added or the reservation
+    /// released, it counts against the room's quota.
+    pub async fn reserve_upload(&self, token: &str, id: &str, size: u64) -> Result<(), FileRefusal> {
+        let (reply, answer) = oneshot::channel();
+        let cmd = Command::ReserveUpload {
+            token: token.to_string(),
+            id: id.to_string(),
+            size,
+            reply,
+        };
+        self.ask(cmd, answer).await
+    }
+
+    pub async fn release_upload(&self, id: &str) {
+        let _ = self.commands.send(Command::ReleaseUpload { id: id.to_string() }).await;
+    }
+
+    /// Hands a stored file to the room, which announces it, in place of
+    /// its reservation. If the room refuses it, the caller deletes the file.
+    pub async fn add_file(&self, token: &str, upload: Upload) -> Result<SharedFile, FileRefusal> {
+        let (reply, answer) = oneshot::channel();
+        let cmd = Command::AddFile {
+            token: token.to_string(),
+            upload,
+            reply,
+        };
+        self.ask(cmd, answer).await
+    }
+
+    /// Looks up a shared file for the holder of `token`.
+    pub async fn file(&self, token: &str, id: &str) -> Result<SharedFile, FileRefusal> {
+        let (reply, answer) = oneshot::channel();
+        let cmd = Command::GetFile {
+            token: token.to_string(),
+            id: id.to_string(),
+            reply,
+        };
+        self.ask(cmd, answer).await
+    }
+
+    /// The user id of the participant whose resume token is `token`.
+    pub async fn participant(&self, token: &str) -> Result<String, FileRefusal> {
+        let (reply, answer) = oneshot::channel();
+        let cmd = Command::Authorize {
+            token: token.to_string(),
+            reply,
+        };
+        self.ask(cmd, answer).await
+    }
+
+    pub async fn avatars(&self) -> Vec<String> {
+        let (reply, answer) = oneshot::channel();
+        if self.commands.send(Command::Avatars { reply }).await.is_err() {
+            return Vec::new();
+        }
+        answer.await.unwrap_or_default()
+    }
+
+    /// Sends a file command. A room that has closed has no participants.
+    async fn ask<T>(&self, cmd: Command, answer: oneshot::Receiver<Result<T, FileRefusal>>) -> Result<T, FileRefusal> {
+        if self.commands.send(cmd).await.is_err() {
+            return Err(FileRefusal::NotParticipant);
+        }
+        answer.await.unwrap_or(Err(FileRefusal::NotParticipant))
+    }
+
+    /// Disconnects everyone in the room and returns how many that were.
+    pub async fn close(&self, code: CloseCode, reason: &'static str) -> usize {
+        let (reply, answer) = oneshot::channel();
+        if self.commands.send(Command::Close { code, reason, reply }).await.is_err() {
+            return 0;
+        }
+        answer.await.unwrap_or(0)
+    }
+
+    fn is(&self, other: &RoomHandle) -> bool {
+        self.commands.same_channel(&other.commands)
+    }
+}
+
+/// The open rooms by id. The lock is only held to look up, add or remove a
+/// room, never while a room handles a message.
+#[derive(Clone)]
+pub struct RoomMap {
+    rooms: Arc<StdMutex<HashMap<String, RoomHandle>>>,
+    config: Arc<Config>,
+    metrics: Arc<Metrics>,
+}
+
+impl RoomMap {
+    pub fn new(config: Arc<Config>, metrics: Arc<Metrics>) -> RoomMap {
+        RoomMap {
+            rooms: Arc::new(StdMutex::new(HashMap::new())),
+            config,
+            metrics,
+        }
+    }
+
+    pub fn get(&self, room_id: &str) -> Option<RoomHandle> {
+        self.rooms.lock().unwrap().get(room_id).cloned()
+    }
+
+    /// Opens a room with `request`'s user as its host and `password`, if
+    /// any, protecting it. Returns `None` if the room exists by now.
+    pub fn create(
+        &self,
+        room_id: &str,
+        request: JoinRequest,
+        participant: Participant,
+        password: Option<Arc<PasswordHash>>,
+    ) -> Option<RoomHandle> {
+        let mut rooms = self.rooms.lock().unwrap();
+        if rooms.contains_key(room_id) {
+            return None;
+        }
+
+        let (commands, inbox) = mpsc::channel(COMMAND_QUEUE);
+        let handle = RoomHandle {
+            commands,
+            participants: Arc::new(AtomicUsize::new(1)),
+        };
+        let mut room = Room {
+            users: HashMap::new(),
+            pending: HashMap::new(),
+            password,
+            host: request.user_id.clone(),
+            locked: false,
+            knock: false,
+            capacity: self.config.max_participants,
+            chat: VecDeque::new(),
+            last_chat_id: 0,
+            files: Vec::new(),
+            uploads: HashMap::new(),
+            config: self.config.clone(),
+            metrics: self.metrics.clone(),
+            handle: handle.clone(),
+        };
+        participant.send(welcome(&request.user_id, &participant, false));
+        let member = Member {
+            participant,
+            presence: request.presence,
+        };
+        room.users.insert(request.user_id.clone(), member);
+        room.announce_join(&request.user_id);
+
+        rooms.insert(room_id.to_string(), handle.clone());
+        tokio::spawn(run(room, inbox, self.clone(), room_id.to_string()));
+        Some(handle)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rooms.lock().unwrap().is_empty()
+    }
+
+    pub fn handles(&self) -> Vec<RoomHandle> {
+        self.rooms.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Avatars shown in any open room.
+    pub async fn avatars(&self) -> HashSet<String> {
+        let mut avatars = HashSet::new();
+        for room in self.handles() {
+            avatars.extend(room.avatars().await);
+        }
+        avatars
+    }
+
+    /// Participants in each open room.
+    pub fn sizes(&self) -> Vec<usize> {
+        self.rooms
+            .lock()
+            .unwrap()
+            .values()
+            .map(|room| room.participants.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    /// Removes the room, unless the id has been taken by a newer one.
+    fn remove(&self, room_id: &str, handle: &RoomHandle) {
+        let mut rooms = self.rooms.lock().unwrap();
+        if rooms.get(room_id).is_some_and(|current| current.is(handle)) {
+            rooms.remove(room_id);
+        }
+    }
+}
+
+/// Runs a room until the last participant has left, then deletes the files
+/// shared in it. Commands still queued when it stops are dropped, which
+/// their senders see as the room being gone.
+async fn run(mut room: Room, mut commands: mpsc::Receiver<Command>, rooms: RoomMap, room_id: String) {
+    while let Some(cmd) = commands.recv().await {
+        room.handle(cmd);
+        room.handle.participants.store(room.users.len(), Ordering::Relaxed);
+        if room.users.is_empty() {
+            break;
+        }
+    }
+    rooms.remove(&room_id, &room.handle);
+    for stored in room.files {
+        files::remove(&room.config.files, &stored.file.id).await;
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Confirms a join to the participant.
+fn welcome(user_id: &str, participant: &Participant, resumed: bool) -> ServerMessage {
+    ServerMessage::Welcome {
+        user_id: user_id.to_string(),
+        data: Welcome {
+            version: PROTOCOL_VERSION,
+            resume_token: participant.resume_token.clone(),
+            resumed,
+        },
+    }
+}
+
+/// A participant, or a guest waiting for the host to let them in, along
+/// with the state they last told the room about.
+struct Member {
+    participant: Participant,
+    presence: Presence,
+}
+
+/// A file shared in the room and who shared it.
+struct StoredFile {
+    user_id: String,
+    file: SharedFile,
+}
+
+/// Quota set aside for an upload in progress.
+struct Reservation {
+    user_id: String,
+    size: u64,
+}
+
+struct Room {
+    users: HashMap<String, Member>,
+    /// Guests waiting for the host while the room is in knock mode.
+    pending: HashMap<String, Member>,
+    /// Set by the first joiner. Later joiners must present the password.
+    password: Option<Arc<PasswordHash>>,
+    /// Participant allowed to kick, lock the room and end the meeting.
+    host: String,
+    /// No new participants can join while set.
+    locked: bool,
+    /// New participants wait for the host's admission while set.
+    knock: bool,
+    /// Most participants allowed at once, 0 for unlimited.
+    capacity: usize,
+    /// The latest chat messages to everyone, replayed to later joiners.
+    chat: VecDeque<ChatEntry>,
+    last_chat_id: u64,
+    /// Shared files that have not expired, oldest first.
+    files: Vec<StoredFile>,
+    /// Uploads in progress by upload id.
+    uploads: HashMap<String, Reservation>,
+    config: Arc<Config>,
+    metrics: Arc<Metrics>,
+    /// This room's own handle, to schedule commands for later.
+    handle: RoomHandle,
+}
+
+impl Room {
+    fn handle(&mut self, cmd: Command) {
+        match cmd {
+            Command::Join {
+                request,
+                participant,
+                verified,
+                reply,
+            } => {
+                let _ = reply.send(self.join(request, participant, verified));
+            }
+            Command::Message {
+                user_id,
+                participant,
+                msg,
+            } => self.receive(&user_id, &participant, msg),
+            Command::Leave {
+                user_id,
+                participant,
+                dropped,
+            } => {
+                if self.is_member(&user_id, &participant) {
+                    let grace = self.config.resume_grace;
+                    if dropped && !grace.is_zero() {
+                        self.schedule(grace, Command::Expire { user_id, participant });
+                    } else {
+                        self.leave(&user_id);
+                    }
+                } else if self.is_waiting(&user_id, &participant) {
+                    self.withdraw(&user_id);
+                }
+            }
+            Command::Expire { user_id, participant } => {
+                // Unless a new connection has resumed the place meanwhile.
+                if self.is_member(&user_id, &participant) {
+                    self.leave(&user_id);
+                }
+            }
+            Command::Broadcast(msg) => self.broadcast(msg, None),
+            Command::ReserveUpload { token, id, size, reply } => {
+                let _ = reply.send(self.reserve_upload(&token, id, size));
+            }
+            Command::ReleaseUpload { id } => {
+                self.uploads.remove(&id);
+            }
+            Command::AddFile { token, upload, reply } => {
+                let _ = reply.send(self.add_file(&token, upload));
+            }
+            Command::GetFile { token, id, reply } => {
+                let file = match self.holder(&token) {
+                    None => Err(FileRefusal::NotParticipant),
+                    Some(_) => self
+                        .files
+                        .iter()
+                        .find(|f| f.file.id == id)
+                        .map(|f| f.file.clone())
+                        .ok_or(FileRefusal::NoSuchFile),
+                };
+                let _ = reply.send(file);
+            }
+            Command::ExpireFile { id } => self.expire_file(&id),
+            Command::Authorize { token, reply } => {
+                let _ = reply.send(self.holder(&token).map(str::to_string).ok_or(FileRefusal::NotParticipant));
+            }
+            Command::Avatars { reply } => {
+                let avatars = self
+                    .users
+                    .values()
+                    .chain(self.pending.values())
+                    .filter_map(|m| m.presence.profile.avatar.clone())
+                    .collect();
+                let _ = reply.send(avatars);
+            }
+            Command::Close { code, reason, reply } => {
+                for member in self.users.values() {
+                    member.participant.disconnect(code, reason);
+                }
+                let _ = reply.send(self.users.len());
+            }
+        }
+    }
+
+    /// Lets a participant in, or puts them on the waiting list if the room
+    /// is in knock mode. A user who is still in the room can take their
+    /// place back with their resume token.
+    fn join(&mut self, request: JoinRequest, participant: Participant, verified: Option<Arc<PasswordHash>>) -> JoinReply {
+        let user_id = &request.user_id;
+        if self.contains(user_id) {
+            return match self.resume(user_id, request.resume_token.as_deref(), &participant) {
+                Ok(()) => {
+                    participant.send(welcome(user_id, &participant, true));
+                    participant.send(self.turn_message(user_id));
+                    participant.send(self.host_message());
+                    participant.send(self.state_message());
+                    if let Some(history) = self.history_message() {
+                        participant.send(history);
+                    }
+                    for msg in self.file_messages() {
+                        participant.send(msg);
+                    }
+                    self.update_presence(user_id, request.presence);
+                    JoinReply::Admitted
+                }
+                Err(refusal) => JoinReply::Refused(refusal),
+            };
+        }
+        if self.locked {
+            return JoinReply::Refused(JoinRefusal::Locked);
+        }
+        if self.is_full() {
+            return JoinReply::Refused(JoinRefusal::Full(self.capacity));
+        }
+        if let Some(hash) = &self.password
+            && !verified.is_some_and(|v| Arc::ptr_eq(&v, hash))
+        {
+            return JoinReply::CheckPassword(hash.clone());
+        }
+
+        participant.send(welcome(user_id, &participant, false));
+        if self.seat(user_id, participant, request.presence) {
+            self.announce_join(user_id);
+        }
+        JoinReply::Admitted
+    }
+
+    /// Handles a message from a participant who joined.
+    fn receive(&mut self, user_id: &str, participant: &Participant, msg: ClientMessage) {
+        // Guests in the waiting room cannot talk to anyone yet. Kicked and
+        // denied participants are removed before their socket closes; nothing
+        // they send afterwards is passed on.
+        if !self.is_member(user_id, participant) {
+            if self.is_waiting(user_id, participant) {
+                participant.send(error_message(ErrorCode::NotJoined, "Waiting for the host to let you in"));
+            }
+            return;
+        }
+
+        match msg {
+            ClientMessage::Join { .. } => {
+                participant.send(error_message(ErrorCode::AlreadyJoined, "Already in the room"));
+            }
+            ClientMessage::UpdateUser { data } => {
+                let presence = Presence {
+                    profile: data.sanitize(),
+                    ..self.presence(user_id)
+                };
+                self.update_presence(user_id, presence);
+            }
+            ClientMessage::CamToggle { data } => {
+                let presence = Presence {
+                    cam_enabled: data.enabled,
+                    ..self.presence(user_id)
+                };
+                self.update_presence(user_id, presence);
+            }
+            ClientMessage::ScreenToggle { data } => {
+                let presence = Presence {
+                    screen_enabled: data.enabled,
+                    screen_audio: data.enabled && data.has_audio,
+                    ..self.presence(user_id)
+                };
+                self.update_presence(user_id, presence);
+            }
+            ClientMessage::TurnCredentials {} => {
+                participant.send(self.turn_message(user_id));
+            }
+            ClientMessage::Identify { target } => {
+                let forwarded = ServerMessage::Identify {
+                    user_id: user_id.to_string(),
+                    data: self.presence(user_id),
+                };
+                if !self.send_to(&target, forwarded) {
+                    participant.send(error_message(ErrorCode::UnknownTarget, "No such participant"));
+                }
+            }
+            ClientMessage::Signal { target, data } => {
+                let forwarded = ServerMessage::Signal {
+                    user_id: user_id.to_string(),
+                    data,
+                };
+                if !self.send_to(&target, forwarded) {
+                    participant.send(error_message(ErrorCode::UnknownTarget, "No such participant"));
+                }
+            }
+            ClientMessage::Chat { target, data } => {
+                if target.as_ref().is_some_and(|t| !self.users.contains_key(t)) {
+                    participant.send(error_message(ErrorCode::UnknownTarget, "No such participant"));
+                } else {
+                    self.chat(user_id, participant, target, data.text);
+                }
+            }
+            ClientMessage::Kick { .. }
+            | ClientMessage::LockRoom { .. }
+            | ClientMessage::Admit { .. }
+            | ClientMessage::Deny { .. }
+            | ClientMessage::SetKnockMode { .. }
+            | ClientMessage::SetCapacity { .. }
+            | ClientMessage::EndMeeting {}
+                if self.host != user_id =>
+            {
+                participant.send(error_message(ErrorCode::NotHost, "Only the host can do that"));
+            }
+            ClientMessage::Kick { target } => {
+                if target == user_id || !self.users.contains_key(&target) {
+                    participant.send(error_message(ErrorCode::UnknownTarget, "No such participant"));
+                } else {
+                    self.kick(&target);
+                }
+            }
+            ClientMessage::LockRoom { data } => {
+                self.locked = data.locked;
+                let notify_msg = ServerMessage::RoomLocked {
+                    user_id: user_id.to_string(),
+                    data: LockRoom { locked: self.locked },
+                };
+                self.broadcast(notify_msg, None);
+            }
+            ClientMessage::Admit { target } | ClientMessage::Deny { target } if !self.pending.contains_key(&target) => {
+                participant.send(error_message(ErrorCode::UnknownTarget, "Nobody by that id is waiting"));
+            }
+            ClientMessage::Deny { target } => {
+                self.deny(&target);
+            }
+            ClientMessage::Admit { target } => {
+                if self.is_full() {
+                    participant.send(JoinRefusal::Full(self.capacity).message());
+                } else {
+                    self.admit(&target);
+                }
+            }
+            ClientMessage::SetKnockMode { data } => {
+                self.set_knock(data.enabled);
+                let notify_msg = ServerMessage::KnockMode {
+                    user_id: user_id.to_string(),
+                    data: SetKnockMode { enabled: data.enabled },
+                };
+                self.broadcast(notify_msg, None);
+            }
+            ClientMessage::SetCapacity { data } => {
+                self.capacity = room_capacity(data.max_participants, self.config.max_participants);
+                let notify_msg = ServerMessage::Capacity {
+                    user_id: user_id.to_string(),
+                    data: Capacity {
+                        max_participants: self.capacity,
+                    },
+                };
+                self.broadcast(notify_msg, None);
+            }
+            ClientMessage::EndMeeting {} => {
+                self.end();
+            }
+        }
+    }
+
+    /// Whether `participant` is the connection currently joined as `user_id`.
+    fn is_member(&self, user_id: &str, participant: &Participant) -> bool {
+        self.users
+            .get(user_id)
+            .is_some_and(|m| m.participant.is(participant))
+    }
+
+    /// Whether `participant` is waiting for admission as `user_id`.
+    fn is_waiting(&self, user_id: &str, participant: &Participant) -> bool {
+        self.pending
+            .get(user_id)
+            .is_some_and(|m| m.participant.is(participant))
+    }
+
+    fn contains(&self, user_id: &str) -> bool {
+        self.users.contains_key(user_id) || self.pending.contains_key(user_id)
+    }
+
+    /// Hands `user_id`'s place to a new connection if it presents the
+    /// resume token of the current one, which is then closed. The others in
+    /// the room are not told.
+    fn resume(&mut self, user_id: &str, token: Option<&str>, participant: &Participant) -> Result<(), JoinRefusal> {
+        let Some(Member { participant: current, .. }) = self.users.get_mut(user_id) else {
+            return Err(JoinRefusal::DuplicateId);
+        };
+        if !token.is_some_and(|t| admin::constant_time_eq(t.as_bytes(), current.resume_token.as_bytes())) {
+            return Err(JoinRefusal::DuplicateId);
+        }
+        let replacement = Participant {
+            joined_at: current.joined_at,
+            ..participant.clone()
+        };
+        let previous = std::mem::replace(current, replacement);
+        previous.disconnect(close_code::NORMAL, "resumed elsewhere");
+        Ok(())
+    }
+
+    /// TURN credentials for a participant. Guests waiting to be let in get
+    /// none, so only someone who is in a room can relay through the server.
+    fn turn_message(&self, user_id: &str) -> ServerMessage {
+        ServerMessage::TurnCredentials {
+            data: self.config.turn.issue_credentials(user_id),
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.capacity != 0 && self.users.len() >= self.capacity
+    }
+
+    /// Adds a participant to the room, or to the waiting list in knock mode.
+    /// Returns true if they are in the room.
+    fn seat(&mut self, user_id: &str, participant: Participant, presence: Presence) -> bool {
+        let member = Member { participant, presence };
+        if !self.knock {
+            self.users.insert(user_id.to_string(), member);
+            return true;
+        }
+
+        member.participant.send(ServerMessage::Waiting {});
+        self.notify_host(knock_message(user_id, &member));
+        self.pending.insert(user_id.to_string(), member);
+        false
+    }
+
+    /// Tells the room about a new participant, and the new participant who
+    /// the host is and who else is there.
+    fn announce_join(&self, user_id: &str) {
+        let Some(member) = self.users.get(user_id) else {
+            return;
+        };
+        self.metrics.joined();
+        let joined = ServerMessage::UserJoined {
+            user_id: user_id.to_string(),
+            data: member.presence.clone(),
+        };
+        self.broadcast(joined, Some(user_id));
+        member.participant.send(self.turn_message(user_id));
+        member.participant.send(self.host_message());
+        member.participant.send(self.state_message());
+        if let Some(history) = self.history_message() {
+            member.participant.send(history);
+        }
+        for msg in self.file_messages() {
+            member.participant.send(msg);
+        }
+    }
+
+    /// Stamps a chat message and delivers it to everyone, or only to its
+    /// target and back to the sender. Only messages to everyone are kept
+    /// for later joiners.
+    fn chat(&mut self, user_id: &str, participant: &Participant, target: Option<String>, text: String) {
+        self.last_chat_id += 1;
+        let message = ChatMessage {
+            id: self.last_chat_id,
+            text,
+            sent_at: now_millis(),
+            target,
+        };
+        let msg = ServerMessage::Chat {
+            user_id: user_id.to_string(),
+            data: message.clone(),
+        };
+
+        match &message.target {
+            Some(target) => {
+                self.send_to(target, msg.clone());
+                if target != user_id {
+                    participant.send(msg);
+                }
+            }
+            None => {
+                self.broadcast(msg, None);
+                if self.config.chat_history > 0 {
+                    if self.chat.len() >= self.config.chat_history {
+                        self.chat.pop_front();
+                    }
+                    self.chat.push_back(ChatEntry {
+                        user_id: user_id.to_string(),
+                        message,
+                    });
+                }
+            }
+        }
+    }
+
+    /// The kept chat messages, if there are any.
+    fn history_message(&self) -> Option<ServerMessage> {
+        if self.chat.is_empty() {
+            return None;
+        }
+        Some(ServerMessage::ChatHistory {
+            data: ChatHistory {
+                messages: self.chat.iter().cloned().collect(),
+            },
+        })
+    }
+
+    /// The participant in the room whose resume token is `token`. Guests
+    /// still waiting for the host have none.
+    fn holder(&self, token: &str) -> Option<&str> {
+        self.users
+            .iter()
+            .find(|(_, m)| admin::constant_time_eq(token.as_bytes(), m.participant.resume_token.as_bytes()))
+            .map(|(user_id, _)| user_id.as_str())
+    }
+
+    /// Bytes taken by shared files and set aside for uploads in progress.
+    fn quota_used(&self) -> u64 {
+        let stored: u64 = self.files.iter().map(|f| f.file.size).sum();
+        let reserved: u64 = self.uploads.values().map(|r| r.size).sum();
+        stored.saturating_add(reserved)
+    }
+
+    fn reserve_upload(&mut self, token: &str, id: String, size: u64) -> Result<(), FileRefusal> {
+        let user_id = self.holder(token).ok_or(FileRefusal::NotParticipant)?;
+        if self.uploads.values().filter(|r| r.user_id == user_id).count() >= MAX_UPLOADS {
+            return Err(FileRefusal::TooManyUploads);
+        }
+        if self.quota_used().saturating_add(size) > self.config.files.room_quota {
+            return Err(FileRefusal::QuotaExceeded);
+        }
+        let user_id = user_id.to_string();
+        self.uploads.insert(id, Reservation { user_id, size });
+        Ok(())
+    }
+
+    /// Shares an uploaded file with everyone in the room in place of its
+    /// reservation, and schedules its removal.
+    fn add_file(&mut self, token: &str, upload: Upload) -> Result<SharedFile, FileRefusal> {
+        // The upload is over either way.
+        let reserved = self.uploads.remove(&upload.id);
+        let user_id = self.holder(token).ok_or(FileRefusal::NotParticipant)?.to_string();
+        if reserved.is_none_or(|r| r.user_id != user_id || upload.size > r.size) {
+            return Err(FileRefusal::QuotaExceeded);
+        }
+        let shared_at = now_millis();
+        let ttl = self.config.files.ttl;
+        let file = SharedFile {
+            id: upload.id,
+            name: upload.name,
+            size: upload.size,
+            shared_at,
+            expires_at: ttl.map(|ttl| shared_at + ttl.as_millis() as u64),
+        };
+        if let Some(ttl) = ttl {
+            self.schedule(ttl, Command::ExpireFile { id: file.id.clone() });
+        }
+        let shared = ServerMessage::FileShared {
+            user_id: user_id.clone(),
+            data: file.clone(),
+        };
+        self.broadcast(shared, None);
+        self.files.push(StoredFile {
+            user_id,
+            file: file.clone(),
+        });
+        Ok(file)
+    }
+
+    fn expire_file(&mut self, id: &str) {
+        let Some(pos) = self.files.iter().position(|f| f.file.id == id) else {
+            return;
+        };
+        let stored = self.files.remove(pos);
+        let config = self.config.clone();
+        tokio::spawn(async move { files::remove(&config.files, &stored.file.id).await });
+        let removed = ServerMessage::FileRemoved {
+            data: FileRemoved { id: id.to_string() },
+        };
+        self.broadcast(removed, None);
+    }
+
+    /// Announcements of the files that are still available.
+    fn file_messages(&self) -> impl Iterator<Item = ServerMessage> + '_ {
+        self.files.iter().map(|f| ServerMessage::FileShared {
+            user_id: f.user_id.clone(),
+            data: f.file.clone(),
+        })
+    }
+
+    /// Sends `cmd` to the room itself once `delay` has passed.
+    fn schedule(&self, delay: Duration, cmd: Command) {
+        let handle = self.handle.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            let _ = handle.commands.send(cmd).await;
+        });
+    }
+
+    /// Everyone in the room and their current state.
+    fn state_message(&self) -> ServerMessage {
+        let mut members: Vec<(&String, &Member)> = self.users.iter().collect();
+        members.sort_by_key(|(_, m)| m.participant.joined_at);
+        let participants = members
+            .into_iter()
+            .map(|(user_id, m)| RosterEntry {
+                user_id: user_id.clone(),
+                presence: m.presence.clone(),
+            })
+            .collect();
+        ServerMessage::RoomState {
+            data: RoomState { participants },
+        }
+    }
+
+    fn presence(&self, user_id: &str) -> Presence {
+        self.users
+            .get(user_id)
+            .map(|m| m.presence.clone())
+            .unwrap_or_default()
+    }
+
+    /// Records a participant's new state and tells the others what changed.
+    fn update_presence(&mut self, user_id: &str, presence: Presence) {
+        let Some(member) = self.users.get_mut(user_id) else {
+            return;
+        };
+        let previous = std::mem::replace(&mut member.presence, presence.clone());
+        let user_id = user_id.to_string();
+        if previous.profile != presence.profile {
+            let notify_msg = ServerMessage::UserUpdate {
+                user_id: user_id.clone(),
+                data: presence.profile,
+            };
+            self.broadcast(notify_msg, Some(&user_id));
+        }
+        if previous.cam_enabled != presence.cam_enabled {
+            let notify_msg = ServerMessage::CamToggle {
+                user_id: user_id.clone(),
+                data: CamToggle {
+                    enabled: presence.cam_enabled,
+                },
+            };
+            self.broadcast(notify_msg, Some(&user_id));
+        }
+        if previous.screen_enabled != presence.screen_enabled || previous.screen_audio != presence.screen_audio {
+            let notify_msg = ServerMessage::ScreenToggle {
+                user_id: user_id.clone(),
+                data: ScreenToggle {
+                    enabled: presence.screen_enabled,
+                    has_audio: presence.screen_audio,
+                },
+            };
+            self.broadcast(notify_msg, Some(&user_id));
+        }
+    }
+
+    /// Names the host, along with the settings they control.
+    fn host_message(&self) -> ServerMessage {
+        ServerMessage::HostChanged {
+            user_id: self.host.clone(),
+            data: RoomSettings {
+                locked: self.locked,
+                knock_mode: self.knock,
+                max_participants: self.capacity,
+            },
+        }
+    }
+
+    fn notify_host(&self, msg: ServerMessage) {
+        if let Some(host) = self.users.get(&self.host) {
+            host.participant.send(msg);
+        }
+    }
+
+    /// Moves a waiting guest into the room. The caller makes sure there is
+    /// space for them.
+    fn admit(&mut self, user_id: &str) -> bool {
+        let Some(mut member) = self.pending.remove(user_id) else {
+            return false;
+        };
+        member.participant.joined_at = Instant::now();
+        member.participant.send(ServerMessage::Admitted {});
+        self.users.insert(user_id.to_string(), member);
+        self.announce_join(user_id);
+        true
+    }
+
+    fn deny(&mut self, user_id: &str) -> bool {
+        let Some(knock) = self.pending.remove(user_id) else {
+            return false;
+        };
+        knock.participant.send(ServerMessage::Denied {});
+        knock.participant.disconnect(close_code::NORMAL, "denied");
+        true
+    }
+
+    /// Turning knock mode off lets the waiting guests in, in the order they
+    /// arrived, as long as there is space. The rest are turned away.
+    fn set_knock(&mut self, enabled: bool) {
+        self.knock = enabled;
+        if !enabled {
+            let mut waiting: Vec<(String, Instant)> = self
+                .pending
+                .iter()
+                .map(|(user_id, knock)| (user_id.clone(), knock.participant.joined_at))
+                .collect();
+            waiting.sort_by_key(|(_, knocked_at)| *knocked_at);
+            for (user_id, _) in waiting {
+                if !self.is_full() {
+                    self.admit(&user_id);
+                } else if let Some(knock) = self.pending.remove(&user_id) {
+                    knock.participant.send(JoinRefusal::Full(self.capacity).message());
+                    knock.participant.disconnect(close_code::NORMAL, "room full");
+                    self.notify_host(ServerMessage::KnockCancelled { user_id });
+                }
+            }
+        }
+    }
+
+    /// Drops a guest who gave up waiting.
+    fn withdraw(&mut self, user_id: &str) {
+        if self.pending.remove(user_id).is_some() {
+            self.notify_host(ServerMessage::KnockCancelled {
+                user_id: user_id.to_string(),
+            });
+        }
+    }
+
+    fn broadcast(&self, msg: ServerMessage, except: Option<&str>) {
+        let priority = msg.priority();
+        let frame = Message::from(msg);
+        for (uid, m) in self.users.iter() {
+            if Some(uid.as_str()) != except {
+                m.participant.outbox.send_frame(frame.clone(), priority.clone());
+            }
+        }
+    }
+
+    /// Sends `msg` to one participant. Returns false if they are not in
+    /// the room.
+    fn send_to(&self, user_id: &str, msg: ServerMessage) -> bool {
+        let Some(m) = self.users.get(user_id) else {
+            return false;
+        };
+        m.participant.send(msg);
+        true
+    }
+
+    /// Removes a participant and tells the others. If they were the host,
+    /// the role passes to whoever has been in the room longest.
+    fn leave(&mut self, user_id: &str) -> Option<Participant> {
+        let Member { participant, .. } = self.users.remove(user_id)?;
+        self.metrics.left(1);
+        let left = ServerMessage::UserLeft {
+            user_id: user_id.to_string(),
+        };
+        self.broadcast(left, None);
+
+        if self.host == user_id
+            && let Some((next, _)) = self.users.iter().min_by_key(|(_, m)| m.participant.joined_at)
+        {
+            self.host = next.clone();
+            self.broadcast(self.host_message(), None);
+            for (guest, knock) in self.pending.iter() {
+                self.notify_host(knock_message(guest, knock));
+            }
+        }
+        if self.users.is_empty() {
+            // Nobody is left to let waiting guests in.
+            for user_id in self.pending.keys().cloned().collect::<Vec<_>>() {
+                self.deny(&user_id);
+            }
+        }
+        Some(participant)
+    }
+
+    fn kick(&mut self, user_id: &str) {
+        if let Some(participant) = self.leave(user_id) {
+            participant.send(ServerMessage::Kicked {});
+            participant.disconnect(close_code::NORMAL, "kicked");
+        }
+    }
+
+    /// Disconnects everyone, which closes the room.
+    fn end(&mut self) {
+        let ended = ServerMessage::MeetingEnded {
+            user_id: self.host.clone(),
+        };
+        self.broadcast(ended, None);
+        for member in self.users.values() {
+            member.participant.disconnect(close_code::NORMAL, "meeting ended");
+        }
+        self.metrics.left(self.users.len());
+        self.users.clear();
+        for user_id in self.pending.keys().cloned().collect::<Vec<_>>() {
+            self.deny(&user_id);
+        }
+    }
+}
+
+/// Asks the host to admit a guest, showing who they are.
+fn knock_message(user_id: &str, guest: &Member) -> ServerMessage {
+    ServerMessage::Knock {
+        user_id: user_id.to_string(),
+        data: guest.presence.profile.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::outbox::{self, SendStats};
+    use crate::protocol::Profile;
+    use futures::channel::mpsc::UnboundedReceiver;
+    use futures::StreamExt;
+    use serde_json::Value;
+
+    /// One client's connection, with what the room sent it.
+    struct Client {
+        participant: Participant,
+        frames: UnboundedReceiver<Message>,
+    }
+
+    impl Client {
+        fn new() -> Client {
+            let (outbox, queues) = Outbox::new(64, Duration::from_secs(60), Arc::new(SendStats::default()));
+            let (sink, frames) = futures::channel::mpsc::unbounded();
+            tokio::spawn(outbox::write(sink, queues, Arc::new(Metrics::default())));
+            Client {
+                participant: Participant::new(Arc::new(outbox)),
+                frames,
+            }
+        }
+
+        /// Everything sent since the last call, as JSON, with a close frame
+        /// as `{"type": "close"}`.
+        async fn received(&mut self) -> Vec<Value> {
+            let mut received = Vec::new();
+            while let Ok(Some(frame)) = tokio::time::timeout(Duration::from_millis(50), self.frames.next()).await {
+                match frame {
+                    Message::Text(text) => received.push(serde_json::from_str(&text).unwrap()),
+                    Message::Close(_) => received.push(serde_json::json!({ "type": "close" })),
+                    _ => {}
+                }
+            }
+            received
+        }
+
+        /// The `type`s of everything sent since the last call.
+        async fn types(&mut self) -> Vec<String> {
+            self.received().await.iter().map(|m| m["type"].as_str().unwrap().to_string()).collect()
+        }
+    }
+
+    fn rooms(configure: impl FnOnce(&mut Config)) -> RoomMap {
+        let mut config = Config::defaults();
+        configure(&mut config);
+        RoomMap::new(Arc::new(config), Arc::new(Metrics::default()))
+    }
+
+    fn request(user_id: &str) -> JoinRequest {
+        JoinRequest {
+            user_id: user_id.to_string(),
+            password: None,
+            resume_token: None,
+            presence: Presence {
+                profile: Profile {
+                    nickname: Some(user_id.to_string()),
+                    avatar: None,
+                },
+                ..Presence::default()
+            },
+        }
+    }
+
+    async fn join(room: &RoomHandle, request: JoinRequest, client: &Client) -> JoinReply {
+        room.join(request, client.participant.clone(), None).await.expect("room is open")
+    }
+
+    async fn send(room: &RoomHandle, user_id: &str, client: &Client, msg: ClientMessage) {
+        assert!(room.send(user_id, &client.participant, msg).await);
+    }
+
+    fn host_changes(received: &[Value]) -> Vec<&str> {
+        received
+            .iter()
+            .filter(|m| m["type"] == "host-changed")
+            .map(|m| m["userId"].as_str().unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn host_passes_to_whoever_joined_first() {
+        let rooms = rooms(|_| {});
+        let mut clients: Vec<Client> = (0..4).map(|_| Client::new()).collect();
+        let room = rooms.create("r", request("a"), clients[0].participant.clone(), None).unwrap();
+        for (user_id, client) in ["b", "c", "d"].into_iter().zip(&clients[1..]) {
+            // Joins a moment apart, so the order is down to joined_at.
+            tokio::time::sleep(Duration::from_millis(2)).await;
+            assert!(matches!(join(&room, request(user_id), client).await, JoinReply::Admitted));
+        }
+
+        // c drops and comes back, keeping its place in line.
+        room.leave("c".into(), clients[2].participant.clone(), true).await;
+        let resumed = Client::new();
+        let token = clients[2].participant.resume_token.clone();
+        let again = JoinRequest {
+            resume_token: Some(token),
+            ..request("c")
+        };
+        assert!(matches!(join(&room, again, &resumed).await, JoinReply::Admitted));
+        clients[2] = resumed;
+
+        clients[3].received().await;
+        room.leave("a".into(), clients[0].participant.clone(), false).await;
+        assert_eq!(host_changes(&clients[3].received().await), ["b"]);
+        room.leave("b".into(), clients[1].participant.clone(), false).await;
+        assert_eq!(host_changes(&clients[3].received().await), ["c"]);
+    }
+
+    #[tokio::test]
+    async fn locked_room_turns_joins_away() {
+        let rooms = rooms(|_| {});
+        let host = Client::new();
+        let room = rooms.create("r", request("a"), host.participant.clone(), None).unwrap();
+        send(&room, "a", &host, ClientMessage::LockRoom { data: LockRoom { locked: true } }).await;
+        assert!(matches!(join(&room, request("b"), &Client::new()).await, JoinReply::Refused(JoinRefusal::Locked)));
+
+        send(&room, "a", &host, ClientMessage::LockRoom { data: LockRoom { locked: false } }).await;
+        assert!(matches!(join(&room, request("b"), &Client::new()).await, JoinReply::Admitted));
+    }
+
+    #[tokio::test]
+    async fn full_room_turns_joins_away() {
+        let rooms = rooms(|config| config.max_participants = 2);
+        let host = Client::new();
+        let room = rooms.create("r", request("a"), host.participant.clone(), None).unwrap();
+        assert!(matches!(join(&room, request("b"), &Client::new()).await, JoinReply::Admitted));
+        assert!(matches!(join(&room, request("c"), &Client::new()).await, JoinReply::Refused(JoinRefusal::Full(2))));
+
+        // A host cannot raise the capacity past the server's limit.
+        send(&room, "a", &host, ClientMessage::SetCapacity { data: Capacity { max_participants: 5 } }).await;
+        assert!(matches!(join(&room, request("c"), &Client::new()).await, JoinReply::Refused(JoinRefusal::Full(2))));
+    }
+
+    #[tokio::test]
+    async fn knocking_guests_are_admitted_or_denied() {
+        let rooms = rooms(|_| {});
+        let mut host = Client::new();
+        let room = rooms.create("r", request("a"), host.participant.clone(), None).unwrap();
+        send(&room, "a", &host, ClientMessage::SetKnockMode { data: SetKnockMode { enabled: true } }).await;
+
+        let mut guest = Client::new();
+        assert!(matches!(join(&room, request("b"), &guest).await, JoinReply::Admitted));
+        assert!(guest.types().await.contains(&"waiting".to_string()));
+        let knocks: Vec<Value> = host.received().await.into_iter().filter(|m| m["type"] == "knock").collect();
+        assert_eq!(knocks.len(), 1);
+        assert_eq!(knocks[0]["userId"], "b");
+
+        // Waiting guests cannot talk to the room yet.
+        send(&room, "b", &guest, ClientMessage::Chat {
+            target: None,
+            data: crate::protocol::ChatText { text: "hi".into() },
+        })
+        .await;
+        let refused = guest.received().await;
+        assert_eq!(refused[0]["data"]["code"], "not-joined");
+
+        send(&room, "a", &host, ClientMessage::Admit { target: "b".into() }).await;
+        let types = guest.types().await;
+        assert!(types.contains(&"admitted".to_string()) && types.contains(&"room-state".to_string()), "{types:?}");
+        assert!(host.types().await.contains(&"user-joined".to_string()));
+
+        let mut other = Client::new();
+        assert!(matches!(join(&room, request("c"), &other).await, JoinReply::Admitted));
+        send(&room, "a", &host, ClientMessage::Deny { target: "c".into() }).await;
+        let types = other.types().await;
+        assert!(types.ends_with(&["denied".to_string(), "close".to_string()]), "{types:?}");
+        assert!(other.participant.closed.is_cancelled());
+    }
+
+    #[tokio::test]
+    async fn dropped_participant_can_resume_within_the_grace_period() {
+        let grace = Duration::from_millis(200);
+        let rooms = rooms(|config| config.resume_grace = grace);
+        let mut host = Client::new();
+        let room = rooms.create("r", request("a"), host.participant.clone(), None).unwrap();
+        let first = Client::new();
+        assert!(matches!(join(&room, request("b"), &first).await, JoinReply::Admitted));
+        let token = first.participant.resume_token.clone();
+        host.received().await;
+
+        room.leave("b".into(), first.participant.clone(), true).await;
+        let mut second = Client::new();
+        let resume = JoinRequest {
+            resume_token: Some(token.clone()),
+            ..request("b")
+        };
+        assert!(matches!(join(&room, resume.clone(), &second).await, JoinReply::Admitted));
+        let welcome = second.received().await.remove(0);
+        assert_eq!(welcome["type"], "welcome");
+        assert_eq!(welcome["data"]["resumed"], true);
+
+        // The first connection's grace period running out does not remove
+        // the connection that took its place.
+        tokio::time::sleep(grace * 2).await;
+        assert!(!host.types().await.contains(&"user-left".to_string()));
+
+        // Without a resume, the place is given up once the grace is over.
+        room.leave("b".into(), second.participant.clone(), true).await;
+        tokio::time::sleep(grace * 2).await;
+        assert!(host.types().await.contains(&"user-left".to_string()));
+        let mut third = Client::new();
+        let resume = JoinRequest {
+            resume_token: Some(second.participant.resume_token.clone()),
+            ..request("b")
+        };
+        assert!(matches!(join(&room, resume, &third).await, JoinReply::Admitted));
+        let welcome = third.received().await.remove(0);
+        assert_eq!(welcome["data"]["resumed"], false);
+    }
+
+    #[tokio::test]
+    async fn wrong_resume_token_is_refused() {
+        let rooms = rooms(|_| {});
+        let host = Client::new();
+        let room = rooms.create("r", request("a"), host.participant.clone(), None).unwrap();
+        let resume = JoinRequest {
+            resume_token: Some("guess".into()),
+            ..request("a")
+        };
+        assert!(matches!(join(&room, resume, &Client::new()).await, JoinReply::Refused(JoinRefusal::DuplicateId)));
+    }
+
+    #[tokio::test]
+    async fn ending_the_meeting_denies_waiting_guests() {
+        let rooms = rooms(|_| {});
+        let mut host = Client::new();
+        let room = rooms.create("r", request("a"), host.participant.clone(), None).unwrap();
+        let mut member = Client::new();
+        assert!(matches!(join(&room, request("b"), &member).await, JoinReply::Admitted));
+        send(&room, "a", &host, ClientMessage::SetKnockMode { data: SetKnockMode { enabled: true } }).await;
+        let mut guest = Client::new();
+        assert!(matches!(join(&room, request("c"), &guest).await, JoinReply::Admitted));
+
+        // Only the host can end it.
+        send(&room, "b", &member, ClientMessage::EndMeeting {}).await;
+        assert!(member.received().await.iter().any(|m| m["data"]["code"] == "not-host"));
+
+        send(&room, "a", &host, ClientMessage::EndMeeting {}).await;
+        for client in [&mut host, &mut member] {
+            let types = client.types().await;
+            assert!(types.ends_with(&["meeting-ended".to_string(), "close".to_string()]), "{types:?}");
+        }
+        let types = guest.types().await;
+        assert!(types.ends_with(&["denied".to_string(), "close".to_string()]), "{types:?}");
+        assert!(rooms.get("r").is_none());
+    }
+}
@@ -0,0 +1,127 @@
+use anyhow::{Context, Result};
+use rustls::ServerConfig;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio_rustls::TlsAcceptor;
+
+/// How often certificate files are checked for changes.
+const RELOAD_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Builds a rustls server config from a PEM certificate chain and private key.
+pub fn load_server_config(cert_path: &Path, key_path: &Path) -> Result<Arc<ServerConfig>> {
+    let mut cert_reader = BufReader::new(
+        File::open(cert_path).with_context(|| format!("failed to open certificate {}", cert_path.display()))?,
+    );
+    let certs = rustls_pemfile::certs(&mut cert_reader)
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("failed to parse certificate {}", cert_path.display()))?;
+    if certs.is_empty() {
+        anyhow::bail!("no certificates found in {}", cert_path.display());
+    }
+
+    let mut key_reader = BufReader::new(
+        File::open(key_path).with_context(|| format!("failed to open private key {}", key_path.display()))?,
+    );
+    let key = rustls_pemfile::private_key(&mut key_reader)
+        .with_context(|| format!("failed to parse private key {}", key_path.display()))?
+        .with_context(|| format!("no private key found in {}", key_path.display()))?;
+
+    let config = ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("certificate and private key do not match")?;
+
+    Ok(Arc::new(config))
+}
+
+/// A TLS server config that is rebuilt whenever its certificate or key
+/// changes on disk, or on SIGHUP. Every connection keeps the config it was
+/// accepted with, so reloading does not affect established sessions.
+pub struct ReloadableConfig {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<Arc<ServerConfig>>,
+    modified: Mutex<Option<(SystemTime, SystemTime)>>,
+}
+
+impl ReloadableConfig {
+    pub fn load(cert_path: PathBuf, key_path: PathBuf) -> Result<Arc<ReloadableConfig>> {
+        let modified = modified_times(&cert_path, &key_path);
+        let config = load_server_config(&cert_path, &key_path)?;
+        Ok(Arc::new(ReloadableConfig {
+            cert_path,
+            key_path,
+            current: RwLock::new(config),
+            modified: Mutex::new(modified),
+        }))
+    }
+
+    /// Acceptor for a new connection, using the most recently loaded config.
+    pub fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(self.current.read().unwrap().clone())
+    }
+
+    /// Loads the files again. On error the previous config stays in use.
+    pub fn reload(&self) -> Result<()> {
+        let modified = modified_times(&self.cert_path, &self.key_path);
+        let config = load_server_config(&self.cert_path, &self.key_path)?;
+        *self.current.write().unwrap() = config;
+        *self.modified.lock().unwrap() = modified;
+        Ok(())
+    }
+
+    fn changed(&self) -> bool {
+        let modified = modified_times(&self.cert_path, &self.key_path);
+        modified.is_some() && modified != *self.modified.lock().unwrap()
+    }
+
+    /// Reloads the config in the background when the files change or the
+    /// process receives SIGHUP.
+    pub fn watch(self: Arc<Self>) {
+        tokio::spawn(async move {
+            #[cfg(unix)]
+            let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+                .map_err(|e| eprintln!("failed to listen for SIGHUP: {}", e))
+                .ok();
+            let mut poll = tokio::time::interval(RELOAD_POLL_INTERVAL);
+            poll.tick().await;
+
+            loop {
+                #[cfg(unix)]
+                let signalled = async {
+                    match hangup.as_mut() {
+                        Some(sig) => sig.recv().await,
+                        None => std::future::pending().await,
+                    }
+                };
+                #[cfg(not(unix))]
+                let signalled = std::future::pending::<Option<()>>();
+
+                tokio::select! {
+                    _ = signalled => {}
+                    _ = poll.tick() => {
+                        if !self.changed() {
+                            continue;
+                        }
+                    }
+                }
+
+                match self.reload() {
+                    Ok(()) => println!("RELOADED TLS CERTIFICATE {}", self.cert_path.display()),
+                    Err(e) => eprintln!("failed to reload TLS certificate: {:#}", e),
+                }
+            }
+        });
+    }
+}
+
+/// Modification times of the certificate and key, following symlinks so
+/// that renewals which swap a link target are noticed.
+fn modified_times(cert_path: &Path, key_path: &Path) -> Option<(SystemTime, SystemTime)> {
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    Some((modified(cert_path)?, modified(key_path)?))
+}
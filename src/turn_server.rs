@@ -1,69 +1,285 @@
-use anyhow::Result;
-use std::net::SocketAddr;
+mod auth;
+mod guard;
+mod quota;
+mod registry;
+mod stream;
+
+pub use auth::TurnCredentials;
+pub use guard::{PeerPolicy, default_denied_peers};
+pub use quota::Quotas;
+pub use registry::{AllocationEvent, AllocationInfo, AllocationTotals};
+pub use stream::StreamLimits;
+
+use anyhow::{Context, Result};
+use auth::EphemeralAuthHandler;
+use guard::GuardedConn;
+use quota::{LimitedRelayGenerator, OwnerSlot, QuotaTracker};
+use registry::AllocationRegistry;
+use serde::Serialize;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::net::UdpSocket;
-use turn::auth::{AuthHandler, generate_auth_key};
+use std::time::Duration;
+use stream::{ConnectionCounter, StreamConn};
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::{broadcast, mpsc};
+use tokio_util::sync::CancellationToken;
 use turn::server::config::{ConnConfig, ServerConfig};
 use turn::server::Server;
-use turn::Error;
-use turn::relay::relay_static::RelayAddressGeneratorStatic;
+use turn::relay::relay_range::RelayAddressGeneratorRanges;
+use webrtc_util::Conn;
 use webrtc_util::vnet::net::Net;
 
-struct SimpleAuthHandler {
-    user: String,
-    key: Vec<u8>,
+use crate::tls::ReloadableConfig;
+
+#[derive(Debug, Clone)]
+pub struct TurnConfig {
+    pub bind_addr: SocketAddr,
+    pub realm: String,
+    /// Shared secret used to sign and verify ephemeral credentials.
+    pub secret: String,
+    /// How long issued credentials stay valid.
+    pub credential_ttl: Duration,
+    /// Address handed out to clients as the relay address. When unset it is
+    /// detected from the interface the server is bound to.
+    pub external_ip: Option<IpAddr>,
+    pub relay_min_port: u16,
+    pub relay_max_port: u16,
+    /// Also accept TURN over TCP on the same port number as UDP.
+    pub tcp: bool,
+    pub tls: Option<TurnTlsConfig>,
+    /// Which peer addresses allocations may relay to.
+    pub peer_policy: PeerPolicy,
+    pub quotas: Quotas,
+    pub stream_limits: StreamLimits,
 }
 
-impl AuthHandler for SimpleAuthHandler {
-    fn auth_handle(
-        &self,
-        username: &str,
-        _realm: &str,
-        _src_addr: SocketAddr,
-    ) -> Result<Vec<u8>, Error> {
-        if username == self.user {
-            Ok(self.key.clone())
-        } else {
-            Err(Error::Other("Invalid user".into()))
-        }
+/// TURN over TLS (`turns:`) listener settings.
+#[derive(Debug, Clone)]
+pub struct TurnTlsConfig {
+    pub port: u16,
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+/// Picks the address of the interface used for outgoing traffic. Connecting a
+/// UDP socket sends nothing, it only asks the kernel for a route. A host
+/// without one has no address to tell clients about, so the operator has to
+/// name it.
+async fn detect_relay_ip(bind_ip: IpAddr) -> Result<IpAddr> {
+    if !bind_ip.is_unspecified() {
+        return Ok(bind_ip);
     }
+
+    let (local, probe) = if bind_ip.is_ipv4() {
+        ("0.0.0.0:0", "8.8.8.8:80")
+    } else {
+        ("[::]:0", "[2001:4860:4860::8888]:80")
+    };
+    let routed = async {
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(probe).await?;
+        socket.local_addr()
+    };
+    let addr = routed.await.context(
+        "could not detect the TURN relay address (no route to the internet); \
+         set --turn-external-ip to the address clients should relay through",
+    )?;
+    Ok(addr.ip())
 }
 
-pub async fn start(port: u16, user: String, pass: String, realm: String) -> Result<()> {
-    let public_ip = "0.0.0.0";
+impl TurnConfig {
+    /// Issues a fresh set of credentials to the participant `user_id`, whose
+    /// id becomes part of the username.
+    pub fn issue_credentials(&self, user_id: &str) -> TurnCredentials {
+        TurnCredentials::issue(&self.secret, self.credential_ttl, user_id)
+    }
+}
 
-    let key = generate_auth_key(&user, &realm, &pass);
+/// Transport of a TURN listener.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    Udp,
+    Tcp,
+    Tls,
+}
 
-    let auth_handler = Arc::new(SimpleAuthHandler { 
-        user: user.clone(), 
-        key 
-    });
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Transport::Udp => "udp",
+            Transport::Tcp => "tcp",
+            Transport::Tls => "tls",
+        })
+    }
+}
+
+/// Point-in-time counters of a running TURN server.
+#[derive(Debug, Clone, Serialize)]
+pub struct TurnStats {
+    pub allocations: usize,
+    pub users: usize,
+    pub client_ips: usize,
+}
+
+/// A running TURN server. Dropping the handle does not stop the server,
+/// call [`TurnHandle::shutdown`] for that.
+pub struct TurnHandle {
+    server: Server,
+    listeners: Vec<(Transport, SocketAddr)>,
+    relay_ip: IpAddr,
+    quotas: Arc<QuotaTracker>,
+    registry: Arc<AllocationRegistry>,
+    shutdown: CancellationToken,
+}
 
-    let bind_addr = format!("0.0.0.0:{}", port);
-    let conn = UdpSocket::bind(&bind_addr).await?;
+impl TurnHandle {
+    /// Addresses the server is listening on, one entry per transport.
+    pub fn local_addrs(&self) -> &[(Transport, SocketAddr)] {
+        &self.listeners
+    }
+
+    /// Address handed out to clients in relayed transport addresses.
+    pub fn relay_ip(&self) -> IpAddr {
+        self.relay_ip
+    }
 
-    let relay_addr_gen = RelayAddressGeneratorStatic {
-        relay_address: public_ip.parse()?,
-        address: "0.0.0.0".to_owned(),
-        net: Arc::new(Net::new(None)),
+    pub async fn stats(&self) -> Result<TurnStats> {
+        let allocations = self.server.get_allocations_info(None).await?.len();
+        let (users, client_ips) = self.quotas.holders();
+        Ok(TurnStats {
+            allocations,
+            users,
+            client_ips,
+        })
+    }
+
+    /// Live allocations, oldest first.
+    pub fn allocations(&self) -> Vec<AllocationInfo> {
+        self.registry.list()
+    }
+
+    /// Allocation counts and relayed bytes since the server started.
+    pub fn totals(&self) -> AllocationTotals {
+        self.registry.totals()
+    }
+
+    /// Receives an event whenever an allocation is created or closed.
+    pub fn subscribe(&self) -> broadcast::Receiver<AllocationEvent> {
+        self.registry.subscribe()
+    }
+
+    /// Closes every allocation and stops all listeners. Calling it again is
+    /// a no-op.
+    pub async fn shutdown(&self) -> Result<()> {
+        self.server.close().await?;
+        self.shutdown.cancel();
+        Ok(())
+    }
+}
+
+/// Binds all configured listeners and starts serving TURN in the background.
+pub async fn spawn(config: TurnConfig) -> Result<TurnHandle> {
+    let realm = config.realm;
+
+    let auth_handler = Arc::new(EphemeralAuthHandler::new(config.secret));
+    let quotas = Arc::new(QuotaTracker::new(config.quotas.clone()));
+    let registry = Arc::new(AllocationRegistry::new());
+
+    let bind_addr = config.bind_addr;
+    let conn = UdpSocket::bind(bind_addr).await?;
+    let mut listeners = vec![(Transport::Udp, conn.local_addr()?)];
+
+    let relay_ip = match config.external_ip {
+        Some(ip) => ip,
+        None => detect_relay_ip(bind_addr.ip()).await?,
+    };
+    let mut peer_policy = config.peer_policy;
+    peer_policy.deny_own([relay_ip, bind_addr.ip()]);
+    let peer_policy = Arc::new(peer_policy);
+
+    // Every listener gets its own guard and relay generator, linked by the
+    // slot through which the guard names the owner of each allocation.
+    let listener_config = |conn: Arc<dyn Conn + Send + Sync>, transport: Transport| {
+        let owner = OwnerSlot::default();
+        let relay_addr_gen = RelayAddressGeneratorRanges {
+            relay_address: relay_ip,
+            min_port: config.relay_min_port,
+            max_port: config.relay_max_port,
+            max_retries: 0,
+            address: bind_addr.ip().to_string(),
+            net: Arc::new(Net::new(None)),
+        };
+        ConnConfig {
+            conn: Arc::new(GuardedConn::new(
+                conn,
+                peer_policy.clone(),
+                auth_handler.clone(),
+                quotas.clone(),
+                owner.clone(),
+            )),
+            relay_addr_generator: Box::new(LimitedRelayGenerator::new(
+                Box::new(relay_addr_gen),
+                owner,
+                quotas.clone(),
+                registry.clone(),
+                transport,
+            )),
+        }
     };
 
-    let config = ServerConfig {
+    let mut conn_configs = vec![listener_config(Arc::new(conn), Transport::Udp)];
+    let shutdown = CancellationToken::new();
+    let connections = ConnectionCounter::new(config.stream_limits.clone());
+
+    if config.tcp {
+        let listener = TcpListener::bind(bind_addr).await?;
+        listeners.push((Transport::Tcp, listener.local_addr()?));
+        conn_configs.push(listener_config(
+            Arc::new(StreamConn::new(listener, None, connections.clone(), shutdown.clone())?),
+            Transport::Tcp,
+        ));
+    }
+
+    if let Some(tls) = &config.tls {
+        let certs = ReloadableConfig::load(tls.cert_path.clone(), tls.key_path.clone())?;
+        certs.clone().watch();
+        let listener = TcpListener::bind(SocketAddr::new(bind_addr.ip(), tls.port)).await?;
+        listeners.push((Transport::Tls, listener.local_addr()?));
+        conn_configs.push(listener_config(
+            Arc::new(StreamConn::new(listener, Some(certs), connections, shutdown.clone())?),
+            Transport::Tls,
+        ));
+    }
+
+    let (close_tx, mut close_rx) = mpsc::channel::<turn::allocation::AllocationInfo>(64);
+    tokio::spawn({
+        let registry = registry.clone();
+        async move {
+            while let Some(info) = close_rx.recv().await {
+                registry.close(info.relay_addr);
+            }
+        }
+    });
+
+    let server_config = ServerConfig {
         auth_handler,
         realm,
-        conn_configs: vec![ConnConfig {
-            conn: Arc::new(conn),
-            relay_addr_generator: Box::new(relay_addr_gen),
-        }],
-        channel_bind_timeout: std::time::Duration::from_secs(600),
-        alloc_close_notify: None,
+        conn_configs,
+        channel_bind_timeout: Duration::from_secs(600),
+        alloc_close_notify: Some(close_tx),
     };
 
-    let server = Server::new(config).await?;
-    println!("TURN Server listening on {}", bind_addr);
-
-    tokio::signal::ctrl_c().await?;
-    server.close().await?;
+    let server = Server::new(server_config).await?;
 
-    Ok(())
-}
\ No newline at end of file
+    Ok(TurnHandle {
+        server,
+        listeners,
+        relay_ip,
+        quotas,
+        registry,
+        shutdown,
+    })
+}
@@ -0,0 +1,127 @@
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha1::Sha1;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use turn::Error;
+use turn::auth::{AuthHandler, generate_auth_key};
+
+/// Short-lived TURN credentials in the format of the TURN REST API draft:
+/// the username is `expiry:userid` and the password is the base64 encoded
+/// HMAC-SHA1 of the username under the shared secret.
+#[derive(Debug, Clone, Serialize)]
+pub struct TurnCredentials {
+    pub username: String,
+    pub credential: String,
+    pub ttl: u64,
+}
+
+impl TurnCredentials {
+    pub fn issue(secret: &str, ttl: Duration, user_id: &str) -> TurnCredentials {
+        let expiry = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .saturating_add(ttl)
+            .as_secs();
+        let username = format!("{}:{}", expiry, user_id);
+        let credential = sign(secret, &username);
+
+        TurnCredentials {
+            username,
+            credential,
+            ttl: ttl.as_secs(),
+        }
+    }
+}
+
+fn sign(secret: &str, username: &str) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(username.as_bytes());
+    BASE64_STANDARD.encode(mac.finalize().into_bytes())
+}
+
+/// Accepts any username signed with the shared secret whose expiry
+/// timestamp has not passed yet.
+pub struct EphemeralAuthHandler {
+    secret: String,
+}
+
+impl EphemeralAuthHandler {
+    pub fn new(secret: String) -> Self {
+        EphemeralAuthHandler { secret }
+    }
+}
+
+impl AuthHandler for EphemeralAuthHandler {
+    fn auth_handle(
+        &self,
+        username: &str,
+        realm: &str,
+        _src_addr: SocketAddr,
+    ) -> Result<Vec<u8>, Error> {
+        let expiry = username
+            .split_once(':')
+            .and_then(|(expiry, _)| expiry.parse::<u64>().ok())
+            .ok_or_else(|| Error::Other("Malformed username".into()))?;
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        if expiry < now {
+            return Err(Error::Other(format!("Expired credentials for {}", username)));
+        }
+
+        let password = sign(&self.secret, username);
+        Ok(generate_auth_key(username, realm, &password))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "shared secret";
+    const REALM: &str = "rustrooms";
+
+    fn src() -> SocketAddr {
+        "198.51.100.1:50000".parse().unwrap()
+    }
+
+    fn now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    #[test]
+    fn issued_credentials_verify() {
+        let creds = TurnCredentials::issue(SECRET, Duration::from_secs(3600), "alice");
+        let (expiry, user_id) = creds.username.split_once(':').unwrap();
+        assert_eq!(user_id, "alice");
+        assert!(expiry.parse::<u64>().unwrap() >= now() + 3599);
+        assert_eq!(creds.ttl, 3600);
+
+        // The key the server derives is the one a client derives from the
+        // password it was given.
+        let handler = EphemeralAuthHandler::new(SECRET.to_string());
+        let key = handler.auth_handle(&creds.username, REALM, src()).unwrap();
+        assert_eq!(key, generate_auth_key(&creds.username, REALM, &creds.credential));
+
+        let other = EphemeralAuthHandler::new("another secret".to_string());
+        assert_ne!(other.auth_handle(&creds.username, REALM, src()).unwrap(), key);
+    }
+
+    #[test]
+    fn expired_credentials_are_refused() {
+        let handler = EphemeralAuthHandler::new(SECRET.to_string());
+        let username = format!("{}:alice", now() - 1);
+        assert!(handler.auth_handle(&username, REALM, src()).is_err());
+        assert!(handler.auth_handle("0:alice", REALM, src()).is_err());
+    }
+
+    #[test]
+    fn malformed_usernames_are_refused() {
+        let handler = EphemeralAuthHandler::new(SECRET.to_string());
+        for username in ["alice", "", "soon:alice", "-5:alice", "1e12:alice", ":alice", "99999999999999999999999:alice"] {
+            assert!(handler.auth_handle(username, REALM, src()).is_err(), "{}", username);
+        }
+    }
+}
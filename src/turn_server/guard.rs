@@ -0,0 +1,420 @@
+use async_trait::async_trait;
+use ipnet::IpNet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use stun::agent::TransactionId;
+use stun::attributes::{ATTR_REALM, ATTR_USERNAME, ATTR_XOR_PEER_ADDRESS};
+use stun::error_code::{CODE_ALLOC_QUOTA_REACHED, CODE_FORBIDDEN, ErrorCode, ErrorCodeAttribute};
+use stun::integrity::MessageIntegrity;
+use stun::message::{
+    CLASS_ERROR_RESPONSE, CLASS_REQUEST, METHOD_ALLOCATE, METHOD_CHANNEL_BIND, METHOD_CREATE_PERMISSION,
+    MAGIC_COOKIE, Message, MessageType, Method, Setter, is_message,
+};
+use stun::textattrs::TextAttribute;
+use turn::auth::AuthHandler;
+use webrtc_util::Conn;
+
+use super::auth::EphemeralAuthHandler;
+use super::quota::{AllocationOwner, OwnerSlot, QuotaTracker};
+
+/// Peer ranges TURN clients may not relay to unless explicitly allowed:
+/// "this network", private, shared address space, loopback, link-local,
+/// IETF protocol assignments, benchmarking, multicast and reserved ranges
+/// for both address families, and the IPv6 ranges that carry an IPv4
+/// address (IPv4-compatible, NAT64 and 6to4), which could reach any of the
+/// IPv4 ones.
+pub fn default_denied_peers() -> Vec<IpNet> {
+    [
+        "0.0.0.0/8",
+        "10.0.0.0/8",
+        "100.64.0.0/10",
+        "127.0.0.0/8",
+        "169.254.0.0/16",
+        "172.16.0.0/12",
+        "192.0.0.0/24",
+        "192.168.0.0/16",
+        "198.18.0.0/15",
+        "224.0.0.0/4",
+        "240.0.0.0/4",
+        // Unspecified, loopback and IPv4-compatible addresses.
+        "::/96",
+        "64:ff9b::/96",
+        "2002::/16",
+        "fc00::/7",
+        "fe80::/10",
+        "ff00::/8",
+    ]
+    .iter()
+    .map(|net| net.parse().expect("valid built-in network"))
+    .collect()
+}
+
+/// Decides which peer addresses allocations may talk to. An address is
+/// permitted when it is in the allow list or not in the deny list.
+#[derive(Debug, Clone)]
+pub struct PeerPolicy {
+    pub allow: Vec<IpNet>,
+    pub deny: Vec<IpNet>,
+}
+
+impl PeerPolicy {
+    /// Denies the server's own addresses, through which a relay would reach
+    /// the server's other ports.
+    pub fn deny_own(&mut self, addrs: impl IntoIterator<Item = IpAddr>) {
+        self.deny.extend(addrs.into_iter().filter(|ip| !ip.is_unspecified()).map(IpNet::from));
+    }
+
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        // IPv4-mapped IPv6 addresses reach the same hosts as their IPv4 form.
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            v4 => v4,
+        };
+        self.allow.iter().any(|net| net.contains(&ip)) || !self.deny.iter().any(|net| net.contains(&ip))
+    }
+
+    /// The first peer of a CreatePermission or ChannelBind request that is
+    /// not permitted. One such peer is enough to refuse the whole request.
+    fn denied_peer(&self, msg: &Message) -> Option<SocketAddr> {
+        peer_addresses(msg).into_iter().find(|peer| !self.permits(peer.ip()))
+    }
+}
+
+/// Sits between a listener socket and the turn crate's server and rejects
+/// requests the server itself has no policy hooks for.
+pub struct GuardedConn {
+    inner: Arc<dyn Conn + Send + Sync>,
+    policy: Arc<PeerPolicy>,
+    auth: Arc<EphemeralAuthHandler>,
+    quotas: Arc<QuotaTracker>,
+    owner: OwnerSlot,
+}
+
+/// What happens to a packet read from the listener.
+enum Verdict {
+    /// Hand it to the server. Allocate requests carry their owner.
+    Forward(Option<AllocationOwner>),
+    /// Already answered with an error response.
+    Rejected,
+}
+
+impl GuardedConn {
+    pub fn new(
+        inner: Arc<dyn Conn + Send + Sync>,
+        policy: Arc<PeerPolicy>,
+        auth: Arc<EphemeralAuthHandler>,
+        quotas: Arc<QuotaTracker>,
+        owner: OwnerSlot,
+    ) -> GuardedConn {
+        GuardedConn {
+            inner,
+            policy,
+            auth,
+            quotas,
+            owner,
+        }
+    }
+
+    /// Inspects an incoming packet and answers it directly with an error
+    /// when it must not reach the server.
+    async fn inspect(&self, packet: &[u8], src: SocketAddr) -> Verdict {
+        if !is_message(packet) {
+            return Verdict::Forward(None);
+        }
+        let mut msg = Message::new();
+        msg.raw = packet.to_vec();
+        if msg.decode().is_err() || msg.typ.class != CLASS_REQUEST {
+            return Verdict::Forward(None);
+        }
+
+        let method = msg.typ.method;
+        let username = text_attr(&msg, ATTR_USERNAME).unwrap_or_default();
+
+        let (code, reason, note) = if method == METHOD_ALLOCATE {
+            if username.is_empty() {
+                return Verdict::Forward(None);
+            }
+            let owner = AllocationOwner {
+                username: username.clone(),
+                src_addr: src,
+            };
+            if self.quotas.admits(&owner) {
+                return Verdict::Forward(Some(owner));
+            }
+            (
+                CODE_ALLOC_QUOTA_REACHED,
+                "Allocation Quota Reached",
+                "allocation quota reached".to_string(),
+            )
+        } else if method == METHOD_CREATE_PERMISSION || method == METHOD_CHANNEL_BIND {
+            let Some(peer) = self.policy.denied_peer(&msg) else {
+                return Verdict::Forward(None);
+            };
+            (CODE_FORBIDDEN, "Forbidden", format!("{} to denied peer {}", method, peer))
+        } else {
+            return Verdict::Forward(None);
+        };
+
+        // Only answer requests that carry valid credentials. Anything else
+        // goes on to the server, which rejects it without side effects.
+        let Some(integrity) = self.verify(&mut msg, src) else {
+            return Verdict::Forward(None);
+        };
+
+        eprintln!("TURN: rejected request from {} ({}): {}", src, username, note);
+        self.reject(msg.transaction_id, method, code, reason, integrity, src)
+            .await;
+        Verdict::Rejected
+    }
+
+    fn verify(&self, msg: &mut Message, src: SocketAddr) -> Option<MessageIntegrity> {
+        let username = text_attr(msg, ATTR_USERNAME)?;
+        let realm = text_attr(msg, ATTR_REALM)?;
+        let key = self.auth.auth_handle(&username, &realm, src).ok()?;
+        let integrity = MessageIntegrity(key);
+        integrity.check(msg).ok()?;
+        Some(integrity)
+    }
+
+    async fn reject(
+        &self,
+        transaction_id: TransactionId,
+        method: Method,
+        code: ErrorCode,
+        reason: &str,
+        integrity: MessageIntegrity,
+        dst: SocketAddr,
+    ) {
+        if let Some(raw) = error_response(transaction_id, method, code, reason, integrity) {
+            let _ = self.inner.send_to(&raw, dst).await;
+        }
+    }
+}
+
+fn error_response(
+    transaction_id: TransactionId,
+    method: Method,
+    code: ErrorCode,
+    reason: &str,
+    integrity: MessageIntegrity,
+) -> Option<Vec<u8>> {
+    let mut resp = Message::new();
+    let setters: Vec<Box<dyn Setter>> = vec![
+        Box::new(Message {
+            transaction_id,
+            ..Default::default()
+        }),
+        Box::new(MessageType::new(method, CLASS_ERROR_RESPONSE)),
+        Box::new(ErrorCodeAttribute {
+            code,
+            reason: reason.as_bytes().to_vec(),
+        }),
+        Box::new(integrity),
+    ];
+    resp.build(&setters).ok()?;
+    Some(resp.raw)
+}
+
+fn text_attr(msg: &Message, attr: stun::attributes::AttrType) -> Option<String> {
+    TextAttribute::get_from_as(msg, attr).ok().map(|a| a.text)
+}
+
+/// Decodes every XOR-PEER-ADDRESS attribute of a message. The stun crate
+/// only exposes the first one, but a CreatePermission may carry several.
+fn peer_addresses(msg: &Message) -> Vec<SocketAddr> {
+    let cookie = MAGIC_COOKIE.to_be_bytes();
+    msg.attributes
+        .0
+        .iter()
+        .filter(|attr| attr.typ == ATTR_XOR_PEER_ADDRESS && attr.value.len() >= 8)
+        .filter_map(|attr| {
+            let v = &attr.value;
+            let port = u16::from_be_bytes([v[2] ^ cookie[0], v[3] ^ cookie[1]]);
+            let ip = match v[1] {
+                0x01 => {
+                    let mut octets = [0u8; 4];
+                    for (i, o) in octets.iter_mut().enumerate() {
+                        *o = v[4 + i] ^ cookie[i];
+                    }
+                    IpAddr::V4(Ipv4Addr::from(octets))
+                }
+                0x02 if v.len() >= 20 => {
+                    let mut key = [0u8; 16];
+                    key[..4].copy_from_slice(&cookie);
+                    key[4..].copy_from_slice(&msg.transaction_id.0);
+                    let mut octets = [0u8; 16];
+                    for (i, o) in octets.iter_mut().enumerate() {
+                        *o = v[4 + i] ^ key[i];
+                    }
+                    IpAddr::V6(Ipv6Addr::from(octets))
+                }
+                _ => return None,
+            };
+            Some(SocketAddr::new(ip, port))
+        })
+        .collect()
+}
+
+#[async_trait]
+impl Conn for GuardedConn {
+    async fn connect(&self, addr: SocketAddr) -> webrtc_util::Result<()> {
+        self.inner.connect(addr).await
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> webrtc_util::Result<usize> {
+        self.inner.recv(buf).await
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> webrtc_util::Result<(usize, SocketAddr)> {
+        loop {
+            let (n, src) = self.inner.recv_from(buf).await?;
+            if let Verdict::Forward(owner) = self.inspect(&buf[..n], src).await {
+                *self.owner.lock().unwrap() = owner;
+                return Ok((n, src));
+            }
+        }
+    }
+
+    async fn send(&self, buf: &[u8]) -> webrtc_util::Result<usize> {
+        self.inner.send(buf).await
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> webrtc_util::Result<usize> {
+        self.inner.send_to(buf, target).await
+    }
+
+    fn local_addr(&self) -> webrtc_util::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.inner.remote_addr()
+    }
+
+    async fn close(&self) -> webrtc_util::Result<()> {
+        self.inner.close().await
+    }
+
+    fn as_any(&self) -> &(dyn std::any::Any + Send + Sync) {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use turn::proto::peeraddr::PeerAddress;
+
+    fn policy(allow: &[&str]) -> PeerPolicy {
+        PeerPolicy {
+            allow: allow.iter().map(|net| net.parse().unwrap()).collect(),
+            deny: default_denied_peers(),
+        }
+    }
+
+    /// A CreatePermission for `peers`, encoded and decoded again the way
+    /// it arrives from the wire.
+    fn create_permission(peers: &[&str]) -> Message {
+        let mut setters: Vec<Box<dyn Setter>> = vec![
+            Box::new(TransactionId::new()),
+            Box::new(MessageType::new(METHOD_CREATE_PERMISSION, CLASS_REQUEST)),
+        ];
+        for peer in peers {
+            let addr: SocketAddr = peer.parse().unwrap();
+            setters.push(Box::new(PeerAddress {
+                ip: addr.ip(),
+                port: addr.port(),
+            }));
+        }
+        let mut built = Message::new();
+        built.build(&setters).unwrap();
+        let mut msg = Message::new();
+        msg.raw = built.raw;
+        msg.decode().unwrap();
+        msg
+    }
+
+    fn addrs(peers: &[&str]) -> Vec<SocketAddr> {
+        peers.iter().map(|peer| peer.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn every_peer_is_decoded() {
+        let peers = ["203.0.113.7:5000", "[2001:db8::1]:443", "198.51.100.1:65535"];
+        assert_eq!(peer_addresses(&create_permission(&peers)), addrs(&peers));
+    }
+
+    #[test]
+    fn one_denied_peer_refuses_the_request() {
+        let policy = policy(&[]);
+        let allowed = create_permission(&["203.0.113.7:5000", "198.51.100.1:6000"]);
+        assert_eq!(policy.denied_peer(&allowed), None);
+
+        let mixed = create_permission(&["203.0.113.7:5000", "10.1.2.3:6000", "198.51.100.1:6000"]);
+        assert_eq!(policy.denied_peer(&mixed), Some("10.1.2.3:6000".parse().unwrap()));
+    }
+
+    #[test]
+    fn ipv6_peers() {
+        let policy = policy(&[]);
+        let public = create_permission(&["[2001:db8::1]:443"]);
+        assert_eq!(policy.denied_peer(&public), None);
+        for peer in ["[::1]:443", "[fe80::1]:443", "[fd00::5]:443", "[ff02::1]:443"] {
+            let msg = create_permission(&[peer]);
+            assert_eq!(policy.denied_peer(&msg), Some(peer.parse().unwrap()), "{}", peer);
+        }
+    }
+
+    #[test]
+    fn ipv4_mapped_peers_count_as_ipv4() {
+        let policy = policy(&[]);
+        let msg = create_permission(&["[::ffff:127.0.0.1]:80"]);
+        assert_eq!(policy.denied_peer(&msg), Some("[::ffff:127.0.0.1]:80".parse().unwrap()));
+        assert!(!policy.permits("::ffff:192.168.1.1".parse().unwrap()));
+        assert!(policy.permits("::ffff:203.0.113.7".parse().unwrap()));
+    }
+
+    #[test]
+    fn special_purpose_ranges_are_denied() {
+        let policy = policy(&[]);
+        for ip in ["192.0.0.8", "198.18.0.1", "198.19.255.254", "::1", "::", "::a00:1", "::7f00:1"] {
+            assert!(!policy.permits(ip.parse().unwrap()), "{}", ip);
+        }
+        assert!(policy.permits("198.20.0.1".parse().unwrap()));
+        assert!(policy.permits("192.0.2.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn ipv6_ranges_embedding_ipv4_are_denied() {
+        let policy = policy(&[]);
+        // NAT64 of 10.0.0.1 and 127.0.0.1.
+        assert!(!policy.permits("64:ff9b::a00:1".parse().unwrap()));
+        assert!(!policy.permits("64:ff9b::7f00:1".parse().unwrap()));
+        // 6to4 of 192.168.1.1.
+        assert!(!policy.permits("2002:c0a8:101::1".parse().unwrap()));
+        assert!(policy.permits("2001:db8::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn own_addresses_are_denied() {
+        let mut policy = policy(&[]);
+        policy.deny_own(["203.0.113.7".parse().unwrap(), "2001:db8::7".parse().unwrap(), "0.0.0.0".parse().unwrap()]);
+        let msg = create_permission(&["203.0.113.7:3000"]);
+        assert_eq!(policy.denied_peer(&msg), Some("203.0.113.7:3000".parse().unwrap()));
+        assert!(!policy.permits("::ffff:203.0.113.7".parse().unwrap()));
+        assert!(!policy.permits("2001:db8::7".parse().unwrap()));
+        assert!(policy.permits("203.0.113.8".parse().unwrap()));
+        assert!(policy.permits("2001:db8::8".parse().unwrap()));
+    }
+
+    #[test]
+    fn allow_list_makes_exceptions_to_denied_ranges() {
+        let policy = policy(&["10.1.0.0/16"]);
+        assert!(policy.permits("10.1.2.3".parse().unwrap()));
+        assert!(policy.permits("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!policy.permits("10.2.0.1".parse().unwrap()));
+
+        let msg = create_permission(&["10.1.2.3:5000", "10.2.0.1:5000"]);
+        assert_eq!(policy.denied_peer(&msg), Some("10.2.0.1:5000".parse().unwrap()));
+    }
+}
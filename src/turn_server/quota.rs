@@ -0,0 +1,397 @@
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::Duration;
+use tokio::time::Instant;
+use turn::relay::RelayAddressGenerator;
+use webrtc_util::{Conn, Error};
+
+use super::Transport;
+use super::registry::AllocationRegistry;
+
+/// Limits applied to TURN allocations. Zero or `None` means unlimited.
+#[derive(Debug, Clone)]
+pub struct Quotas {
+    pub max_allocations_per_user: usize,
+    pub max_allocations_per_ip: usize,
+    /// Total time an allocation may exist, refreshes included.
+    pub max_allocation_lifetime: Option<Duration>,
+    /// Relayed bytes per second per allocation, both directions combined.
+    pub allocation_bandwidth: Option<u64>,
+}
+
+/// The client an Allocate request came from.
+#[derive(Debug, Clone)]
+pub struct AllocationOwner {
+    pub username: String,
+    pub src_addr: SocketAddr,
+}
+
+/// Hands the owner of the Allocate request being processed from the guard
+/// to the relay generator. The server handles the requests of a listener
+/// one at a time, so the owner stored for the request the guard passed on
+/// last is the one the relay socket is being created for.
+pub type OwnerSlot = Arc<StdMutex<Option<AllocationOwner>>>;
+
+/// Counts live allocations per username and per client IP.
+#[derive(Debug)]
+pub struct QuotaTracker {
+    quotas: Quotas,
+    by_user: StdMutex<HashMap<String, usize>>,
+    by_ip: StdMutex<HashMap<IpAddr, usize>>,
+}
+
+impl QuotaTracker {
+    pub fn new(quotas: Quotas) -> QuotaTracker {
+        QuotaTracker {
+            quotas,
+            by_user: StdMutex::new(HashMap::new()),
+            by_ip: StdMutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether `owner` may create another allocation.
+    pub fn admits(&self, owner: &AllocationOwner) -> bool {
+        let within = |limit: usize, count: Option<&usize>| limit == 0 || count.copied().unwrap_or(0) < limit;
+        within(self.quotas.max_allocations_per_user, self.by_user.lock().unwrap().get(&owner.username))
+            && within(self.quotas.max_allocations_per_ip, self.by_ip.lock().unwrap().get(&owner.src_addr.ip()))
+    }
+
+    /// Number of distinct usernames and client IPs holding allocations.
+    pub fn holders(&self) -> (usize, usize) {
+        (self.by_user.lock().unwrap().len(), self.by_ip.lock().unwrap().len())
+    }
+
+    fn acquire(&self, owner: &AllocationOwner) {
+        *self.by_user.lock().unwrap().entry(owner.username.clone()).or_insert(0) += 1;
+        *self.by_ip.lock().unwrap().entry(owner.src_addr.ip()).or_insert(0) += 1;
+    }
+
+    fn release(&self, owner: &AllocationOwner) {
+        fn decrement<K: std::hash::Hash + Eq>(map: &mut HashMap<K, usize>, key: &K) {
+            if let Some(count) = map.get_mut(key) {
+                *count -= 1;
+                if *count == 0 {
+                    map.remove(key);
+                }
+            }
+        }
+        decrement(&mut self.by_user.lock().unwrap(), &owner.username);
+        decrement(&mut self.by_ip.lock().unwrap(), &owner.src_addr.ip());
+    }
+}
+
+/// Wraps the relay address generator of one listener so every relay socket
+/// it creates counts against its owner's quota, enforces the lifetime and
+/// bandwidth limits and shows up in the allocation registry.
+pub struct LimitedRelayGenerator {
+    inner: Box<dyn RelayAddressGenerator + Send + Sync>,
+    owner: OwnerSlot,
+    tracker: Arc<QuotaTracker>,
+    registry: Arc<AllocationRegistry>,
+    transport: Transport,
+}
+
+impl LimitedRelayGenerator {
+    pub fn new(
+        inner: Box<dyn RelayAddressGenerator + Send + Sync>,
+        owner: OwnerSlot,
+        tracker: Arc<QuotaTracker>,
+        registry: Arc<AllocationRegistry>,
+        transport: Transport,
+    ) -> LimitedRelayGenerator {
+        LimitedRelayGenerator {
+            inner,
+            owner,
+            tracker,
+            registry,
+            transport,
+        }
+    }
+}
+
+#[async_trait]
+impl RelayAddressGenerator for LimitedRelayGenerator {
+    fn validate(&self) -> Result<(), turn::Error> {
+        self.inner.validate()
+    }
+
+    async fn allocate_conn(
+        &self,
+        use_ipv4: bool,
+        requested_port: u16,
+    ) -> Result<(Arc<dyn Conn + Send + Sync>, SocketAddr), turn::Error> {
+        let (conn, addr) = self.inner.allocate_conn(use_ipv4, requested_port).await?;
+        let owner = self.owner.lock().unwrap().clone();
+        let quotas = &self.tracker.quotas;
+
+        let bytes_relayed = owner.as_ref().map(|owner| {
+            self.tracker.acquire(owner);
+            self.registry
+                .open(owner.username.clone(), owner.src_addr, addr, self.transport)
+        });
+
+        let limited = LimitedRelayConn {
+            inner: conn,
+            relay_addr: addr,
+            owner,
+            tracker: self.tracker.clone(),
+            registry: self.registry.clone(),
+            bytes_relayed: bytes_relayed.unwrap_or_default(),
+            deadline: quotas.max_allocation_lifetime.map(|max| Instant::now() + max),
+            bucket: quotas.allocation_bandwidth.map(|rate| StdMutex::new(TokenBucket::new(rate))),
+        };
+        Ok((Arc::new(limited), addr))
+    }
+}
+
+/// Relay socket of a single allocation. Once the lifetime cap passes it
+/// reports an error, which makes the server drop the allocation; traffic
+/// over the bandwidth limit is dropped like on a congested link.
+struct LimitedRelayConn {
+    inner: Arc<dyn Conn + Send + Sync>,
+    relay_addr: SocketAddr,
+    owner: Option<AllocationOwner>,
+    tracker: Arc<QuotaTracker>,
+    registry: Arc<AllocationRegistry>,
+    bytes_relayed: Arc<AtomicU64>,
+    deadline: Option<Instant>,
+    bucket: Option<StdMutex<TokenBucket>>,
+}
+
+impl LimitedRelayConn {
+    fn expired(&self) -> bool {
+        self.deadline.is_some_and(|d| Instant::now() >= d)
+    }
+
+    fn within_bandwidth(&self, bytes: usize) -> bool {
+        self.bucket.as_ref().is_none_or(|b| b.lock().unwrap().take(bytes, Instant::now()))
+    }
+
+    fn count(&self, bytes: usize) {
+        self.bytes_relayed.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+impl Drop for LimitedRelayConn {
+    fn drop(&mut self) {
+        if let Some(owner) = &self.owner {
+            self.tracker.release(owner);
+            self.registry.close(self.relay_addr);
+        }
+    }
+}
+
+#[async_trait]
+impl Conn for LimitedRelayConn {
+    async fn connect(&self, addr: SocketAddr) -> webrtc_util::Result<()> {
+        self.inner.connect(addr).await
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> webrtc_util::Result<usize> {
+        self.inner.recv(buf).await
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> webrtc_util::Result<(usize, SocketAddr)> {
+        loop {
+            let received = match self.deadline {
+                Some(deadline) => tokio::select! {
+                    v = self.inner.recv_from(buf) => v?,
+                    _ = tokio::time::sleep_until(deadline) => {
+                        self.registry.close(self.relay_addr);
+                        return Err(Error::ErrTimeout);
+                    }
+                },
+                None => self.inner.recv_from(buf).await?,
+            };
+            if self.within_bandwidth(received.0) {
+                self.count(received.0);
+                return Ok(received);
+            }
+        }
+    }
+
+    async fn send(&self, buf: &[u8]) -> webrtc_util::Result<usize> {
+        self.inner.send(buf).await
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> webrtc_util::Result<usize> {
+        if self.expired() {
+            return Err(Error::ErrTimeout);
+        }
+        if !self.within_bandwidth(buf.len()) {
+            return Ok(buf.len());
+        }
+        let sent = self.inner.send_to(buf, target).await?;
+        self.count(sent);
+        Ok(sent)
+    }
+
+    fn local_addr(&self) -> webrtc_util::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.inner.remote_addr()
+    }
+
+    async fn close(&self) -> webrtc_util::Result<()> {
+        self.inner.close().await
+    }
+
+    fn as_any(&self) -> &(dyn std::any::Any + Send + Sync) {
+        self
+    }
+}
+
+/// Largest packet a relay is expected to carry. The bucket always holds at
+/// least this much, or a limit below one packet per second would let
+/// nothing through at all.
+const MAX_PACKET: f64 = 1500.0;
+
+/// Allows `rate` bytes per second with bursts of up to one second, or of
+/// one full-sized packet if that is more.
+struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> TokenBucket {
+        let capacity = (rate as f64).max(MAX_PACKET);
+        TokenBucket {
+            rate: rate as f64,
+            capacity,
+            tokens: capacity,
+            last: Instant::now(),
+        }
+    }
+
+    fn take(&mut self, bytes: usize, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+
+        if self.tokens >= bytes as f64 {
+            self.tokens -= bytes as f64;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use turn::relay::relay_static::RelayAddressGeneratorStatic;
+    use webrtc_util::vnet::net::Net;
+
+    fn quotas(per_user: usize, per_ip: usize, lifetime: Option<Duration>) -> Quotas {
+        Quotas {
+            max_allocations_per_user: per_user,
+            max_allocations_per_ip: per_ip,
+            max_allocation_lifetime: lifetime,
+            allocation_bandwidth: None,
+        }
+    }
+
+    fn owner(username: &str, addr: &str) -> AllocationOwner {
+        AllocationOwner {
+            username: username.to_string(),
+            src_addr: addr.parse().unwrap(),
+        }
+    }
+
+    /// A generator handing out loopback relay sockets, and the slot naming
+    /// the owner of the next one.
+    fn generator(tracker: &Arc<QuotaTracker>, registry: &Arc<AllocationRegistry>) -> (LimitedRelayGenerator, OwnerSlot) {
+        let slot = OwnerSlot::default();
+        let inner = RelayAddressGeneratorStatic {
+            relay_address: "127.0.0.1".parse().unwrap(),
+            address: "127.0.0.1".to_string(),
+            net: Arc::new(Net::new(None)),
+        };
+        let generator = LimitedRelayGenerator::new(Box::new(inner), slot.clone(), tracker.clone(), registry.clone(), Transport::Udp);
+        (generator, slot)
+    }
+
+    async fn allocate(generator: &LimitedRelayGenerator, slot: &OwnerSlot, owner: &AllocationOwner) -> Arc<dyn Conn + Send + Sync> {
+        *slot.lock().unwrap() = Some(owner.clone());
+        generator.allocate_conn(true, 0).await.unwrap().0
+    }
+
+    #[tokio::test]
+    async fn allocations_per_user_are_limited() {
+        let tracker = Arc::new(QuotaTracker::new(quotas(2, 0, None)));
+        let registry = Arc::new(AllocationRegistry::new());
+        let (generator, slot) = generator(&tracker, &registry);
+        let alice = owner("1:alice", "198.51.100.1:5000");
+        let first = allocate(&generator, &slot, &alice).await;
+        let _second = allocate(&generator, &slot, &owner("1:alice", "198.51.100.2:5000")).await;
+        assert!(!tracker.admits(&alice));
+        // Other users are not held back.
+        assert!(tracker.admits(&owner("1:bob", "198.51.100.1:5001")));
+
+        drop(first);
+        assert!(tracker.admits(&alice));
+        assert_eq!(tracker.holders(), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn allocations_per_ip_are_limited() {
+        let tracker = Arc::new(QuotaTracker::new(quotas(0, 1, None)));
+        let registry = Arc::new(AllocationRegistry::new());
+        let (generator, slot) = generator(&tracker, &registry);
+        let conn = allocate(&generator, &slot, &owner("1:alice", "198.51.100.1:5000")).await;
+        assert!(!tracker.admits(&owner("1:bob", "198.51.100.1:6000")));
+        assert!(tracker.admits(&owner("1:bob", "198.51.100.2:6000")));
+
+        drop(conn);
+        assert!(tracker.admits(&owner("1:bob", "198.51.100.1:6000")));
+        assert_eq!(tracker.holders(), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn lifetime_cap_ends_the_allocation() {
+        let tracker = Arc::new(QuotaTracker::new(quotas(0, 0, Some(Duration::from_millis(50)))));
+        let registry = Arc::new(AllocationRegistry::new());
+        let (generator, slot) = generator(&tracker, &registry);
+        let conn = allocate(&generator, &slot, &owner("1:alice", "198.51.100.1:5000")).await;
+        let peer: SocketAddr = "127.0.0.1:9".parse().unwrap();
+        assert!(conn.send_to(b"early", peer).await.is_ok());
+        assert_eq!(registry.totals().live, 1);
+
+        let mut buf = [0u8; 16];
+        assert!(matches!(conn.recv_from(&mut buf).await, Err(Error::ErrTimeout)));
+        assert!(matches!(conn.send_to(b"late", peer).await, Err(Error::ErrTimeout)));
+        assert_eq!(registry.totals().live, 0);
+    }
+
+    #[test]
+    fn bucket_refills_at_its_rate() {
+        let mut bucket = TokenBucket::new(10_000);
+        let start = bucket.last;
+        assert!(bucket.take(10_000, start));
+        assert!(!bucket.take(1, start));
+        assert!(bucket.take(5_000, start + Duration::from_millis(500)));
+        assert!(!bucket.take(1_000, start + Duration::from_millis(500)));
+        // Idle time never saves up more than a second's worth.
+        assert!(bucket.take(10_000, start + Duration::from_secs(10)));
+        assert!(!bucket.take(1, start + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn rates_below_one_packet_per_second_still_pass_packets() {
+        // 8 kbit/s against 1200 byte RTP packets.
+        let mut bucket = TokenBucket::new(1_000);
+        let start = bucket.last;
+        assert!(bucket.take(1_200, start));
+        assert!(!bucket.take(1_200, start + Duration::from_millis(500)));
+        assert!(bucket.take(1_200, start + Duration::from_millis(1_200)));
+    }
+}
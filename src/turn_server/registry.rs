@@ -0,0 +1,227 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+
+use super::Transport;
+
+const EVENT_QUEUE: usize = 256;
+
+/// Snapshot of one live allocation.
+#[derive(Debug, Clone, Serialize)]
+pub struct AllocationInfo {
+    pub username: String,
+    pub client_addr: SocketAddr,
+    pub relay_addr: SocketAddr,
+    pub transport: Transport,
+    pub bytes_relayed: u64,
+    /// Unix timestamp of creation, in seconds.
+    pub created_at: u64,
+    pub age_secs: u64,
+}
+
+/// Allocation lifecycle notifications.
+#[derive(Debug, Clone)]
+pub enum AllocationEvent {
+    Created(AllocationInfo),
+    Closed(AllocationInfo),
+}
+
+struct Entry {
+    username: String,
+    client_addr: SocketAddr,
+    transport: Transport,
+    bytes_relayed: Arc<AtomicU64>,
+    created_at: SystemTime,
+    started: Instant,
+}
+
+impl Entry {
+    fn info(&self, relay_addr: SocketAddr) -> AllocationInfo {
+        AllocationInfo {
+            username: self.username.clone(),
+            client_addr: self.client_addr,
+            relay_addr,
+            transport: self.transport,
+            bytes_relayed: self.bytes_relayed.load(Ordering::Relaxed),
+            created_at: self
+                .created_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            age_secs: self.started.elapsed().as_secs(),
+        }
+    }
+}
+
+/// Counters over the server's lifetime.
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AllocationTotals {
+    /// Allocations that exist right now.
+    pub live: usize,
+    /// Allocations ever created.
+    pub created: u64,
+    /// Bytes relayed by all allocations, closed ones included.
+    pub bytes_relayed: u64,
+}
+
+/// Every allocation the server currently holds, keyed by relay address.
+pub struct AllocationRegistry {
+    entries: StdMutex<HashMap<SocketAddr, Entry>>,
+    events: broadcast::Sender<AllocationEvent>,
+    created: AtomicU64,
+    /// Bytes relayed by allocations that have been closed.
+    closed_bytes: AtomicU64,
+}
+
+impl AllocationRegistry {
+    pub fn new() -> AllocationRegistry {
+        AllocationRegistry {
+            entries: StdMutex::new(HashMap::new()),
+            events: broadcast::channel(EVENT_QUEUE).0,
+            created: AtomicU64::new(0),
+            closed_bytes: AtomicU64::new(0),
+        }
+    }
+
+    /// Records a new allocation. The returned counter is shared with the
+    /// relay socket, which adds every byte it forwards.
+    pub fn open(
+        &self,
+        username: String,
+        client_addr: SocketAddr,
+        relay_addr: SocketAddr,
+        transport: Transport,
+    ) -> Arc<AtomicU64> {
+        let bytes_relayed = Arc::new(AtomicU64::new(0));
+        let entry = Entry {
+            username,
+            client_addr,
+            transport,
+            bytes_relayed: bytes_relayed.clone(),
+            created_at: SystemTime::now(),
+            started: Instant::now(),
+        };
+        let info = entry.info(relay_addr);
+        self.entries.lock().unwrap().insert(relay_addr, entry);
+        self.created.fetch_add(1, Ordering::Relaxed);
+        let _ = self.events.send(AllocationEvent::Created(info));
+        bytes_relayed
+    }
+
+    /// Forgets an allocation. Several paths report the same close, only the
+    /// first one emits an event.
+    pub fn close(&self, relay_addr: SocketAddr) {
+        let entry = {
+            let mut entries = self.entries.lock().unwrap();
+            let entry = entries.remove(&relay_addr);
+            // Moved over under the lock, so totals never miss these bytes.
+            if let Some(entry) = &entry {
+                self.closed_bytes
+                    .fetch_add(entry.bytes_relayed.load(Ordering::Relaxed), Ordering::Relaxed);
+            }
+            entry
+        };
+        if let Some(entry) = entry {
+            let _ = self.events.send(AllocationEvent::Closed(entry.info(relay_addr)));
+        }
+    }
+
+    pub fn list(&self) -> Vec<AllocationInfo> {
+        let mut list: Vec<_> = self
+            .entries
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(relay_addr, entry)| entry.info(*relay_addr))
+            .collect();
+        list.sort_by_key(|info| info.created_at);
+        list
+    }
+
+    pub fn totals(&self) -> AllocationTotals {
+        let entries = self.entries.lock().unwrap();
+        let live_bytes: u64 = entries.values().map(|e| e.bytes_relayed.load(Ordering::Relaxed)).sum();
+        AllocationTotals {
+            live: entries.len(),
+            created: self.created.load(Ordering::Relaxed),
+            bytes_relayed: self.closed_bytes.load(Ordering::Relaxed) + live_bytes,
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<AllocationEvent> {
+        self.events.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::broadcast::error::TryRecvError;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn open_and_close_emit_one_event_each() {
+        let registry = AllocationRegistry::new();
+        let mut events = registry.subscribe();
+        let relay = addr("127.0.0.1:50000");
+        registry.open("1:alice".to_string(), addr("198.51.100.1:5000"), relay, Transport::Udp);
+
+        let AllocationEvent::Created(info) = events.try_recv().unwrap() else {
+            panic!("expected a created event");
+        };
+        assert_eq!(info.username, "1:alice");
+        assert_eq!(info.client_addr, addr("198.51.100.1:5000"));
+        assert_eq!(info.relay_addr, relay);
+        assert_eq!(info.transport, Transport::Udp);
+        assert_eq!(registry.list().len(), 1);
+
+        registry.close(relay);
+        assert!(matches!(events.try_recv(), Ok(AllocationEvent::Closed(info)) if info.relay_addr == relay));
+        // Later reports of the same close stay quiet.
+        registry.close(relay);
+        assert!(matches!(events.try_recv(), Err(TryRecvError::Empty)));
+        assert!(registry.list().is_empty());
+    }
+
+    #[test]
+    fn closed_event_carries_the_bytes_relayed() {
+        let registry = AllocationRegistry::new();
+        let relay = addr("127.0.0.1:50000");
+        let bytes = registry.open("1:alice".to_string(), addr("198.51.100.1:5000"), relay, Transport::Tcp);
+        let mut events = registry.subscribe();
+        bytes.fetch_add(1_500, Ordering::Relaxed);
+
+        assert_eq!(registry.list()[0].bytes_relayed, 1_500);
+        registry.close(relay);
+        assert!(matches!(events.try_recv(), Ok(AllocationEvent::Closed(info)) if info.bytes_relayed == 1_500));
+    }
+
+    #[test]
+    fn totals_keep_the_bytes_of_closed_allocations() {
+        let registry = AllocationRegistry::new();
+        let first = addr("127.0.0.1:50000");
+        let second = addr("127.0.0.1:50001");
+        registry.open("1:alice".to_string(), addr("198.51.100.1:5000"), first, Transport::Udp).fetch_add(100, Ordering::Relaxed);
+        registry.open("1:bob".to_string(), addr("198.51.100.2:5000"), second, Transport::Udp).fetch_add(20, Ordering::Relaxed);
+
+        let totals = registry.totals();
+        assert_eq!((totals.live, totals.created, totals.bytes_relayed), (2, 2, 120));
+
+        registry.close(first);
+        registry.close(first);
+        let totals = registry.totals();
+        assert_eq!((totals.live, totals.created, totals.bytes_relayed), (1, 2, 120));
+
+        registry.close(second);
+        let totals = registry.totals();
+        assert_eq!((totals.live, totals.created, totals.bytes_relayed), (0, 2, 120));
+    }
+}
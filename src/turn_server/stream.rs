@@ -0,0 +1,463 @@
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, Mutex};
+use tokio_util::sync::CancellationToken;
+use webrtc_util::{Conn, Error};
+
+use crate::tls::ReloadableConfig;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const READ_TIMEOUTS: ReadTimeouts = ReadTimeouts {
+    // A client using its allocation refreshes its permissions at least
+    // every five minutes, since they expire after that.
+    idle: Duration::from_secs(360),
+    frame: Duration::from_secs(10),
+};
+const OUTBOUND_QUEUE: usize = 256;
+const INBOUND_QUEUE: usize = 1024;
+const STUN_HEADER_SIZE: usize = 20;
+const CHANNEL_DATA_HEADER_SIZE: usize = 4;
+
+/// How long a connection may go without starting a frame, and how long the
+/// rest of a frame may take once its header has arrived.
+#[derive(Debug, Clone, Copy)]
+struct ReadTimeouts {
+    idle: Duration,
+    frame: Duration,
+}
+
+type Peers = Arc<StdMutex<HashMap<SocketAddr, mpsc::Sender<Vec<u8>>>>>;
+
+/// Limits on connections to the TCP and TLS listeners together. Zero means
+/// unlimited.
+#[derive(Debug, Clone)]
+pub struct StreamLimits {
+    pub max_connections: usize,
+    pub max_connections_per_ip: usize,
+}
+
+/// Open connections across the stream listeners, in total and per client IP.
+#[derive(Debug)]
+pub struct ConnectionCounter {
+    limits: StreamLimits,
+    total: StdMutex<usize>,
+    by_ip: StdMutex<HashMap<IpAddr, usize>>,
+}
+
+impl ConnectionCounter {
+    pub fn new(limits: StreamLimits) -> Arc<ConnectionCounter> {
+        Arc::new(ConnectionCounter {
+            limits,
+            total: StdMutex::new(0),
+            by_ip: StdMutex::new(HashMap::new()),
+        })
+    }
+
+    /// Counts a connection from `ip`, or returns `None` if that would go
+    /// over a limit. The connection is counted until the slot is dropped.
+    fn open(self: &Arc<Self>, ip: IpAddr) -> Option<ConnectionSlot> {
+        let within = |limit: usize, count: usize| limit == 0 || count < limit;
+        let mut total = self.total.lock().unwrap();
+        let mut by_ip = self.by_ip.lock().unwrap();
+        let from_ip = by_ip.get(&ip).copied().unwrap_or(0);
+        if !within(self.limits.max_connections, *total) || !within(self.limits.max_connections_per_ip, from_ip) {
+            return None;
+        }
+        *total += 1;
+        by_ip.insert(ip, from_ip + 1);
+        Some(ConnectionSlot {
+            counter: self.clone(),
+            ip,
+        })
+    }
+}
+
+struct ConnectionSlot {
+    counter: Arc<ConnectionCounter>,
+    ip: IpAddr,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        *self.counter.total.lock().unwrap() -= 1;
+        let mut by_ip = self.counter.by_ip.lock().unwrap();
+        if let Some(count) = by_ip.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                by_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// Presents a TCP (or TLS over TCP) listener as the packet oriented `Conn`
+/// the turn crate's server reads from. Every accepted connection is split
+/// into STUN and ChannelData frames, which are handed out by `recv_from`
+/// tagged with the connection's remote address. `send_to` writes back on
+/// the connection belonging to that address.
+///
+/// For TLS, every connection is accepted with the certificate loaded last,
+/// so renewals are picked up without a restart.
+pub struct StreamConn {
+    local_addr: SocketAddr,
+    inbound: Mutex<mpsc::Receiver<(Vec<u8>, SocketAddr)>>,
+    peers: Peers,
+    shutdown: CancellationToken,
+}
+
+impl StreamConn {
+    /// Starts accepting connections until `shutdown` is cancelled.
+    pub fn new(
+        listener: TcpListener,
+        tls: Option<Arc<ReloadableConfig>>,
+        connections: Arc<ConnectionCounter>,
+        shutdown: CancellationToken,
+    ) -> std::io::Result<StreamConn> {
+        let local_addr = listener.local_addr()?;
+        let (inbound_tx, inbound_rx) = mpsc::channel(INBOUND_QUEUE);
+        let peers: Peers = Arc::new(StdMutex::new(HashMap::new()));
+
+        tokio::spawn(accept_loop(listener, tls, connections, inbound_tx, peers.clone(), shutdown.clone()));
+
+        Ok(StreamConn {
+            local_addr,
+            inbound: Mutex::new(inbound_rx),
+            peers,
+            shutdown,
+        })
+    }
+}
+
+async fn accept_loop(
+    listener: TcpListener,
+    tls: Option<Arc<ReloadableConfig>>,
+    connections: Arc<ConnectionCounter>,
+    inbound: mpsc::Sender<(Vec<u8>, SocketAddr)>,
+    peers: Peers,
+    shutdown: CancellationToken,
+) {
+    loop {
+        let (stream, addr) = tokio::select! {
+            res = listener.accept() => match res {
+                Ok(v) => v,
+                Err(e) => {
+                    eprintln!("TURN accept error: {}", e);
+                    continue;
+                }
+            },
+            _ = shutdown.cancelled() => break,
+        };
+        // Over the limit the connection is closed right away, like a
+        // listener with a full backlog would.
+        let Some(slot) = connections.open(addr.ip()) else {
+            continue;
+        };
+        let _ = stream.set_nodelay(true);
+
+        let inbound = inbound.clone();
+        let peers = peers.clone();
+        let shutdown = shutdown.clone();
+        match &tls {
+            Some(certs) => {
+                let acceptor = certs.acceptor();
+                tokio::spawn(async move {
+                    let _slot = slot;
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => serve_connection(stream, addr, inbound, peers, shutdown).await,
+                        Ok(Err(e)) => eprintln!("TURN TLS handshake with {} failed: {}", addr, e),
+                        Err(_) => eprintln!("TURN TLS handshake with {} timed out", addr),
+                    }
+                });
+            }
+            None => {
+                tokio::spawn(async move {
+                    let _slot = slot;
+                    serve_connection(stream, addr, inbound, peers, shutdown).await
+                });
+            }
+        }
+    }
+}
+
+async fn serve_connection<S>(
+    stream: S,
+    addr: SocketAddr,
+    inbound: mpsc::Sender<(Vec<u8>, SocketAddr)>,
+    peers: Peers,
+    shutdown: CancellationToken,
+) where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let (tx, mut rx) = mpsc::channel::<Vec<u8>>(OUTBOUND_QUEUE);
+    peers.lock().unwrap().insert(addr, tx);
+
+    let write_task = tokio::spawn(async move {
+        while let Some(frame) = rx.recv().await {
+            if writer.write_all(&frame).await.is_err() {
+                break;
+            }
+        }
+        let _ = writer.shutdown().await;
+    });
+
+    tokio::select! {
+        _ = read_frames(&mut reader, addr, &inbound, READ_TIMEOUTS) => {}
+        _ = shutdown.cancelled() => {}
+    }
+
+    peers.lock().unwrap().remove(&addr);
+    write_task.abort();
+}
+
+/// Reads length-delimited frames until the connection ends or stays quiet
+/// for too long. STUN messages carry their length after a 20 byte header,
+/// ChannelData messages after a 4 byte header and are padded to a multiple
+/// of four bytes on streams.
+async fn read_frames<R>(
+    reader: &mut R,
+    addr: SocketAddr,
+    inbound: &mpsc::Sender<(Vec<u8>, SocketAddr)>,
+    timeouts: ReadTimeouts,
+) where
+    R: AsyncRead + Unpin,
+{
+    loop {
+        let mut header = [0u8; CHANNEL_DATA_HEADER_SIZE];
+        if !matches!(tokio::time::timeout(timeouts.idle, reader.read_exact(&mut header)).await, Ok(Ok(_))) {
+            return;
+        }
+        let length = u16::from_be_bytes([header[2], header[3]]) as usize;
+        let frame_len = match header[0] >> 6 {
+            0b00 => STUN_HEADER_SIZE + length,
+            0b01 => CHANNEL_DATA_HEADER_SIZE + length.div_ceil(4) * 4,
+            _ => return,
+        };
+
+        let mut frame = vec![0u8; frame_len];
+        frame[..CHANNEL_DATA_HEADER_SIZE].copy_from_slice(&header);
+        let rest = reader.read_exact(&mut frame[CHANNEL_DATA_HEADER_SIZE..]);
+        if !matches!(tokio::time::timeout(timeouts.frame, rest).await, Ok(Ok(_))) {
+            return;
+        }
+
+        if inbound.send((frame, addr)).await.is_err() {
+            return;
+        }
+    }
+}
+
+#[async_trait]
+impl Conn for StreamConn {
+    async fn connect(&self, _addr: SocketAddr) -> webrtc_util::Result<()> {
+        Err(Error::Other("connect is not supported on a stream listener".into()))
+    }
+
+    async fn recv(&self, _buf: &mut [u8]) -> webrtc_util::Result<usize> {
+        Err(Error::Other("recv is not supported on a stream listener".into()))
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> webrtc_util::Result<(usize, SocketAddr)> {
+        let mut inbound = self.inbound.lock().await;
+        loop {
+            let (frame, addr) = tokio::select! {
+                v = inbound.recv() => v.ok_or(Error::ErrUseClosedNetworkConn)?,
+                _ = self.shutdown.cancelled() => return Err(Error::ErrUseClosedNetworkConn),
+            };
+            // Anything larger than the server's read buffer cannot be a
+            // valid request, so it is dropped like an oversized datagram.
+            if frame.len() <= buf.len() {
+                buf[..frame.len()].copy_from_slice(&frame);
+                return Ok((frame.len(), addr));
+            }
+        }
+    }
+
+    async fn send(&self, _buf: &[u8]) -> webrtc_util::Result<usize> {
+        Err(Error::Other("send is not supported on a stream listener".into()))
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> webrtc_util::Result<usize> {
+        let tx = self
+            .peers
+            .lock()
+            .unwrap()
+            .get(&target)
+            .cloned()
+            .ok_or(Error::ErrUseClosedNetworkConn)?;
+        // Mirror UDP semantics: a connection that cannot keep up loses
+        // frames instead of stalling the whole server.
+        let _ = tx.try_send(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn local_addr(&self) -> webrtc_util::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    async fn close(&self) -> webrtc_util::Result<()> {
+        // The server closes the listener socket whenever a single allocation
+        // ends, so this has to be a no-op like it is for UDP sockets. The
+        // listener stops when its shutdown token is cancelled.
+        Ok(())
+    }
+
+    fn as_any(&self) -> &(dyn std::any::Any + Send + Sync) {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use tokio::io::ReadBuf;
+
+    /// A stream that hands out its bytes in the pieces it was given, the
+    /// way TCP may split them up.
+    struct Pieces(VecDeque<Vec<u8>>);
+
+    impl AsyncRead for Pieces {
+        fn poll_read(mut self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+            if let Some(mut piece) = self.0.pop_front() {
+                let n = piece.len().min(buf.remaining());
+                buf.put_slice(&piece[..n]);
+                if n < piece.len() {
+                    self.0.push_front(piece.split_off(n));
+                }
+            }
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// A Binding request with one 4 byte attribute.
+    fn stun() -> Vec<u8> {
+        let mut frame = vec![0x00, 0x01, 0x00, 0x08, 0x21, 0x12, 0xa4, 0x42];
+        frame.extend(1..=12u8);
+        frame.extend([0x80, 0x22, 0x00, 0x04, b'r', b'u', b's', b't']);
+        frame
+    }
+
+    /// ChannelData on channel 0x4000, padded to a multiple of four bytes.
+    fn channel_data(data: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0x40, 0x00];
+        frame.extend((data.len() as u16).to_be_bytes());
+        frame.extend(data);
+        frame.resize(frame.len().next_multiple_of(4), 0);
+        frame
+    }
+
+    /// Feeds `bytes` to `read_frames` in pieces of `piece` bytes and
+    /// returns the frames it passed on.
+    async fn frames(bytes: &[u8], piece: usize) -> Vec<Vec<u8>> {
+        let addr: SocketAddr = "192.0.2.1:50000".parse().unwrap();
+        let mut reader = Pieces(bytes.chunks(piece).map(<[u8]>::to_vec).collect());
+        let (tx, mut rx) = mpsc::channel(16);
+        read_frames(&mut reader, addr, &tx, READ_TIMEOUTS).await;
+        drop(tx);
+        let mut frames = Vec::new();
+        while let Some((frame, from)) = rx.recv().await {
+            assert_eq!(from, addr);
+            frames.push(frame);
+        }
+        frames
+    }
+
+    #[tokio::test]
+    async fn frames_split_across_reads() {
+        let expected = [stun(), channel_data(b"hello"), channel_data(b"four"), channel_data(b""), stun()];
+        let stream = expected.concat();
+        for piece in [1, 3, 5, 7, 64, stream.len()] {
+            assert_eq!(frames(&stream, piece).await, expected, "pieces of {} bytes", piece);
+        }
+    }
+
+    #[tokio::test]
+    async fn channel_data_padding_is_consumed() {
+        let padded = channel_data(b"hello");
+        assert_eq!(padded.len(), 12);
+        // The padding must not be mistaken for the start of the next frame.
+        let stream = [padded.clone(), stun()].concat();
+        assert_eq!(frames(&stream, 1).await, [padded, stun()]);
+    }
+
+    #[tokio::test]
+    async fn incomplete_and_unknown_frames_end_the_stream() {
+        let stream = [stun(), channel_data(b"hello")].concat();
+        assert_eq!(frames(&stream[..stream.len() - 1], 2).await, [stun()]);
+
+        let stream = [stun(), vec![0x80, 0, 0, 0], stun()].concat();
+        assert_eq!(frames(&stream, 4).await, [stun()]);
+    }
+
+    #[tokio::test]
+    async fn quiet_and_trickling_connections_are_dropped() {
+        let addr: SocketAddr = "192.0.2.1:50000".parse().unwrap();
+        let timeouts = ReadTimeouts {
+            idle: Duration::from_millis(100),
+            frame: Duration::from_millis(100),
+        };
+        let (tx, mut rx) = mpsc::channel(16);
+
+        // Nothing at all after one frame.
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(&stun()).await.unwrap();
+        let read = tokio::time::timeout(Duration::from_secs(5), read_frames(&mut server, addr, &tx, timeouts));
+        read.await.expect("idle connection dropped");
+        assert_eq!(rx.try_recv().unwrap().0, stun());
+
+        // Half a frame, with the rest never coming.
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(&stun()[..10]).await.unwrap();
+        let read = tokio::time::timeout(Duration::from_secs(5), read_frames(&mut server, addr, &tx, timeouts));
+        read.await.expect("incomplete frame dropped");
+        assert!(rx.try_recv().is_err());
+        drop(client);
+    }
+
+    #[test]
+    fn connections_are_limited_in_total_and_per_ip() {
+        let counter = ConnectionCounter::new(StreamLimits {
+            max_connections: 3,
+            max_connections_per_ip: 2,
+        });
+        let a: IpAddr = "192.0.2.1".parse().unwrap();
+        let b: IpAddr = "192.0.2.2".parse().unwrap();
+        let c: IpAddr = "192.0.2.3".parse().unwrap();
+
+        let first = counter.open(a).unwrap();
+        let _second = counter.open(a).unwrap();
+        assert!(counter.open(a).is_none(), "per-IP limit");
+        let _third = counter.open(b).unwrap();
+        assert!(counter.open(c).is_none(), "global limit");
+
+        drop(first);
+        assert!(counter.open(c).is_some());
+        assert!(counter.open(a).is_some());
+    }
+
+    #[test]
+    fn zero_means_unlimited() {
+        let counter = ConnectionCounter::new(StreamLimits {
+            max_connections: 0,
+            max_connections_per_ip: 0,
+        });
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let slots: Vec<_> = (0..100).map(|_| counter.open(ip).unwrap()).collect();
+        assert_eq!(counter.by_ip.lock().unwrap()[&ip], 100);
+        drop(slots);
+        assert!(counter.by_ip.lock().unwrap().is_empty());
+        assert_eq!(*counter.total.lock().unwrap(), 0);
+    }
+}